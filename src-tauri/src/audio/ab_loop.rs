@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Engine, Error, PlaybackEvent};
+
+/// Region of the current song which mpv repeats until it is cleared.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AbLoop {
+    /// Start in seconds, the loop is inactive until both ends are set.
+    pub(crate) a: Option<f64>,
+    /// End in seconds.
+    pub(crate) b: Option<f64>,
+}
+
+impl Engine {
+    pub fn ab_loop(&self) -> AbLoop {
+        self.ab_loop
+    }
+
+    pub fn set_ab_loop(&mut self, ab_loop: AbLoop) -> Result<(), Error> {
+        let ab_loop = match ab_loop {
+            // mpv loops nothing if the ends are the wrong way around
+            AbLoop {
+                a: Some(a),
+                b: Some(b),
+            } if b < a => AbLoop {
+                a: Some(b),
+                b: Some(a),
+            },
+            ab_loop => ab_loop,
+        };
+        let point = |seconds: Option<f64>| seconds.map_or("no".into(), |s| format!("{:.6}", s));
+        self.mpv
+            .set_property("ab-loop-a", point(ab_loop.a))
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        self.mpv
+            .set_property("ab-loop-b", point(ab_loop.b))
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        self.ab_loop = ab_loop;
+        self.emit(PlaybackEvent::AbLoop(ab_loop));
+        Ok(())
+    }
+
+    /// Sets the next unset end of the loop to the current position, or clears the loop once
+    /// both are set, like mpv's `l` key.
+    pub fn cycle_ab_loop(&mut self) -> Result<(), Error> {
+        let position = Some(self.status.position);
+        let ab_loop = match self.ab_loop {
+            AbLoop { a: None, .. } => AbLoop {
+                a: position,
+                b: None,
+            },
+            AbLoop { a, b: None } => AbLoop { a, b: position },
+            _ => AbLoop::default(),
+        };
+        self.set_ab_loop(ab_loop)
+    }
+}
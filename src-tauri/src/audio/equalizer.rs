@@ -0,0 +1,232 @@
+//! Parametric equalizer, applied through one labelled lavfi filter in mpv's `af` chain so it
+//! can be swapped while a song plays.
+
+use serde::{Deserialize, Serialize};
+
+use super::{Engine, Error, PlaybackEvent};
+
+/// Label of our filter in mpv's filter chain, so other filters are left alone.
+const LABEL: &str = "@ample-eq";
+
+/// Gains are clamped to this many dB either way.
+pub const MAX_GAIN: f64 = 24.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Band {
+    /// Centre frequency in Hz.
+    pub(crate) frequency: f64,
+    /// Gain in dB.
+    pub(crate) gain: f64,
+    /// Quality factor, higher is narrower.
+    #[serde(default = "default_q")]
+    pub(crate) q: f64,
+}
+
+fn default_q() -> f64 {
+    1.0
+}
+
+impl Band {
+    const fn new(frequency: f64, gain: f64) -> Self {
+        Self {
+            frequency,
+            gain,
+            q: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    pub(crate) name: String,
+    /// Gain in dB applied before the bands, usually negative to leave headroom for boosts.
+    #[serde(default)]
+    pub(crate) preamp: f64,
+    pub(crate) bands: Vec<Band>,
+}
+
+const FREQUENCIES: [f64; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+impl Preset {
+    fn ten_band(name: &str, preamp: f64, gains: [f64; 10]) -> Self {
+        Self {
+            name: name.into(),
+            preamp,
+            bands: FREQUENCIES
+                .iter()
+                .zip(gains)
+                .map(|(&frequency, gain)| Band::new(frequency, gain))
+                .collect(),
+        }
+    }
+
+    /// Presets which are always available, ahead of the ones from the config file.
+    pub fn built_in() -> Vec<Self> {
+        vec![
+            Self::ten_band("Flat", 0.0, [0.0; 10]),
+            Self::ten_band(
+                "Bass Boost",
+                -6.0,
+                [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            ),
+            Self::ten_band(
+                "Treble Boost",
+                -5.0,
+                [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 3.0, 4.0, 5.0],
+            ),
+            Self::ten_band(
+                "Vocal",
+                -3.0,
+                [-2.0, -2.0, -1.0, 1.0, 3.0, 3.0, 2.0, 1.0, 0.0, -1.0],
+            ),
+            Self::ten_band(
+                "Loudness",
+                -5.0,
+                [5.0, 4.0, 2.0, 0.0, -1.0, 0.0, 0.0, 1.0, 3.0, 4.0],
+            ),
+        ]
+    }
+
+    fn is_flat(&self) -> bool {
+        self.preamp == 0.0 && self.bands.iter().all(|band| band.gain == 0.0)
+    }
+
+    /// The lavfi graph for this preset, or `None` if it would not change anything.
+    fn filter(&self) -> Option<String> {
+        if self.is_flat() {
+            return None;
+        }
+        let mut filters: Vec<String> = self
+            .bands
+            .iter()
+            .filter(|band| band.gain != 0.0)
+            .map(|band| {
+                format!(
+                    "equalizer=f={}:t=q:w={}:g={}",
+                    band.frequency, band.q, band.gain
+                )
+            })
+            .collect();
+        if self.preamp != 0.0 {
+            filters.insert(0, format!("volume={}dB", self.preamp));
+        }
+        Some(format!("{}:lavfi=[{}]", LABEL, filters.join(",")))
+    }
+}
+
+/// The equalizer as it is applied, which starts out as a copy of a preset and may then be
+/// adjusted band by band.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Equalizer {
+    pub(crate) preset: Preset,
+    /// Whether the preset was changed since it was selected.
+    pub(crate) modified: bool,
+    /// Skips the equalizer without forgetting its settings, for comparing with and without.
+    pub(crate) bypass: bool,
+}
+
+impl Default for Equalizer {
+    fn default() -> Self {
+        Self {
+            preset: Preset::ten_band("Flat", 0.0, [0.0; 10]),
+            modified: false,
+            bypass: false,
+        }
+    }
+}
+
+impl Engine {
+    pub fn equalizer(&self) -> &Equalizer {
+        &self.equalizer
+    }
+
+    pub fn presets(&self) -> &[Preset] {
+        &self.presets
+    }
+
+    /// Adds presets from the config file, replacing built-in ones with the same name.
+    pub fn add_presets(&mut self, presets: impl IntoIterator<Item = Preset>) {
+        for preset in presets {
+            match self.presets.iter_mut().find(|p| p.name == preset.name) {
+                Some(existing) => *existing = preset,
+                None => self.presets.push(preset),
+            }
+        }
+    }
+
+    pub fn set_equalizer(&mut self, mut equalizer: Equalizer) -> Result<(), Error> {
+        for band in &mut equalizer.preset.bands {
+            band.gain = band.gain.clamp(-MAX_GAIN, MAX_GAIN);
+            band.q = band.q.max(0.01);
+        }
+        equalizer.preset.preamp = equalizer.preset.preamp.clamp(-MAX_GAIN, MAX_GAIN);
+
+        self.equalizer = equalizer;
+        self.apply_equalizer(&self.mpv)?;
+        if let Some(tail) = &self.tail {
+            self.apply_equalizer(tail)?;
+        }
+        self.emit(PlaybackEvent::Equalizer(self.equalizer.clone()));
+        let equalizer = self.equalizer.clone();
+        self.remember(|settings| settings.equalizer = equalizer)
+    }
+
+    pub fn select_preset(&mut self, name: &str) -> Result<(), Error> {
+        let preset = self
+            .presets
+            .iter()
+            .find(|preset| preset.name == name)
+            .cloned()
+            .ok_or_else(|| Error::Audio(format!("no equalizer preset named {name}")))?;
+        self.set_equalizer(Equalizer {
+            preset,
+            modified: false,
+            bypass: self.equalizer.bypass,
+        })
+    }
+
+    pub fn set_band_gain(&mut self, index: usize, gain: f64) -> Result<(), Error> {
+        let mut equalizer = self.equalizer.clone();
+        let band = equalizer
+            .preset
+            .bands
+            .get_mut(index)
+            .ok_or_else(|| Error::Audio(format!("no equalizer band at index {index}")))?;
+        band.gain = gain;
+        equalizer.modified = true;
+        self.set_equalizer(equalizer)
+    }
+
+    pub fn set_equalizer_preamp(&mut self, preamp: f64) -> Result<(), Error> {
+        let mut equalizer = self.equalizer.clone();
+        equalizer.preset.preamp = preamp;
+        equalizer.modified = true;
+        self.set_equalizer(equalizer)
+    }
+
+    pub fn set_equalizer_bypass(&mut self, bypass: bool) -> Result<(), Error> {
+        let mut equalizer = self.equalizer.clone();
+        equalizer.bypass = bypass;
+        self.set_equalizer(equalizer)
+    }
+
+    /// Replaces our filter in the chain of `mpv`. mpv rebuilds the chain in place, so the
+    /// song keeps playing.
+    pub(crate) fn apply_equalizer(&self, mpv: &libmpv2::Mpv) -> Result<(), Error> {
+        let filter = if self.equalizer.bypass || self.output.bit_perfect {
+            None
+        } else {
+            self.equalizer.preset.filter()
+        };
+        // removing a filter which is not in the chain is not an error worth reporting
+        mpv.command("af", &["remove", LABEL]).ok();
+        if let Some(filter) = filter {
+            mpv.command("af", &["add", &filter])
+                .map_err(|e| Error::Audio(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,270 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use libmpv2::{
+    events::{Event, EventContext, PropertyData},
+    mpv_end_file_reason, mpv_error, EndFileReason, Format,
+};
+use serde::Serialize;
+
+use super::{AbLoop, Engine, Equalizer, Error, Output, ReplayGain, SleepStatus, Speed};
+
+/// Everything that happens to playback, broadcast to the frontend and any other subscriber.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PlaybackEvent {
+    /// A new song was started, either by a command or by following the queue.
+    TrackChanged {
+        song_id: usize,
+        queue_index: Option<usize>,
+        #[serde(skip)]
+        path: PathBuf,
+    },
+    FileStarted,
+    FileEnded {
+        reason: EndReason,
+        /// Why the file could not be played, if `reason` is `error`.
+        error: Option<String>,
+    },
+    Position {
+        seconds: f64,
+    },
+    Duration {
+        seconds: f64,
+    },
+    Paused {
+        paused: bool,
+    },
+    /// Playback restarted after a seek, at `seconds`.
+    Seeked {
+        seconds: f64,
+    },
+    Volume {
+        level: f64,
+        muted: bool,
+    },
+    ReplayGain(ReplayGain),
+    Equalizer(Equalizer),
+    Speed(Speed),
+    Output(Output),
+    AbLoop(AbLoop),
+    Sleep(Option<SleepStatus>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    Eof,
+    Stop,
+    Quit,
+    Error,
+    Redirect,
+    Unknown,
+}
+
+impl EndReason {
+    fn from_mpv(reason: EndFileReason) -> Self {
+        match reason {
+            mpv_end_file_reason::Eof => EndReason::Eof,
+            mpv_end_file_reason::Stop => EndReason::Stop,
+            mpv_end_file_reason::Quit => EndReason::Quit,
+            mpv_end_file_reason::Error => EndReason::Error,
+            mpv_end_file_reason::Redirect => EndReason::Redirect,
+            _ => EndReason::Unknown,
+        }
+    }
+}
+
+/// Starts the thread which waits on mpv's events and keeps `engine` in sync with them.
+pub fn spawn_event_loop(engine: &Arc<RwLock<Engine>>) -> Result<(), Error> {
+    let mut ctx = EventContext::new(engine.read().unwrap().mpv.ctx);
+    ctx.disable_deprecated_events()
+        .map_err(|e| Error::Audio(e.to_string()))?;
+    for (name, format) in [
+        ("time-pos", Format::Double),
+        ("duration", Format::Double),
+        ("pause", Format::Flag),
+        ("playlist-pos", Format::Int64),
+    ] {
+        ctx.observe_property(name, format, 0)
+            .map_err(|e| Error::Audio(e.to_string()))?;
+    }
+
+    let engine = Arc::clone(engine);
+    std::thread::Builder::new()
+        .name("mpv-events".into())
+        .spawn(move || loop {
+            match ctx.wait_event(600.0) {
+                Some(Ok(Event::Shutdown)) => break,
+                Some(Ok(event)) => handle(&engine, event),
+                Some(Err(libmpv2::Error::Raw(code))) if is_load_failure(code) => {
+                    let message = libmpv2::Error::Raw(code).to_string();
+                    file_failed(&mut engine.write().unwrap(), message)
+                }
+                Some(Err(e)) => log::error!("mpv reported an error: {}", e),
+                None => {}
+            }
+        })
+        .map_err(|e| Error::Audio(e.to_string()))?;
+
+    Ok(())
+}
+
+/// Errors `end-file` carries for files which could not be played, as opposed to requests
+/// which failed.
+fn is_load_failure(code: i32) -> bool {
+    matches!(
+        code,
+        mpv_error::LoadingFailed
+            | mpv_error::AoInitFailed
+            | mpv_error::NothingToPlay
+            | mpv_error::UnknownFormat
+            | mpv_error::Unsupported
+    )
+}
+
+fn handle(engine: &RwLock<Engine>, event: Event<'_>) {
+    let mut engine = engine.write().unwrap();
+    match event {
+        Event::PropertyChange {
+            name: "time-pos",
+            change: PropertyData::Double(seconds),
+            ..
+        } => {
+            engine.status.position = seconds;
+            engine.emit(PlaybackEvent::Position { seconds });
+            if let Err(e) = engine.maybe_crossfade() {
+                log::error!("failed to crossfade: {}", e);
+            }
+            engine.check_sleep();
+        }
+        Event::PropertyChange {
+            name: "duration",
+            change: PropertyData::Double(seconds),
+            ..
+        } => {
+            engine.status.duration = seconds;
+            engine.emit(PlaybackEvent::Duration { seconds });
+        }
+        Event::PropertyChange {
+            name: "pause",
+            change: PropertyData::Flag(paused),
+            ..
+        } => {
+            engine.status.paused = paused;
+            engine.emit(PlaybackEvent::Paused { paused });
+        }
+        // mpv moved on to the entry we appended, see `Engine::preload_next`
+        Event::PropertyChange {
+            name: "playlist-pos",
+            change: PropertyData::Int64(1),
+            ..
+        } if engine.preloaded.is_some() => {
+            if let Err(e) = engine.finish_switch() {
+                log::error!("failed to follow gapless switch: {}", e);
+            }
+        }
+        Event::StartFile => {
+            engine.status.position = 0.0;
+            engine.status.finished = false;
+            engine.status.ended = None;
+            engine.status.error = None;
+            engine.emit(PlaybackEvent::FileStarted);
+        }
+        Event::EndFile(reason) => match EndReason::from_mpv(reason) {
+            EndReason::Error => file_failed(&mut engine, "unable to open or decode file".into()),
+            reason => {
+                engine.status.ended = Some(reason);
+                if reason == EndReason::Eof {
+                    engine.status.switching = engine.preloaded.is_some();
+                    advance(&mut engine);
+                }
+                engine.emit(PlaybackEvent::FileEnded {
+                    reason,
+                    error: None,
+                });
+            }
+        },
+        Event::FileLoaded => {
+            engine.status.failures = 0;
+            // audiobooks go on from where they were left, however they were started
+            let resume_at = engine.resume_at.take().or_else(|| engine.saved_progress());
+            if let Some(position) = resume_at {
+                let position = format!("{:.6}", position);
+                if let Err(e) = engine.mpv.command("seek", &[&position, "absolute+exact"]) {
+                    log::error!("failed to seek to resumed position: {}", e);
+                }
+            }
+        }
+        Event::Seek => engine.status.seeking = true,
+        // the position is only settled once playback restarts, which also happens on load
+        Event::PlaybackRestart if engine.status.seeking => {
+            engine.status.seeking = false;
+            let seconds = engine
+                .mpv
+                .get_property("time-pos")
+                .unwrap_or(engine.status.position);
+            engine.status.position = seconds;
+            engine.emit(PlaybackEvent::Seeked { seconds });
+        }
+        _ => {}
+    }
+}
+
+/// Moves on to the next queue entry once a file ended, unless mpv already does so by itself
+/// because the next entry is preloaded.
+fn advance(engine: &mut Engine) {
+    if engine.preloaded.is_some() {
+        return;
+    }
+
+    // skip entries which cannot be played, but only go around the queue once
+    loop {
+        if engine.status.failures >= engine.queue.len().max(1) {
+            log::warn!("no entry of the queue can be played, stopping");
+            engine.status.finished = true;
+            return;
+        }
+        match engine.advance() {
+            Ok(true) => return,
+            Ok(false) => {
+                engine.status.finished = true;
+                return;
+            }
+            Err(e) => report_error(engine, e.to_string()),
+        }
+    }
+}
+
+fn report_error(engine: &mut Engine, error: String) {
+    log::error!("{}", error);
+    engine.status.failures += 1;
+    engine.status.ended = Some(EndReason::Error);
+    engine.status.error = Some(error.clone());
+    engine.emit(PlaybackEvent::FileEnded {
+        reason: EndReason::Error,
+        error: Some(error),
+    });
+}
+
+fn file_failed(engine: &mut Engine, message: String) {
+    // mpv went on to the preloaded entry by itself, so that is the one which failed
+    if engine.status.switching {
+        engine.status.switching = false;
+        engine.preloaded = None;
+        engine.current = engine.queue.advance(false).cloned();
+    }
+    let error = Error::Load {
+        path: engine
+            .current
+            .as_ref()
+            .map(|e| e.path.clone())
+            .unwrap_or_default(),
+        message,
+    };
+    report_error(engine, error.to_string());
+    // a broken file should not stop the rest of the queue from playing
+    advance(engine);
+}
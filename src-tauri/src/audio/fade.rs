@@ -0,0 +1,377 @@
+//! Volume ramps, used for crossfading between songs and for short fades around pausing,
+//! resuming and seeking so they do not click.
+
+use std::{
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use libmpv2::Mpv;
+
+use super::{new_mpv, Engine, Error, QueueEntry};
+
+/// How many volume steps a ramp takes per second.
+const STEPS_PER_SECOND: f64 = 50.0;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Fades {
+    /// Overlap between songs which are not from the same album, zero to disable.
+    pub crossfade: Duration,
+    /// Fade around pause, play and seek, zero to disable.
+    pub fade: Duration,
+}
+
+type Action = Box<dyn FnOnce(&Mpv) + Send>;
+
+/// Handle to the ramp of one mpv instance. Starting a new ramp stops the old one.
+#[derive(Debug)]
+pub(crate) struct Ramp {
+    lane: usize,
+    jobs: mpsc::Sender<(usize, Job)>,
+}
+
+enum Job {
+    Start(Running),
+    /// Stops the ramp and puts the volume at a level.
+    Settle(Arc<Mpv>, f64),
+    Cancel,
+}
+
+/// Starts the thread all ramps run on, and returns `N` independent ramps on it.
+pub(crate) fn ramps<const N: usize>() -> [Ramp; N] {
+    let (jobs, received) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("volume-ramps".into())
+        .spawn(move || work(received, N))
+        .expect("failed to start volume ramp thread");
+    std::array::from_fn(|lane| Ramp {
+        lane,
+        jobs: jobs.clone(),
+    })
+}
+
+impl Ramp {
+    /// Ramps the volume of `mpv` from `from` to `to`, calling `then` once done.
+    pub(crate) fn start(
+        &self,
+        mpv: Arc<Mpv>,
+        from: f64,
+        to: f64,
+        duration: Duration,
+        then: impl FnOnce(&Mpv) + Send + 'static,
+    ) {
+        self.send(Job::Start(Running::new(
+            mpv,
+            from,
+            to,
+            duration,
+            Some(Box::new(then) as Action),
+        )));
+    }
+
+    /// Ramps the volume down to silence, runs `action` and ramps it back up to `level`. A
+    /// ramp started or settled before `action` ran runs it right away instead of dropping it.
+    pub(crate) fn dip(
+        &self,
+        mpv: Arc<Mpv>,
+        level: f64,
+        duration: Duration,
+        action: impl FnOnce(&Mpv) + Send + 'static,
+    ) {
+        let half = duration / 2;
+        let mut down = Running::new(mpv, level, 0.0, half, Some(Box::new(action) as Action));
+        down.back_up = Some((level, half));
+        down.finish = true;
+        self.send(Job::Start(down));
+    }
+
+    /// Ramps the volume down to silence from `from` and calls `then`, which like the action of
+    /// a dip runs right away if another ramp starts or settles first.
+    pub(crate) fn fade_out(
+        &self,
+        mpv: Arc<Mpv>,
+        from: f64,
+        duration: Duration,
+        then: impl FnOnce(&Mpv) + Send + 'static,
+    ) {
+        let mut down = Running::new(mpv, from, 0.0, duration, Some(Box::new(then) as Action));
+        down.finish = true;
+        self.send(Job::Start(down));
+    }
+
+    /// Stops the ramp and puts the volume of `mpv` back at `level`, after running what a dip
+    /// or fade out had left to run.
+    pub(crate) fn settle(&self, mpv: Arc<Mpv>, level: f64) {
+        self.send(Job::Settle(mpv, level));
+    }
+
+    /// Stops the ramp and puts the volume of `mpv` back at `level`, dropping what it would
+    /// have run.
+    pub(crate) fn reset(&self, mpv: Arc<Mpv>, level: f64) {
+        self.cancel();
+        self.settle(mpv, level);
+    }
+
+    /// Stops the ramp where it is, without running what it would have run.
+    pub(crate) fn cancel(&self) {
+        self.send(Job::Cancel);
+    }
+
+    fn send(&self, job: Job) {
+        if self.jobs.send((self.lane, job)).is_err() {
+            log::error!("volume ramp thread is gone");
+        }
+    }
+}
+
+/// A ramp in progress.
+struct Running {
+    mpv: Arc<Mpv>,
+    from: f64,
+    to: f64,
+    steps: u32,
+    step: u32,
+    then: Option<Action>,
+    /// Level and duration of the ramp back up after a dip, whose `then` is its action.
+    back_up: Option<(f64, Duration)>,
+    /// Whether `then` still runs when another ramp takes over, as for pausing or seeking.
+    finish: bool,
+}
+
+impl Running {
+    fn new(mpv: Arc<Mpv>, from: f64, to: f64, duration: Duration, then: Option<Action>) -> Self {
+        Self {
+            mpv,
+            from,
+            to,
+            steps: (duration.as_secs_f64() * STEPS_PER_SECOND).ceil().max(1.0) as u32,
+            step: 0,
+            then,
+            back_up: None,
+            finish: false,
+        }
+    }
+
+    /// Takes the volume one step further, returning what is left to do.
+    fn step(mut self) -> Option<Self> {
+        self.step += 1;
+        let volume = self.from + (self.to - self.from) * self.step as f64 / self.steps as f64;
+        if let Err(e) = self.mpv.set_property("volume", volume) {
+            log::error!("failed to ramp volume: {}", e);
+            return None;
+        }
+        if self.step < self.steps {
+            return Some(self);
+        }
+        if let Some(then) = self.then.take() {
+            then(&self.mpv);
+        }
+        let (level, duration) = self.back_up?;
+        Some(Running::new(self.mpv, 0.0, level, duration, None))
+    }
+
+    /// Gives way to another ramp, which a dip or fade out does only after running `then`.
+    fn supersede(self) {
+        if let (Some(then), true) = (self.then, self.finish) {
+            then(&self.mpv);
+        }
+    }
+}
+
+/// Steps all running ramps together until every handle is gone.
+fn work(jobs: mpsc::Receiver<(usize, Job)>, lanes: usize) {
+    let tick = Duration::from_secs_f64(1.0 / STEPS_PER_SECOND);
+    let mut running: Vec<Option<Running>> = (0..lanes).map(|_| None).collect();
+    let mut next_tick = Instant::now();
+    loop {
+        let received = if running.iter().all(Option::is_none) {
+            let received = jobs
+                .recv()
+                .map_err(|_| mpsc::RecvTimeoutError::Disconnected);
+            next_tick = Instant::now() + tick;
+            received
+        } else {
+            jobs.recv_timeout(next_tick.saturating_duration_since(Instant::now()))
+        };
+
+        match received {
+            Ok((lane, job)) => {
+                let superseded = running[lane].take();
+                match job {
+                    Job::Start(ramp) => {
+                        if let Some(superseded) = superseded {
+                            superseded.supersede();
+                        }
+                        if let Err(e) = ramp.mpv.set_property("volume", ramp.from) {
+                            log::error!("failed to start volume ramp: {}", e);
+                        }
+                        running[lane] = Some(ramp);
+                    }
+                    Job::Settle(mpv, level) => {
+                        if let Some(superseded) = superseded {
+                            superseded.supersede();
+                        }
+                        if let Err(e) = mpv.set_property("volume", level) {
+                            log::error!("failed to restore volume: {}", e);
+                        }
+                    }
+                    Job::Cancel => {}
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // a slow step delays the ones after it rather than bunching them up
+                next_tick = Instant::now() + tick;
+                for ramp in &mut running {
+                    *ramp = ramp.take().and_then(Running::step);
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+/// Songs from the same album are played gaplessly rather than crossfaded.
+pub(crate) fn same_album(current: &QueueEntry, next: &QueueEntry) -> bool {
+    current.album.is_some() && current.album == next.album
+}
+
+impl Engine {
+    /// Runs `action` in a short dip of the volume, or right away if fading is disabled.
+    pub(crate) fn faded(&mut self, action: impl FnOnce(&Mpv) + Send + 'static) {
+        if self.fades.fade.is_zero() || self.status.paused {
+            action(&self.mpv);
+            return;
+        }
+        self.ramp.dip(
+            Arc::clone(&self.mpv),
+            self.volume.level,
+            self.fades.fade,
+            action,
+        );
+    }
+
+    pub(crate) fn fade_in(&mut self) -> Result<(), Error> {
+        if self.fades.fade.is_zero() {
+            return Ok(());
+        }
+        self.mpv
+            .set_property("volume", 0.0)
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        self.ramp.start(
+            Arc::clone(&self.mpv),
+            0.0,
+            self.volume.level,
+            self.fades.fade,
+            |_| {},
+        );
+        Ok(())
+    }
+
+    pub(crate) fn fade_out(&mut self, then: impl FnOnce(&Mpv) + Send + 'static) {
+        if self.fades.fade.is_zero() {
+            then(&self.mpv);
+            return;
+        }
+        let level = self.volume.level;
+        self.ramp
+            .fade_out(Arc::clone(&self.mpv), level, self.fades.fade, move |mpv| {
+                then(mpv);
+                // restore the volume behind the pause, so resuming without a fade works too
+                if let Err(e) = mpv.set_property("volume", level) {
+                    log::error!("failed to restore volume: {}", e);
+                }
+            });
+    }
+
+    /// Called on every position update. Once the song is about to end, hands its remaining
+    /// audio over to the tail instance to fade out there, and fades in the next queue entry.
+    pub(crate) fn maybe_crossfade(&mut self) -> Result<(), Error> {
+        let crossfade = self.fades.crossfade;
+        let remaining = self.status.duration - self.status.position;
+        if crossfade.is_zero()
+            || self.preloaded.is_some()
+            || self.status.duration <= 0.0
+            || remaining > crossfade.as_secs_f64()
+            || self.queue.peek_next().is_none()
+        {
+            return Ok(());
+        }
+        let Some(current) = self.current.clone() else {
+            return Ok(());
+        };
+
+        let tail = match &self.tail {
+            Some(tail) => Arc::clone(tail),
+            None => {
+                let tail = Arc::new(new_mpv()?);
+                self.apply_output(&tail)?;
+                self.apply_equalizer(&tail)?;
+                Arc::clone(self.tail.insert(tail))
+            }
+        };
+        // a pause may still be fading out the tail, which would stop it once done
+        self.tail_ramp.cancel();
+        // the tail plays out the current song, as loud and as fast as it was playing
+        self.apply_replay_gain(&tail)?;
+        self.apply_speed(&tail)?;
+        let level = self.volume.level;
+        let set = |name: &str, value: String| {
+            tail.set_property(name, value)
+                .map_err(|e| Error::Audio(e.to_string()))
+        };
+        set("start", format!("{:.3}", self.status.position))?;
+        set("volume", level.to_string())?;
+        set("mute", if self.volume.muted { "yes" } else { "no" }.into())?;
+        set("pause", "no".into())?;
+        tail.command("loadfile", &[&current.path.to_string_lossy(), "replace"])
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        self.tail_ramp.start(
+            Arc::clone(&tail),
+            level,
+            0.0,
+            Duration::from_secs_f64(remaining),
+            |tail| {
+                if let Err(e) = tail.command("stop", &[]) {
+                    log::error!("failed to stop crossfade tail: {}", e);
+                }
+            },
+        );
+
+        self.mpv
+            .set_property("volume", 0.0)
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        self.advance()?;
+        self.ramp
+            .start(Arc::clone(&self.mpv), 0.0, level, crossfade, |_| {});
+        Ok(())
+    }
+
+    /// Fades out a crossfade in progress along with a pause, as it does not resume after.
+    pub(crate) fn fade_out_tail(&mut self) {
+        let Some(tail) = &self.tail else {
+            return;
+        };
+        if self.fades.fade.is_zero() {
+            self.stop_tail();
+            return;
+        }
+        // the tail may be halfway through its own fade
+        let level = tail.get_property("volume").unwrap_or(0.0);
+        self.tail_ramp
+            .fade_out(Arc::clone(tail), level, self.fades.fade, |tail| {
+                if let Err(e) = tail.command("stop", &[]) {
+                    log::error!("failed to stop crossfade tail: {}", e);
+                }
+            });
+    }
+
+    /// Stops a crossfade in progress, e.g. because the user started another song.
+    pub(crate) fn stop_tail(&mut self) {
+        self.tail_ramp.cancel();
+        if let Some(tail) = &self.tail {
+            if let Err(e) = tail.command("stop", &[]) {
+                log::error!("failed to stop crossfade tail: {}", e);
+            }
+        }
+    }
+}
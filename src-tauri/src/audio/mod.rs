@@ -1,120 +1,570 @@
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, RwLock},
     time::Duration,
 };
 
 use libmpv2::Mpv;
+use tokio::sync::broadcast;
 
-use crate::library::LibraryState;
+use crate::{
+    config::Config,
+    context::{Context, ContextState},
+    library::{self, Library},
+    settings::Settings,
+};
+
+mod ab_loop;
+mod equalizer;
+mod events;
+mod fade;
+mod output;
+mod queue;
+mod seek;
+mod sleep;
+mod speed;
+mod volume;
+
+pub use ab_loop::AbLoop;
+pub use equalizer::{Band, Equalizer, Preset, MAX_GAIN};
+pub use events::{spawn_event_loop, EndReason, PlaybackEvent};
+pub use fade::Fades;
+pub use output::{AudioDevice, Output, SampleFormat};
+pub use queue::{Queue, QueueEntry, QueuePayload, Repeat, Shuffle};
+pub use seek::{Chapter, SeekPrecision, SeekTarget};
+pub use sleep::{start_sleep, SleepStatus, SleepTarget};
+pub use speed::{Speed, SpeedScope, MAX_SPEED, MIN_SPEED};
+pub use volume::{ReplayGain, ReplayGainMode, Volume, MAX_VOLUME};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("{0}")]
     Audio(String),
+    #[error("no queue entry at index {0}")]
+    QueueIndex(usize),
+    #[error("unable to play {path:?}: {message}")]
+    Load { path: PathBuf, message: String },
+    #[error("{0}")]
+    Settings(#[from] crate::settings::SettingsInitError),
 }
 
+/// Playback state as last reported by mpv's events.
+#[derive(Debug, Default)]
+struct Status {
+    position: f64,
+    duration: f64,
+    paused: bool,
+    finished: bool,
+    /// Set by a seek until playback restarts at its target.
+    seeking: bool,
+    /// Set once the playing file ended and mpv moves on to the preloaded entry.
+    switching: bool,
+    /// Files which failed one after the other, reset once one loads.
+    failures: usize,
+    ended: Option<EndReason>,
+    error: Option<String>,
+}
+
+/// How long a song has to play before going to the previous one restarts it instead.
+const RESTART_THRESHOLD: f64 = 3.0;
+
 pub struct Engine {
-    mpv: Mpv,
+    mpv: Arc<Mpv>,
     generation: u64,
+    events: broadcast::Sender<PlaybackEvent>,
+    status: Status,
+    volume: Volume,
+    replay_gain: ReplayGain,
+    equalizer: Equalizer,
+    speed: Speed,
+    output: Output,
+    ab_loop: AbLoop,
+    /// Position to seek to once the file being loaded is ready.
+    resume_at: Option<f64>,
+    /// Set while an earlier session is put back, which does not count as a play.
+    restoring: bool,
+    sleep: Option<sleep::Sleep>,
+    /// Bumped whenever a sleep timer starts or is cancelled, so the threads of earlier timers
+    /// can tell they are stale.
+    sleep_generation: u64,
+    presets: Vec<Preset>,
+    fades: Fades,
+    ramp: fade::Ramp,
+    /// Second mpv instance which plays out the end of a song while the next one fades in.
+    tail: Option<Arc<Mpv>>,
+    tail_ramp: fade::Ramp,
+    pub(crate) queue: Queue,
+    /// The song mpv is playing.
+    current: Option<QueueEntry>,
+    /// Upcoming queue entry already appended to mpv's playlist, which mpv switches to by itself.
+    preloaded: Option<PathBuf>,
+    /// Where changes made through the setters are remembered.
+    settings: Arc<RwLock<Settings>>,
 }
 
 impl std::fmt::Debug for Engine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Engine")
             .field("generation", &self.generation)
+            .field("queue", &self.queue)
+            .field("current", &self.current)
+            .field("preloaded", &self.preloaded)
+            .field("status", &self.status)
+            .field("volume", &self.volume)
+            .field("replay_gain", &self.replay_gain)
+            .field("equalizer", &self.equalizer)
+            .field("speed", &self.speed)
+            .field("output", &self.output)
+            .field("ab_loop", &self.ab_loop)
+            .field("fades", &self.fades)
             .finish()
     }
 }
 
+fn new_mpv() -> Result<Mpv, Error> {
+    Mpv::with_initializer(|init| {
+        init.set_option("vo", "null")?;
+        init.set_option("video", "no")?;
+        init.set_option("keep-open", "no")?;
+        init.set_option("idle", "yes")?;
+
+        // High-quality audio resampling
+        // SoX resampler (soxr) is the gold standard for audio resampling.
+        // If soxr is not available in the build, this option is silently ignored.
+        init.set_option("audio-swresample-o", output::SOXR).ok();
+        init.set_option("audio-resample-filter-size", "32")?;
+        init.set_option("audio-resample-linear", "no")?;
+        init.set_option("gapless-audio", "yes")?;
+        init.set_option("volume-max", MAX_VOLUME)?;
+
+        Ok(())
+    })
+    .map_err(|e| Error::Audio(e.to_string()))
+}
+
 impl Engine {
-    pub fn init() -> Result<Self, Error> {
-        let mpv = Mpv::with_initializer(|init| {
-            init.set_option("vo", "null")?;
-            init.set_option("video", "no")?;
-            init.set_option("keep-open", "no")?;
-            init.set_option("idle", "yes")?;
-
-            // High-quality audio resampling
-            // SoX resampler (soxr) is the gold standard for audio resampling.
-            // If soxr is not available in the build, this option is silently ignored.
-            init.set_option("audio-swresample-o", "resampler=soxr").ok();
-            init.set_option("audio-resample-filter-size", "32")?;
-            init.set_option("audio-resample-linear", "no")?;
-            init.set_option("gapless-audio", "yes")?;
-
-            Ok(())
-        })
-        .map_err(|e| Error::Audio(e.to_string()))?;
+    /// Starts mpv with the audio settings and queue modes saved in `settings`.
+    pub(crate) fn init(settings: Arc<RwLock<Settings>>, config: &Config) -> Result<Self, Error> {
+        let saved = settings.read().unwrap();
+        let mut queue = Queue::default();
+        let [ramp, tail_ramp] = fade::ramps();
+        queue.set_repeat(saved.repeat);
+        queue.set_shuffle(saved.shuffle);
+        let mut engine = Self {
+            mpv: Arc::new(new_mpv()?),
+            generation: 0,
+            events: broadcast::channel(64).0,
+            status: Status::default(),
+            volume: saved.volume,
+            replay_gain: saved.replay_gain,
+            equalizer: saved.equalizer.clone(),
+            speed: saved.speed,
+            output: saved.output.clone(),
+            ab_loop: AbLoop::default(),
+            resume_at: None,
+            restoring: false,
+            sleep: None,
+            sleep_generation: 0,
+            presets: Preset::built_in(),
+            fades: Fades {
+                crossfade: Duration::from_millis(config.crossfade_ms),
+                fade: Duration::from_millis(config.fade_ms),
+            },
+            ramp,
+            tail: None,
+            tail_ramp,
+            queue,
+            current: None,
+            preloaded: None,
+            settings: Arc::clone(&settings),
+        };
+        drop(saved);
+        engine.add_presets(config.equalizer_presets.iter().cloned());
+        engine.apply_settings();
+        Ok(engine)
+    }
+
+    /// Hands the settings the engine was created with to mpv. A saved value mpv rejects
+    /// should not keep the player from starting, so it is replaced by the default.
+    fn apply_settings(&mut self) {
+        self.restore("volume", |engine| &mut engine.volume, Self::apply_volume);
+        self.restore(
+            "ReplayGain",
+            |engine| &mut engine.replay_gain,
+            |engine| engine.apply_replay_gain(&engine.mpv),
+        );
+        // the saved device may be unplugged, which leaves the rest of the output as it is
+        if let Err(e) = self.check_device(&self.output) {
+            log::warn!("unable to restore audio device, using the default: {}", e);
+            self.output.device = None;
+        }
+        self.restore(
+            "audio output",
+            |engine| &mut engine.output,
+            |engine| {
+                engine.check_output(&engine.output)?;
+                engine.apply_output(&engine.mpv)
+            },
+        );
+        // bit-perfect output plays at normal speed
+        if self.output.bit_perfect {
+            self.speed.rate = 1.0;
+        }
+        self.restore(
+            "speed",
+            |engine| &mut engine.speed,
+            |engine| engine.apply_speed(&engine.mpv),
+        );
+        self.restore(
+            "equalizer",
+            |engine| &mut engine.equalizer,
+            |engine| engine.apply_equalizer(&engine.mpv),
+        );
+    }
+
+    /// Applies one of the saved settings, going back to its default if that fails.
+    fn restore<T: Default>(
+        &mut self,
+        name: &str,
+        setting: fn(&mut Self) -> &mut T,
+        apply: fn(&Self) -> Result<(), Error>,
+    ) {
+        let Err(e) = apply(self) else {
+            return;
+        };
+        log::warn!("unable to restore {}, using the default: {}", name, e);
+        *setting(self) = T::default();
+        if let Err(e) = apply(self) {
+            log::error!("unable to apply the default {}: {}", name, e);
+        }
+    }
+
+    /// Applies `change` to the settings and saves them.
+    fn remember(&self, change: impl FnOnce(&mut Settings)) -> Result<(), Error> {
+        let mut settings = self.settings.write().unwrap();
+        change(&mut settings);
+        settings.save()?;
+        Ok(())
+    }
+
+    /// Applies `change` to the settings, which are saved with the session later on. For
+    /// changes such as dragging a slider, which come too often to save each.
+    fn remember_later(&self, change: impl FnOnce(&mut Settings)) {
+        let mut settings = self.settings.write().unwrap();
+        change(&mut settings);
+        settings.save_later();
+    }
+
+    /// Changes the repeat mode and remembers it.
+    pub fn set_repeat(&mut self, repeat: Repeat) -> Result<(), Error> {
+        self.queue.set_repeat(repeat);
+        self.remember(|settings| settings.repeat = repeat)
+    }
+
+    /// Changes the shuffle mode, which reorders the queue, and remembers it.
+    pub fn set_shuffle(&mut self, shuffle: Shuffle) -> Result<(), Error> {
+        self.queue.set_shuffle(shuffle);
+        self.remember(|settings| settings.shuffle = shuffle)
+    }
+
+    /// Starts playing `entry`, taking over from whatever was started before.
+    pub fn start(&mut self, entry: QueueEntry) -> Result<(), Error> {
+        self.switch(entry)?;
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
 
-        Ok(Self { mpv, generation: 0 })
+    /// Loads `entry` right away, cutting short any fade or crossfade.
+    fn switch(&mut self, entry: QueueEntry) -> Result<(), Error> {
+        self.status.failures = 0;
+        self.stop_tail();
+        self.ramp.reset(Arc::clone(&self.mpv), self.volume.level);
+        self.load(entry)
+    }
+
+    /// Skips to the next queue entry, returning whether there was one.
+    pub fn next(&mut self) -> Result<bool, Error> {
+        let Some(entry) = self.queue.advance(true).cloned() else {
+            return Ok(false);
+        };
+        self.switch(entry)?;
+        Ok(true)
     }
 
-    pub fn start(&mut self, path: PathBuf) -> Result<(), Error> {
-        let path_str = path.to_string_lossy().to_string();
+    /// Plays the queue entry at `index`.
+    pub fn jump(&mut self, index: usize) -> Result<(), Error> {
+        let entry = self.queue.jump(index)?.clone();
+        self.switch(entry)
+    }
+
+    /// Goes back to the previous queue entry, or to the start of the current one if it has
+    /// been playing for a bit, like most players do.
+    pub fn previous(&mut self) -> Result<(), Error> {
+        if self.status.position > RESTART_THRESHOLD || self.queue.current_index() == Some(0) {
+            return self.seek(SeekTarget::Absolute { seconds: 0.0 }, SeekPrecision::Exact);
+        }
+        match self.queue.previous().cloned() {
+            Some(entry) => self.switch(entry),
+            None => Ok(()),
+        }
+    }
+
+    fn load(&mut self, entry: QueueEntry) -> Result<(), Error> {
+        // mpv only reports this asynchronously, so catch the obvious case early
+        if !entry.path.is_file() {
+            return Err(Error::Load {
+                path: entry.path,
+                message: "file does not exist".into(),
+            });
+        }
+
+        let path_str = entry.path.to_string_lossy().to_string();
         self.mpv
             .command("loadfile", &[&path_str, "replace"])
-            .map_err(|e| Error::Audio(e.to_string()))?;
-        self.generation = self.generation.wrapping_add(1);
+            .map_err(|e| Error::Load {
+                path: entry.path.clone(),
+                message: e.to_string(),
+            })?;
+        // stale position updates of the previous file must not look like this one is ending
+        self.status.position = 0.0;
+        self.status.duration = 0.0;
+        self.status.switching = false;
+        self.preloaded = None;
+        self.set_current(Some(entry));
+        self.preload_next()
+    }
+
+    fn set_current(&mut self, entry: Option<QueueEntry>) {
+        if let Some(entry) = &entry {
+            let mut settings = self.settings.write().unwrap();
+            if !self.restoring {
+                settings.record_play(entry.path.clone());
+            }
+            let rate = settings.speed_for(&entry.path);
+            drop(settings);
+            // the speed remembered for the song or its folder, or the one for everything else
+            if self.speed.rate != rate {
+                if let Err(e) = self.change_speed(rate) {
+                    log::error!("failed to restore playback speed: {}", e);
+                }
+            }
+            self.emit(PlaybackEvent::TrackChanged {
+                song_id: entry.id,
+                queue_index: self.queue.current_index(),
+                path: entry.path.clone(),
+            });
+        }
+        self.current = entry;
+        // mpv keeps the loop points across files, but they only make sense for one song
+        if self.ab_loop != AbLoop::default() {
+            if let Err(e) = self.set_ab_loop(AbLoop::default()) {
+                log::error!("failed to clear A-B loop: {}", e);
+            }
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PlaybackEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: PlaybackEvent) {
+        // no subscribers is fine
+        let _ = self.events.send(event);
+    }
+
+    /// Keeps the next queue entry appended to mpv's playlist, so that mpv can switch to it
+    /// without a gap. Needs to be called whenever the queue changes. With crossfading enabled
+    /// this only happens within an album, everything else is crossfaded instead.
+    pub fn preload_next(&mut self) -> Result<(), Error> {
+        let next = match &self.current {
+            Some(current) => self
+                .queue
+                .peek_next()
+                .filter(|next| self.fades.crossfade.is_zero() || fade::same_album(current, next))
+                .map(|next| next.path.clone()),
+            None => None,
+        };
+        if next == self.preloaded {
+            return Ok(());
+        }
+
+        if self.preloaded.take().is_some() {
+            // removes everything but the playing file
+            self.mpv
+                .command("playlist-clear", &[])
+                .map_err(|e| Error::Audio(e.to_string()))?;
+        }
+
+        if let Some(next) = next {
+            let path_str = next.to_string_lossy().to_string();
+            self.mpv
+                .command("loadfile", &[&path_str, "append"])
+                .map_err(|e| Error::Audio(e.to_string()))?;
+            self.preloaded = Some(next);
+        }
+
         Ok(())
     }
 
+    /// Catches the queue up after mpv switched to the preloaded entry.
+    fn finish_switch(&mut self) -> Result<(), Error> {
+        self.preloaded = None;
+        self.status.switching = false;
+        self.mpv
+            .command("playlist-remove", &["0"])
+            .map_err(|e| Error::Audio(e.to_string()))?;
+
+        let entry = self.queue.advance(false).cloned();
+        self.sleep_on_switch(entry.as_ref());
+        self.set_current(entry);
+        self.preload_next()
+    }
+
+    /// Starts the entry after the current one in the queue, returning whether there was one.
+    pub fn advance(&mut self) -> Result<bool, Error> {
+        let Some(entry) = self.queue.advance(false).cloned() else {
+            // playback stops by itself, which is all a timer waiting for it would do
+            self.sleep_on_switch(None);
+            self.current = None;
+            return Ok(false);
+        };
+        self.sleep_on_switch(Some(&entry));
+        self.load(entry)?;
+        Ok(true)
+    }
+
     pub fn play(&mut self) -> Result<(), Error> {
+        // a fade out still running would pause again once it is done
+        self.ramp.reset(Arc::clone(&self.mpv), self.volume.level);
+        if self.status.paused && !self.fades.fade.is_zero() {
+            self.fade_in()?;
+        }
         self.mpv
             .set_property("pause", false)
             .map_err(|e| Error::Audio(e.to_string()))
     }
 
     pub fn pause(&mut self) -> Result<(), Error> {
-        self.mpv
-            .set_property("pause", true)
-            .map_err(|e| Error::Audio(e.to_string()))
+        if self.status.paused {
+            return Ok(());
+        }
+        self.fade_out_tail();
+        self.fade_out(|mpv| {
+            if let Err(e) = mpv.set_property("pause", true) {
+                log::error!("failed to pause: {}", e);
+            }
+        });
+        Ok(())
     }
 
-    pub fn seek_forward(&mut self, seconds: f32) -> Result<(), Error> {
-        let current: f64 = self
-            .mpv
-            .get_property("time-pos")
-            .map_err(|e| Error::Audio(e.to_string()))?;
-        let duration: f64 = self
-            .mpv
-            .get_property("duration")
+    /// Pauses if playing and plays if paused.
+    pub fn toggle(&mut self) -> Result<(), Error> {
+        if self.status.paused {
+            self.play()
+        } else {
+            self.pause()
+        }
+    }
+
+    /// Stops playback and unloads the current song. The queue keeps its position, so
+    /// playing again starts that entry over.
+    pub fn stop(&mut self) -> Result<(), Error> {
+        self.stop_tail();
+        self.ramp.reset(Arc::clone(&self.mpv), self.volume.level);
+        // also drops the preloaded entry, mpv's playlist is cleared
+        self.mpv
+            .command("stop", &[])
             .map_err(|e| Error::Audio(e.to_string()))?;
+        self.preloaded = None;
+        self.current = None;
+        self.status.position = 0.0;
+        self.status.duration = 0.0;
+        Ok(())
+    }
 
-        let new_pos = (current + seconds as f64).clamp(0.0, duration - 0.1);
+    /// Seeks to the bookmark called `name` in the current song.
+    pub fn jump_to_bookmark(&mut self, name: &str) -> Result<(), Error> {
+        let Some(path) = self.current.as_ref().map(|entry| entry.path.clone()) else {
+            return Err(Error::Audio("nothing is playing".into()));
+        };
+        let seconds = self
+            .settings
+            .read()
+            .unwrap()
+            .bookmark(&path, name)
+            .map(|bookmark| bookmark.seconds)
+            .ok_or_else(|| Error::Audio(format!("no bookmark named {name}")))?;
+        self.seek(SeekTarget::Absolute { seconds }, SeekPrecision::Exact)
+    }
 
-        self.mpv
-            .command("seek", &[&format!("{:.6}", new_pos), "absolute"])
-            .map_err(|e| Error::Audio(e.to_string()))
+    /// Position within the current song in seconds.
+    pub fn position(&self) -> f64 {
+        self.status.position
     }
 
-    pub fn seek_backward(&mut self, seconds: f32) -> Result<(), Error> {
-        let current: f64 = self
-            .mpv
-            .get_property("time-pos")
-            .map_err(|e| Error::Audio(e.to_string()))?;
-        let duration: f64 = self
-            .mpv
-            .get_property("duration")
+    /// The song mpv is playing, which need not be in the queue.
+    pub(crate) fn current(&self) -> Option<&QueueEntry> {
+        self.current.as_ref()
+    }
+
+    /// Puts back a queue from an earlier session, with `index` its playing entry, and
+    /// loads `current` paused at `position`.
+    pub fn resume(
+        &mut self,
+        entries: Vec<QueueEntry>,
+        index: Option<usize>,
+        current: Option<QueueEntry>,
+        position: f64,
+    ) -> Result<(), Error> {
+        self.queue.restore(entries, index);
+        let Some(entry) = current else {
+            return Ok(());
+        };
+        self.mpv
+            .set_property("pause", true)
             .map_err(|e| Error::Audio(e.to_string()))?;
+        self.restoring = true;
+        let started = self.start(entry);
+        self.restoring = false;
+        started?;
+        self.seek_when_loaded(position);
+        Ok(())
+    }
 
-        let new_pos = (current - seconds as f64).clamp(0.0, duration - 0.1);
+    /// Seeks to `position` as soon as the file being loaded is ready.
+    pub fn seek_when_loaded(&mut self, position: f64) {
+        self.resume_at = Some(position).filter(|&position| position > 0.0);
+    }
 
-        self.mpv
-            .command("seek", &[&format!("{:.6}", new_pos), "absolute"])
-            .map_err(|e| Error::Audio(e.to_string()))
+    /// Where the file mpv just loaded was left off, if it is an audiobook. Asks mpv, as with
+    /// gapless playback the file may be loaded before the queue catches up.
+    fn saved_progress(&self) -> Option<f64> {
+        let path: String = self.mpv.get_property("path").ok()?;
+        let duration: f64 = self.mpv.get_property("duration").ok()?;
+        let duration = Duration::try_from_secs_f64(duration).ok()?;
+        self.settings
+            .read()
+            .unwrap()
+            .resume_position(Path::new(&path), duration)
     }
 
     pub fn get_status(&self) -> (PlaybackPayload, u64) {
-        let progress: f64 = self.mpv.get_property("time-pos").unwrap_or(0.0);
-        let total: f64 = self.mpv.get_property("duration").unwrap_or(0.0);
-        let eof: String = self.mpv.get_property("eof-reached").unwrap_or_default();
-        let idle: String = self.mpv.get_property("idle-active").unwrap_or_default();
-
         let payload = PlaybackPayload {
-            progress_frames: (progress * 1000.0) as u64,
-            total_frames: (total * 1000.0) as u64,
-            is_finished: eof == "yes" || idle == "yes",
+            progress_frames: (self.status.position * 1000.0) as u64,
+            total_frames: (self.status.duration * 1000.0) as u64,
+            is_finished: self.status.finished,
+            is_paused: self.status.paused,
+            end_reason: self.status.ended,
+            error: self.status.error.clone(),
+            song_id: self.current.as_ref().map(|entry| entry.id),
+            queue_index: self.queue.current_index(),
+            repeat: self.queue.repeat(),
+            shuffle: self.queue.shuffle_mode(),
+            volume: self.volume,
+            replay_gain: self.replay_gain,
+            speed: self.speed,
+            ab_loop: self.ab_loop,
+            sleep: self.sleep_status(),
         };
         (payload, self.generation)
     }
@@ -129,49 +579,76 @@ pub struct PlaybackPayload {
     pub progress_frames: u64,
     pub total_frames: u64,
     pub is_finished: bool,
+    pub is_paused: bool,
+    /// Why the last file stopped playing, until the next one starts.
+    pub end_reason: Option<EndReason>,
+    pub error: Option<String>,
+    pub song_id: Option<usize>,
+    pub queue_index: Option<usize>,
+    pub repeat: Repeat,
+    pub shuffle: Shuffle,
+    pub volume: Volume,
+    pub replay_gain: ReplayGain,
+    pub speed: Speed,
+    pub ab_loop: AbLoop,
+    pub sleep: Option<SleepStatus>,
+}
+
+/// Sends the status to `on_event` on every playback event, until another song is started.
+fn spawn_status_task(
+    engine: Arc<RwLock<Engine>>,
+    generation: u64,
+    on_event: tauri::ipc::Channel<PlaybackPayload>,
+) {
+    let mut events = engine.read().unwrap().subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+
+            let (payload, new_generation) = engine.read().unwrap().get_status();
+            if generation != new_generation {
+                break;
+            }
+            if on_event.send(payload).is_err() {
+                break;
+            }
+        }
+    });
 }
 
 #[tauri::command]
 pub async fn song_start(
     id: usize,
-    library: LibraryState<'_>,
-    engine: EngineState<'_>,
+    context: ContextState<'_>,
     on_event: tauri::ipc::Channel<PlaybackPayload>,
 ) -> crate::Result<()> {
-    let lock = library.read().unwrap();
+    let generation = start_song(&context, id)?;
+    spawn_status_task(Arc::clone(&context.engine), generation, on_event);
+    Ok(())
+}
+
+/// Plays the song `id` of the library on its own, from where it was left off if it is an
+/// audiobook. Returns the generation of the engine it started.
+pub(crate) fn start_song(ctx: &Context, id: usize) -> crate::Result<u64> {
+    let lock = ctx.library.read().unwrap();
     let Some(library) = lock.as_ref() else {
         log::error!("trying to play when library does not exist");
         return Err(Error::Audio("library not loaded".into()))?;
     };
-    let Some(file) = library.files.get(id).map(|file| file.path.clone()) else {
+    let Some(file) = library.files.get(id) else {
         log::error!("invalid id to play");
         return Err(Error::Audio("invalid song id".into()))?;
     };
+    let entry = QueueEntry::new(id, file.path.clone(), file.album());
     drop(lock);
 
-    let mut engine_guard = engine.write().unwrap();
-    engine_guard.start(file)?;
-    let generation = engine_guard.generation;
-    drop(engine_guard);
-
-    let engine = Arc::clone(&engine);
-    tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(Duration::from_millis(100)).await;
-            let guard = engine.read().unwrap();
-            let (payload, new_generation) = guard.get_status();
-            drop(guard);
-            if generation == new_generation {
-                if on_event.send(payload).is_err() {
-                    break;
-                }
-            } else {
-                break;
-            }
-        }
-    });
-
-    Ok(())
+    let mut engine = ctx.engine.write().unwrap();
+    engine.queue.detach();
+    engine.start(entry)?;
+    Ok(engine.generation)
 }
 
 #[tauri::command]
@@ -186,6 +663,18 @@ pub fn song_play(engine: EngineState<'_>) -> crate::Result<()> {
     engine.play().map_err(Into::into)
 }
 
+#[tauri::command]
+pub fn song_next(engine: EngineState<'_>) -> crate::Result<bool> {
+    let mut engine = engine.write().unwrap();
+    engine.next().map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn song_previous(engine: EngineState<'_>) -> crate::Result<()> {
+    let mut engine = engine.write().unwrap();
+    engine.previous().map_err(Into::into)
+}
+
 #[tauri::command]
 pub fn song_seek_forward(engine: EngineState<'_>, seconds: f32) -> crate::Result<()> {
     let mut engine = engine.write().unwrap();
@@ -197,3 +686,270 @@ pub fn song_seek_backward(engine: EngineState<'_>, seconds: f32) -> crate::Resul
     let mut engine = engine.write().unwrap();
     engine.seek_backward(seconds).map_err(Into::into)
 }
+
+#[tauri::command]
+pub fn song_seek(
+    target: SeekTarget,
+    precision: Option<SeekPrecision>,
+    engine: EngineState<'_>,
+) -> crate::Result<()> {
+    let mut engine = engine.write().unwrap();
+    engine
+        .seek(target, precision.unwrap_or_default())
+        .map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn song_chapters(engine: EngineState<'_>) -> crate::Result<Vec<Chapter>> {
+    engine.read().unwrap().chapters().map_err(Into::into)
+}
+
+pub(crate) fn queue_entries(
+    ids: Vec<usize>,
+    library: &RwLock<Option<Library>>,
+) -> crate::Result<Vec<QueueEntry>> {
+    let library = library.read().unwrap();
+    let library = library.as_ref().ok_or(library::Error::NotLoaded)?;
+    ids.into_iter()
+        .map(|id| match library.files.get(id) {
+            Some(file) => Ok(QueueEntry::new(id, file.path.clone(), file.album())),
+            None => Err(library::Error::InvalidId(id).into()),
+        })
+        .collect()
+}
+
+#[tauri::command]
+pub fn queue_list(engine: EngineState<'_>) -> QueuePayload {
+    engine.read().unwrap().queue.payload()
+}
+
+#[tauri::command]
+pub fn queue_add(ids: Vec<usize>, context: ContextState<'_>) -> crate::Result<()> {
+    let entries = queue_entries(ids, &context.library)?;
+    context.change_queue(|engine| {
+        engine.queue.add(entries);
+        Ok(())
+    })
+}
+
+#[tauri::command]
+pub fn queue_insert(index: usize, ids: Vec<usize>, context: ContextState<'_>) -> crate::Result<()> {
+    let entries = queue_entries(ids, &context.library)?;
+    context.change_queue(|engine| Ok(engine.queue.insert(index, entries)?))
+}
+
+#[tauri::command]
+pub fn queue_remove(index: usize, context: ContextState<'_>) -> crate::Result<()> {
+    context.change_queue(|engine| Ok(engine.queue.remove(index)?))
+}
+
+#[tauri::command]
+pub fn queue_move(from: usize, to: usize, context: ContextState<'_>) -> crate::Result<()> {
+    context.change_queue(|engine| Ok(engine.queue.move_entry(from, to)?))
+}
+
+#[tauri::command]
+pub fn queue_clear(context: ContextState<'_>) {
+    let mut engine = context.engine.write().unwrap();
+    engine.queue.clear();
+    context.queue_changed(&mut engine);
+}
+
+#[tauri::command]
+pub fn queue_shuffle(context: ContextState<'_>) {
+    let mut engine = context.engine.write().unwrap();
+    engine.queue.shuffle();
+    context.queue_changed(&mut engine);
+}
+
+#[tauri::command]
+pub fn queue_set_repeat(repeat: Repeat, context: ContextState<'_>) -> crate::Result<()> {
+    context
+        .change_queue(|engine| engine.set_repeat(repeat))
+        .map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn queue_set_shuffle(shuffle: Shuffle, context: ContextState<'_>) -> crate::Result<()> {
+    context
+        .change_queue(|engine| engine.set_shuffle(shuffle))
+        .map_err(Into::into)
+}
+
+#[tauri::command]
+pub async fn queue_jump(
+    index: usize,
+    engine: EngineState<'_>,
+    on_event: tauri::ipc::Channel<PlaybackPayload>,
+) -> crate::Result<()> {
+    let mut engine_guard = engine.write().unwrap();
+    let entry = engine_guard.queue.jump(index)?.clone();
+    engine_guard.start(entry)?;
+    let generation = engine_guard.generation;
+    drop(engine_guard);
+
+    spawn_status_task(Arc::clone(&engine), generation, on_event);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn audio_set_volume(level: f64, engine: EngineState<'_>) -> crate::Result<()> {
+    engine
+        .write()
+        .unwrap()
+        .set_volume(level)
+        .map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn audio_toggle_mute(engine: EngineState<'_>) -> crate::Result<bool> {
+    engine.write().unwrap().toggle_mute().map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn audio_set_replay_gain(
+    replay_gain: ReplayGain,
+    engine: EngineState<'_>,
+) -> crate::Result<()> {
+    engine
+        .write()
+        .unwrap()
+        .set_replay_gain(replay_gain)
+        .map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn equalizer_presets(engine: EngineState<'_>) -> Vec<Preset> {
+    engine.read().unwrap().presets().to_vec()
+}
+
+#[tauri::command]
+pub fn equalizer_get(engine: EngineState<'_>) -> Equalizer {
+    engine.read().unwrap().equalizer().clone()
+}
+
+/// Applies `change` to the equalizer, returning the result.
+fn change_equalizer(
+    engine: EngineState<'_>,
+    change: impl FnOnce(&mut Engine) -> Result<(), Error>,
+) -> crate::Result<Equalizer> {
+    let mut engine = engine.write().unwrap();
+    change(&mut engine)?;
+    Ok(engine.equalizer().clone())
+}
+
+#[tauri::command]
+pub fn equalizer_select(name: String, engine: EngineState<'_>) -> crate::Result<Equalizer> {
+    change_equalizer(engine, |engine| engine.select_preset(&name))
+}
+
+#[tauri::command]
+pub fn equalizer_set_band(
+    index: usize,
+    gain: f64,
+    engine: EngineState<'_>,
+) -> crate::Result<Equalizer> {
+    change_equalizer(engine, |engine| engine.set_band_gain(index, gain))
+}
+
+#[tauri::command]
+pub fn equalizer_set_preamp(preamp: f64, engine: EngineState<'_>) -> crate::Result<Equalizer> {
+    change_equalizer(engine, |engine| engine.set_equalizer_preamp(preamp))
+}
+
+#[tauri::command]
+pub fn equalizer_set_bypass(bypass: bool, engine: EngineState<'_>) -> crate::Result<Equalizer> {
+    change_equalizer(engine, |engine| engine.set_equalizer_bypass(bypass))
+}
+
+/// Sets the playback speed, remembering it for the current song or its folder if `remember`
+/// is given, and as the speed for everything else otherwise.
+#[tauri::command]
+pub fn audio_set_speed(
+    rate: f64,
+    remember: Option<SpeedScope>,
+    engine: EngineState<'_>,
+) -> crate::Result<()> {
+    engine
+        .write()
+        .unwrap()
+        .set_speed(rate, remember)
+        .map_err(Into::into)
+}
+
+/// Forgets the speed remembered for the current song or its folder and goes back to the
+/// general speed.
+#[tauri::command]
+pub fn audio_forget_speed(scope: SpeedScope, engine: EngineState<'_>) -> crate::Result<()> {
+    engine
+        .write()
+        .unwrap()
+        .forget_speed(scope)
+        .map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn audio_set_pitch_correction(enabled: bool, engine: EngineState<'_>) -> crate::Result<()> {
+    engine
+        .write()
+        .unwrap()
+        .set_pitch_correction(enabled)
+        .map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn audio_devices(engine: EngineState<'_>) -> crate::Result<Vec<AudioDevice>> {
+    engine.read().unwrap().audio_devices().map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn audio_output(engine: EngineState<'_>) -> Output {
+    engine.read().unwrap().output().clone()
+}
+
+#[tauri::command]
+pub fn audio_set_output(output: Output, engine: EngineState<'_>) -> crate::Result<()> {
+    engine
+        .write()
+        .unwrap()
+        .set_output(output)
+        .map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn audio_set_ab_loop(ab_loop: AbLoop, engine: EngineState<'_>) -> crate::Result<()> {
+    engine
+        .write()
+        .unwrap()
+        .set_ab_loop(ab_loop)
+        .map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn audio_cycle_ab_loop(engine: EngineState<'_>) -> crate::Result<AbLoop> {
+    let mut engine = engine.write().unwrap();
+    engine.cycle_ab_loop()?;
+    Ok(engine.ab_loop())
+}
+
+/// Seeks to the bookmark called `name` in the current song.
+#[tauri::command]
+pub fn song_jump_bookmark(name: String, engine: EngineState<'_>) -> crate::Result<()> {
+    engine
+        .write()
+        .unwrap()
+        .jump_to_bookmark(&name)
+        .map_err(Into::into)
+}
+
+/// Starts the sleep timer, replacing the one running before.
+#[tauri::command]
+pub fn sleep_start(target: SleepTarget, fade: bool, engine: EngineState<'_>) -> crate::Result<()> {
+    start_sleep(&engine, target, fade).map_err(Into::into)
+}
+
+#[tauri::command]
+pub fn sleep_cancel(engine: EngineState<'_>) {
+    engine.write().unwrap().cancel_sleep();
+}
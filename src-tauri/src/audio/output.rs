@@ -0,0 +1,182 @@
+//! Choice of the output device and how the audio is handed to it.
+
+use libmpv2::Mpv;
+use serde::{Deserialize, Serialize};
+
+use super::{Engine, Error, PlaybackEvent};
+
+/// Device name which lets mpv pick the system default.
+const AUTO_DEVICE: &str = "auto";
+
+/// The resampler settings `new_mpv` starts with, restored when bit-perfect mode is left.
+pub(crate) const SOXR: &str = "resampler=soxr";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioDevice {
+    pub(crate) name: String,
+    pub(crate) description: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleFormat {
+    S16,
+    S32,
+    Float,
+}
+
+impl SampleFormat {
+    fn as_mpv(self) -> &'static str {
+        match self {
+            SampleFormat::S16 => "s16",
+            SampleFormat::S32 => "s32",
+            SampleFormat::Float => "float",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Output {
+    /// Name from `audio-device-list`, `None` for the system default.
+    pub(crate) device: Option<String>,
+    /// Opens the device exclusively, bypassing the system mixer where the backend allows it.
+    pub(crate) exclusive: bool,
+    /// Sample rate to convert everything to, `None` to keep the rate of each file.
+    pub(crate) sample_rate: Option<u32>,
+    /// Sample format to convert everything to, `None` to let mpv choose.
+    pub(crate) format: Option<SampleFormat>,
+    /// Passes samples through untouched: no resampling, no conversion, no speed changes and
+    /// no equalizer. Volume and ReplayGain still apply, so they should be left at 100% and
+    /// off for a bit-perfect signal.
+    pub(crate) bit_perfect: bool,
+}
+
+impl Output {
+    /// Fails for settings which contradict each other.
+    fn check(&self) -> Result<(), Error> {
+        if self.bit_perfect && (self.sample_rate.is_some() || self.format.is_some()) {
+            return Err(Error::Audio(
+                "bit-perfect output cannot convert the sample rate or format".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Engine {
+    pub fn output(&self) -> &Output {
+        &self.output
+    }
+
+    pub fn audio_devices(&self) -> Result<Vec<AudioDevice>, Error> {
+        // node properties read as strings come back as JSON
+        let list: String = self
+            .mpv
+            .get_property("audio-device-list")
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        serde_json::from_str(&list).map_err(|e| Error::Audio(e.to_string()))
+    }
+
+    /// Switches to `output`. mpv reopens the device in place, so playback carries on.
+    pub fn set_output(&mut self, output: Output) -> Result<(), Error> {
+        self.check_output(&output)?;
+        self.output = output;
+        // bit-perfect output plays at normal speed, and the remembered one comes back after
+        let rate = match &self.current {
+            Some(entry) => self.settings.read().unwrap().speed_for(&entry.path),
+            None => self.settings.read().unwrap().speed.rate,
+        };
+        self.change_speed(rate)?;
+        self.apply_output(&self.mpv)?;
+        self.apply_equalizer(&self.mpv)?;
+        if let Some(tail) = &self.tail {
+            self.apply_speed(tail)?;
+            self.apply_output(tail)?;
+            self.apply_equalizer(tail)?;
+        }
+        self.emit(PlaybackEvent::Output(self.output.clone()));
+        let output = self.output.clone();
+        self.remember(|settings| settings.output = output)
+    }
+
+    /// Fails if `output` cannot be used, such as for a device which is not plugged in.
+    pub(crate) fn check_output(&self, output: &Output) -> Result<(), Error> {
+        output.check()?;
+        self.check_device(output)
+    }
+
+    /// Fails if the device of `output` is not plugged in.
+    pub(crate) fn check_device(&self, output: &Output) -> Result<(), Error> {
+        if let Some(device) = &output.device {
+            if !self.audio_devices()?.iter().any(|d| &d.name == device) {
+                return Err(Error::Audio(format!("unknown audio device {device}")));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply_output(&self, mpv: &Mpv) -> Result<(), Error> {
+        let output = &self.output;
+        let set = |name: &str, value: &str| {
+            mpv.set_property(name, value)
+                .map_err(|e| Error::Audio(e.to_string()))
+        };
+
+        set(
+            "audio-device",
+            output.device.as_deref().unwrap_or(AUTO_DEVICE),
+        )?;
+        set(
+            "audio-exclusive",
+            if output.exclusive { "yes" } else { "no" },
+        )?;
+        set(
+            "audio-samplerate",
+            &output.sample_rate.unwrap_or(0).to_string(),
+        )?;
+        set(
+            "audio-format",
+            output.format.map_or("no", SampleFormat::as_mpv),
+        )?;
+        // soxr may not be compiled in, see `new_mpv`
+        set(
+            "audio-swresample-o",
+            if output.bit_perfect { "" } else { SOXR },
+        )
+        .ok();
+
+        mpv.command("ao-reload", &[])
+            .map_err(|e| Error::Audio(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Output, SampleFormat};
+
+    #[test]
+    fn bit_perfect_output_converts_nothing() {
+        let bit_perfect = Output {
+            bit_perfect: true,
+            ..Output::default()
+        };
+        assert!(bit_perfect.check().is_ok());
+        let resampled = Output {
+            sample_rate: Some(48000),
+            ..bit_perfect.clone()
+        };
+        assert!(resampled.check().is_err());
+        let converted = Output {
+            format: Some(SampleFormat::S16),
+            ..bit_perfect
+        };
+        assert!(converted.check().is_err());
+        let converted = Output {
+            format: Some(SampleFormat::S16),
+            sample_rate: Some(48000),
+            ..Output::default()
+        };
+        assert!(converted.check().is_ok());
+    }
+}
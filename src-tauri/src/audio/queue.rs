@@ -0,0 +1,617 @@
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{fade::same_album, Error};
+
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub(crate) id: usize,
+    pub(crate) path: PathBuf,
+    pub(crate) album: Option<String>,
+    /// Tells the entries of the queue apart, as `id` is the same for a song queued twice.
+    /// Given out by the queue when the entry is added.
+    pub(crate) queue_id: usize,
+}
+
+impl QueueEntry {
+    /// An entry for the library song with `id`, which gets its queue id once queued.
+    pub(crate) fn new(id: usize, path: PathBuf, album: Option<String>) -> Self {
+        Self {
+            id,
+            path,
+            album,
+            queue_id: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Repeat {
+    #[default]
+    Off,
+    One,
+    All,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shuffle {
+    #[default]
+    Off,
+    Tracks,
+    /// Shuffles the order of albums, keeping the tracks of each album in order.
+    Albums,
+}
+
+/// Where playback is relative to the queue entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+    /// The entry at this index is playing.
+    At(usize),
+    /// The playing entry was removed from the queue, the one at this index comes next.
+    Before(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct Queue {
+    entries: Vec<QueueEntry>,
+    position: Option<Position>,
+    repeat: Repeat,
+    shuffle: Shuffle,
+    rng: Rng,
+    /// The queue id the next entry added gets.
+    next_id: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuePayload {
+    pub ids: Vec<usize>,
+    pub current: Option<usize>,
+}
+
+/// Index of the entry at `index` after moving the entry at `from` to `to`.
+fn moved(index: usize, from: usize, to: usize) -> usize {
+    if index == from {
+        to
+    } else if from < index && index <= to {
+        index - 1
+    } else if to <= index && index < from {
+        index + 1
+    } else {
+        index
+    }
+}
+
+impl Queue {
+    /// A queue whose shuffles are reproducible for the given seed.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            ..Self::default()
+        }
+    }
+
+    pub fn repeat(&self) -> Repeat {
+        self.repeat
+    }
+
+    pub fn set_repeat(&mut self, repeat: Repeat) {
+        self.repeat = repeat;
+    }
+
+    pub fn shuffle_mode(&self) -> Shuffle {
+        self.shuffle
+    }
+
+    /// Changes the shuffle mode, reordering the queue right away when it is turned on. Turning
+    /// it off keeps the current order.
+    pub fn set_shuffle(&mut self, shuffle: Shuffle) {
+        self.shuffle = shuffle;
+        self.reorder(shuffle);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn current_index(&self) -> Option<usize> {
+        match self.position {
+            Some(Position::At(index)) => Some(index),
+            _ => None,
+        }
+    }
+
+    pub fn current(&self) -> Option<&QueueEntry> {
+        self.current_index()
+            .and_then(|index| self.entries.get(index))
+    }
+
+    pub fn entries(&self) -> &[QueueEntry] {
+        &self.entries
+    }
+
+    /// Replaces the queue with `entries` as they are, without shuffling them again.
+    pub fn restore(&mut self, entries: Vec<QueueEntry>, current: Option<usize>) {
+        self.position = current
+            .filter(|&index| index < entries.len())
+            .map(Position::At);
+        self.entries = self.numbered(entries);
+    }
+
+    /// `entries` with queue ids no entry had before, counting up.
+    fn numbered(&mut self, entries: impl IntoIterator<Item = QueueEntry>) -> Vec<QueueEntry> {
+        entries
+            .into_iter()
+            .map(|mut entry| {
+                entry.queue_id = self.next_id;
+                self.next_id += 1;
+                entry
+            })
+            .collect()
+    }
+
+    pub fn payload(&self) -> QueuePayload {
+        QueuePayload {
+            ids: self.entries.iter().map(|entry| entry.id).collect(),
+            current: self.current_index(),
+        }
+    }
+
+    /// Appends `entries`, or with shuffle on scatters them over the entries which have not
+    /// been played yet, leaving the order of those as it is.
+    pub fn add(&mut self, entries: impl IntoIterator<Item = QueueEntry>) {
+        let entries = self.numbered(entries);
+        if self.shuffle == Shuffle::Off {
+            self.entries.extend(entries);
+            return;
+        }
+        let start = self.upcoming();
+        for group in self.shuffled(entries) {
+            let points: Vec<usize> = (start..=self.entries.len())
+                .filter(|&index| self.splits_no_album(index))
+                .collect();
+            let at = points[self.rng.below(points.len())];
+            self.entries.splice(at..at, group);
+        }
+    }
+
+    /// Inserts `entries` at `index`, in shuffled order with shuffle on.
+    pub fn insert(
+        &mut self,
+        index: usize,
+        entries: impl IntoIterator<Item = QueueEntry>,
+    ) -> Result<(), Error> {
+        if index > self.entries.len() {
+            return Err(Error::QueueIndex(index));
+        }
+
+        let before = self.entries.len();
+        let entries = self.numbered(entries);
+        let entries = self.shuffled(entries).concat();
+        self.entries.splice(index..index, entries);
+        let inserted = self.entries.len() - before;
+
+        self.position = self.position.map(|position| match position {
+            Position::At(current) if current >= index => Position::At(current + inserted),
+            Position::Before(next) if next > index => Position::Before(next + inserted),
+            position => position,
+        });
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<QueueEntry, Error> {
+        if index >= self.entries.len() {
+            return Err(Error::QueueIndex(index));
+        }
+
+        let entry = self.entries.remove(index);
+        self.position = self.position.map(|position| match position {
+            Position::At(current) if current == index => Position::Before(index),
+            Position::At(current) if current > index => Position::At(current - 1),
+            Position::Before(next) if next > index => Position::Before(next - 1),
+            position => position,
+        });
+        Ok(entry)
+    }
+
+    pub fn move_entry(&mut self, from: usize, to: usize) -> Result<(), Error> {
+        if from >= self.entries.len() {
+            return Err(Error::QueueIndex(from));
+        }
+        if to >= self.entries.len() {
+            return Err(Error::QueueIndex(to));
+        }
+
+        let entry = self.entries.remove(from);
+        self.entries.insert(to, entry);
+        self.position = self.position.map(|position| match position {
+            Position::At(current) => Position::At(moved(current, from, to)),
+            Position::Before(next) => Position::Before(moved(next, from, to)),
+        });
+        Ok(())
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.position = None;
+    }
+
+    /// Shuffles the queue once, keeping the playing entry (if any) at the front. With album
+    /// shuffle on, albums stay in one piece.
+    pub fn shuffle(&mut self) {
+        let shuffle = match self.shuffle {
+            Shuffle::Off => Shuffle::Tracks,
+            shuffle => shuffle,
+        };
+        self.reorder(shuffle);
+    }
+
+    /// Index of the first entry which has not been played yet.
+    fn upcoming(&self) -> usize {
+        match self.position {
+            Some(Position::At(current)) => current + 1,
+            Some(Position::Before(next)) => next,
+            None => 0,
+        }
+    }
+
+    /// Whether inserting at `index` keeps the albums album shuffle plays in one piece.
+    fn splits_no_album(&self, index: usize) -> bool {
+        self.shuffle != Shuffle::Albums
+            || index == 0
+            || index == self.entries.len()
+            || !same_album(&self.entries[index - 1], &self.entries[index])
+    }
+
+    /// `entries` in the units the shuffle mode moves around, in random order.
+    fn shuffled(&mut self, entries: Vec<QueueEntry>) -> Vec<Vec<QueueEntry>> {
+        if self.shuffle == Shuffle::Off {
+            return vec![entries];
+        }
+        let mut groups = groups(&entries, self.shuffle);
+        self.rng.shuffle(&mut groups);
+        let mut entries: Vec<Option<QueueEntry>> = entries.into_iter().map(Some).collect();
+        groups
+            .into_iter()
+            .map(|group| {
+                group
+                    .into_iter()
+                    .map(|index| entries[index].take().unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn reorder(&mut self, shuffle: Shuffle) {
+        if shuffle == Shuffle::Off {
+            return;
+        }
+        let mut groups = groups(&self.entries, shuffle);
+
+        // the group of the playing entry stays in front
+        let current = self.current_index();
+        let start = match current.and_then(|c| groups.iter().position(|g| g.contains(&c))) {
+            Some(group) => {
+                groups.swap(0, group);
+                1
+            }
+            None => 0,
+        };
+
+        self.rng.shuffle(&mut groups[start..]);
+
+        let order = groups.concat();
+        self.position = self.position.map(|position| match position {
+            Position::At(current) => {
+                Position::At(order.iter().position(|&index| index == current).unwrap())
+            }
+            Position::Before(_) => Position::Before(0),
+        });
+
+        let mut entries: Vec<Option<QueueEntry>> = std::mem::take(&mut self.entries)
+            .into_iter()
+            .map(Some)
+            .collect();
+        self.entries = order
+            .into_iter()
+            .map(|index| entries[index].take().unwrap())
+            .collect();
+    }
+
+    pub fn jump(&mut self, index: usize) -> Result<&QueueEntry, Error> {
+        if index >= self.entries.len() {
+            return Err(Error::QueueIndex(index));
+        }
+        self.position = Some(Position::At(index));
+        Ok(&self.entries[index])
+    }
+
+    /// Index of the entry to play after the current one. `manual` skips ignore repeat-one.
+    fn next_index(&self, manual: bool) -> Option<usize> {
+        let next = match self.position? {
+            Position::At(current) if self.repeat == Repeat::One && !manual => return Some(current),
+            Position::At(current) => current + 1,
+            Position::Before(next) => next,
+        };
+
+        if next < self.entries.len() {
+            Some(next)
+        } else if self.repeat == Repeat::All && !self.entries.is_empty() {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// The entry which comes after the playing one once it finishes.
+    pub fn peek_next(&self) -> Option<&QueueEntry> {
+        self.next_index(false).map(|index| &self.entries[index])
+    }
+
+    pub fn peek_next_index(&self) -> Option<usize> {
+        self.next_index(false)
+    }
+
+    /// Moves to the entry after the playing one, returning it if there is one.
+    pub fn advance(&mut self, manual: bool) -> Option<&QueueEntry> {
+        let next = self.next_index(manual);
+        self.position = next.map(Position::At);
+        next.map(|index| &self.entries[index])
+    }
+
+    pub fn previous(&mut self) -> Option<&QueueEntry> {
+        let previous = match self.position? {
+            Position::At(current) => current.checked_sub(1)?,
+            Position::Before(next) => next.checked_sub(1)?,
+        };
+        self.position = Some(Position::At(previous));
+        self.entries.get(previous)
+    }
+
+    /// Stops following the queue, e.g. because a song outside of it was started.
+    pub fn detach(&mut self) {
+        self.position = None;
+    }
+}
+
+/// Indices of `entries` in the units shuffling moves around, which are single entries or
+/// whole albums for album shuffle.
+fn groups(entries: &[QueueEntry], shuffle: Shuffle) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = vec![];
+    for (index, entry) in entries.iter().enumerate() {
+        let album = match shuffle {
+            Shuffle::Albums => groups
+                .iter_mut()
+                .find(|group| same_album(&entries[group[0]], entry)),
+            _ => None,
+        };
+        match album {
+            Some(group) => group.push(index),
+            None => groups.push(vec![index]),
+        }
+    }
+    groups
+}
+
+/// Small splitmix64 generator, good enough for shuffling and seedable so shuffles can be
+/// reproduced.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Default for Rng {
+    fn default() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or(0);
+        Self::new(nanos)
+    }
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    /// Fisher-Yates.
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i + 1);
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{Queue, QueueEntry, Repeat, Shuffle};
+
+    fn entry(id: usize, album: Option<&str>) -> QueueEntry {
+        QueueEntry::new(
+            id,
+            PathBuf::from(format!("/music/{id}.flac")),
+            album.map(String::from),
+        )
+    }
+
+    fn singles(ids: std::ops::Range<usize>) -> Vec<QueueEntry> {
+        ids.map(|id| entry(id, None)).collect()
+    }
+
+    /// Three albums of three tracks, ids counting up within each album.
+    fn albums() -> Vec<QueueEntry> {
+        ["a", "b", "c"]
+            .into_iter()
+            .enumerate()
+            .flat_map(|(album, name)| (0..3).map(move |track| entry(album * 3 + track, Some(name))))
+            .collect()
+    }
+
+    fn ids(queue: &Queue) -> Vec<usize> {
+        queue.entries().iter().map(|entry| entry.id).collect()
+    }
+
+    fn sorted(mut ids: Vec<usize>) -> Vec<usize> {
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn track_shuffle_keeps_the_playing_entry_in_front() {
+        let mut queue = Queue::with_seed(7);
+        queue.add(singles(0..20));
+        queue.jump(5).unwrap();
+        queue.set_shuffle(Shuffle::Tracks);
+
+        assert_eq!(queue.current_index(), Some(0));
+        assert_eq!(queue.current().unwrap().id, 5);
+        assert_eq!(sorted(ids(&queue)), (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shuffles_are_reproducible_with_a_seed() {
+        let shuffled = |seed| {
+            let mut queue = Queue::with_seed(seed);
+            queue.add(singles(0..20));
+            queue.set_shuffle(Shuffle::Tracks);
+            ids(&queue)
+        };
+        assert_eq!(shuffled(42), shuffled(42));
+    }
+
+    #[test]
+    fn album_shuffle_keeps_albums_together_and_in_order() {
+        let mut queue = Queue::with_seed(3);
+        queue.add(albums());
+        queue.set_shuffle(Shuffle::Albums);
+
+        let ids = ids(&queue);
+        assert_eq!(sorted(ids.clone()), (0..9).collect::<Vec<_>>());
+        for album in ids.chunks(3) {
+            assert_eq!(album[0] % 3, 0);
+            assert_eq!(album, [album[0], album[0] + 1, album[0] + 2]);
+        }
+    }
+
+    #[test]
+    fn repeat_one_replays_unless_skipped() {
+        let mut queue = Queue::with_seed(1);
+        queue.add(singles(0..3));
+        queue.jump(1).unwrap();
+        queue.set_repeat(Repeat::One);
+
+        assert_eq!(queue.advance(false).unwrap().id, 1);
+        assert_eq!(queue.advance(true).unwrap().id, 2);
+    }
+
+    #[test]
+    fn repeat_all_wraps_around() {
+        let mut queue = Queue::with_seed(1);
+        queue.add(singles(0..3));
+        queue.jump(2).unwrap();
+
+        assert!(queue.peek_next().is_none());
+        queue.set_repeat(Repeat::All);
+        assert_eq!(queue.peek_next().unwrap().id, 0);
+        assert_eq!(queue.advance(false).unwrap().id, 0);
+        assert_eq!(queue.current_index(), Some(0));
+    }
+
+    #[test]
+    fn added_entries_are_shuffled_into_what_is_still_to_come() {
+        let mut queue = Queue::with_seed(11);
+        queue.set_shuffle(Shuffle::Tracks);
+        queue.add(singles(0..10));
+        queue.jump(4).unwrap();
+        let before = ids(&queue);
+
+        queue.add(singles(10..20));
+        let after = ids(&queue);
+        assert_eq!(after[..5], before[..5]);
+        assert_eq!(queue.current_index(), Some(4));
+        // the entries which were already queued keep their order
+        let kept: Vec<usize> = after.iter().copied().filter(|&id| id < 10).collect();
+        assert_eq!(kept, before);
+        assert_eq!(sorted(after), (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn added_albums_do_not_split_others() {
+        let mut queue = Queue::with_seed(5);
+        queue.set_shuffle(Shuffle::Albums);
+        queue.add(albums());
+        // in the middle of the first album, whose rest must still follow
+        queue.jump(1).unwrap();
+        let rest_of_album = queue.entries()[2].id;
+
+        queue.add((9..12).map(|id| entry(id, Some("d"))));
+        let ids = ids(&queue);
+        assert_eq!(ids[2], rest_of_album);
+        let start = ids.iter().position(|&id| id == 9).unwrap();
+        assert_eq!(ids[start..start + 3], [9, 10, 11]);
+        for album in ids.chunks(3) {
+            assert_eq!(album, [album[0], album[0] + 1, album[0] + 2]);
+        }
+    }
+
+    #[test]
+    fn inserted_entries_stay_where_they_were_put() {
+        let mut queue = Queue::with_seed(9);
+        queue.add(singles(0..5));
+        queue.set_shuffle(Shuffle::Tracks);
+        let before = ids(&queue);
+
+        queue.insert(2, singles(5..10)).unwrap();
+        let after = ids(&queue);
+        assert_eq!(after[..2], before[..2]);
+        assert_eq!(after[7..], before[2..]);
+        assert_eq!(sorted(after[2..7].to_vec()), (5..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shuffling_once_keeps_albums_together_with_album_shuffle() {
+        let mut queue = Queue::with_seed(5);
+        queue.set_shuffle(Shuffle::Albums);
+        queue.add(albums());
+        for _ in 0..5 {
+            queue.shuffle();
+            for album in ids(&queue).chunks(3) {
+                assert_eq!(album, [album[0], album[0] + 1, album[0] + 2]);
+            }
+        }
+    }
+
+    #[test]
+    fn songs_queued_twice_are_told_apart() {
+        let mut queue = Queue::default();
+        queue.add(singles(0..2));
+        queue.add(singles(0..1));
+        queue.insert(1, singles(1..2)).unwrap();
+        let queue_ids: Vec<usize> = queue.entries().iter().map(|e| e.queue_id).collect();
+        assert_eq!(ids(&queue), [0, 1, 1, 0]);
+        assert_eq!(queue_ids, [0, 3, 1, 2]);
+    }
+}
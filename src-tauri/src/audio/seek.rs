@@ -0,0 +1,235 @@
+use serde::{Deserialize, Serialize};
+
+use super::{Engine, Error};
+
+/// How close to the end a seek may land, so it does not skip to the next song by accident.
+const END_MARGIN: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SeekTarget {
+    /// To a timestamp in seconds.
+    Absolute {
+        seconds: f64,
+    },
+    /// By a number of seconds from the current position, negative to go back.
+    Relative {
+        seconds: f64,
+    },
+    /// To a point between 0 and 100 percent of the song, 100 being just before its end.
+    Percentage {
+        percent: f64,
+    },
+    /// To the start of a chapter, counted from zero.
+    Chapter {
+        index: usize,
+    },
+    NextChapter,
+    PreviousChapter,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeekPrecision {
+    /// Lands on the exact position, at the cost of decoding up to it.
+    #[default]
+    Exact,
+    /// Lands on the closest keyframe, which is faster in some containers.
+    Keyframes,
+}
+
+impl SeekPrecision {
+    fn as_mpv(self) -> &'static str {
+        match self {
+            SeekPrecision::Exact => "exact",
+            SeekPrecision::Keyframes => "keyframes",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chapter {
+    #[serde(default)]
+    pub(crate) title: Option<String>,
+    /// Start of the chapter in seconds.
+    pub(crate) time: f64,
+}
+
+/// The amount and mode of the mpv seek going to `target`, in a song at `position` of
+/// `duration` seconds, zero if unknown. Chapters are looked up by mpv, so give `None`.
+fn resolve(target: SeekTarget, position: f64, duration: f64) -> Option<(f64, &'static str)> {
+    // streams have no duration, in which case mpv is left to handle the bounds itself
+    let duration = (duration > 0.0).then_some(duration);
+    // a seek to the very end would skip the song, which songs shorter than the margin
+    // cannot avoid
+    let clamp = |seconds: f64| match duration {
+        Some(duration) => seconds.min(duration - END_MARGIN).max(0.0),
+        None => seconds.max(0.0),
+    };
+
+    let resolved = match target {
+        SeekTarget::Absolute { seconds } => (clamp(seconds), "absolute"),
+        SeekTarget::Relative { seconds } => match duration {
+            Some(_) => (clamp(position + seconds), "absolute"),
+            None => (seconds, "relative"),
+        },
+        // 100 percent lands just before the end, like any seek
+        SeekTarget::Percentage { percent } => match duration {
+            Some(duration) => (
+                clamp(duration * percent.clamp(0.0, 100.0) / 100.0),
+                "absolute",
+            ),
+            None => (percent.clamp(0.0, 100.0), "absolute-percent"),
+        },
+        SeekTarget::Chapter { .. } | SeekTarget::NextChapter | SeekTarget::PreviousChapter => {
+            return None
+        }
+    };
+    Some(resolved)
+}
+
+impl Engine {
+    pub fn seek(&mut self, target: SeekTarget, precision: SeekPrecision) -> Result<(), Error> {
+        let Some((amount, flag)) = resolve(target, self.status.position, self.status.duration)
+        else {
+            return match target {
+                SeekTarget::Chapter { index } => self.seek_chapter(index),
+                SeekTarget::NextChapter => self.step_chapter(1),
+                _ => self.step_chapter(-1),
+            };
+        };
+
+        let flags = format!("{}+{}", flag, precision.as_mpv());
+        self.faded(move |mpv| {
+            if let Err(e) = mpv.command("seek", &[&format!("{:.6}", amount), &flags]) {
+                log::error!("failed to seek: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    pub fn seek_forward(&mut self, seconds: f32) -> Result<(), Error> {
+        self.seek(
+            SeekTarget::Relative {
+                seconds: seconds as f64,
+            },
+            SeekPrecision::Exact,
+        )
+    }
+
+    pub fn seek_backward(&mut self, seconds: f32) -> Result<(), Error> {
+        self.seek(
+            SeekTarget::Relative {
+                seconds: -seconds as f64,
+            },
+            SeekPrecision::Exact,
+        )
+    }
+
+    /// The chapters of the current song, empty if it has none.
+    pub fn chapters(&self) -> Result<Vec<Chapter>, Error> {
+        // node properties read as strings come back as JSON
+        let list: String = self
+            .mpv
+            .get_property("chapter-list")
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        serde_json::from_str(&list).map_err(|e| Error::Audio(e.to_string()))
+    }
+
+    fn seek_chapter(&mut self, index: usize) -> Result<(), Error> {
+        if index >= self.chapters()?.len() {
+            return Err(Error::Audio(format!("no chapter at index {index}")));
+        }
+        self.faded(move |mpv| {
+            if let Err(e) = mpv.set_property("chapter", index as i64) {
+                log::error!("failed to seek to chapter: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    fn step_chapter(&mut self, step: i64) -> Result<(), Error> {
+        if self.chapters()?.is_empty() {
+            return Err(Error::Audio("the current song has no chapters".into()));
+        }
+        // like mpv's own key bindings, going back from the middle of a chapter restarts it
+        self.faded(move |mpv| {
+            if let Err(e) = mpv.command("add", &["chapter", &step.to_string()]) {
+                log::error!("failed to change chapter: {}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, SeekTarget, END_MARGIN};
+
+    #[test]
+    fn seeks_stay_within_the_song() {
+        assert_eq!(
+            resolve(SeekTarget::Absolute { seconds: 30.0 }, 0.0, 60.0),
+            Some((30.0, "absolute"))
+        );
+        assert_eq!(
+            resolve(SeekTarget::Absolute { seconds: -5.0 }, 0.0, 60.0),
+            Some((0.0, "absolute"))
+        );
+        assert_eq!(
+            resolve(SeekTarget::Absolute { seconds: 90.0 }, 0.0, 60.0),
+            Some((60.0 - END_MARGIN, "absolute"))
+        );
+    }
+
+    #[test]
+    fn songs_shorter_than_the_margin_seek_to_their_start() {
+        assert_eq!(
+            resolve(SeekTarget::Absolute { seconds: 1.0 }, 0.0, 0.05),
+            Some((0.0, "absolute"))
+        );
+        assert_eq!(
+            resolve(SeekTarget::Percentage { percent: 100.0 }, 0.0, 0.05),
+            Some((0.0, "absolute"))
+        );
+    }
+
+    #[test]
+    fn relative_seeks_start_from_the_position() {
+        assert_eq!(
+            resolve(SeekTarget::Relative { seconds: 10.0 }, 20.0, 60.0),
+            Some((30.0, "absolute"))
+        );
+        assert_eq!(
+            resolve(SeekTarget::Relative { seconds: -30.0 }, 20.0, 60.0),
+            Some((0.0, "absolute"))
+        );
+        // without a duration there is nothing to clamp against
+        assert_eq!(
+            resolve(SeekTarget::Relative { seconds: -30.0 }, 20.0, 0.0),
+            Some((-30.0, "relative"))
+        );
+    }
+
+    #[test]
+    fn percentages_do_not_skip_the_song() {
+        assert_eq!(
+            resolve(SeekTarget::Percentage { percent: 50.0 }, 0.0, 60.0),
+            Some((30.0, "absolute"))
+        );
+        assert_eq!(
+            resolve(SeekTarget::Percentage { percent: 100.0 }, 0.0, 60.0),
+            Some((60.0 - END_MARGIN, "absolute"))
+        );
+        assert_eq!(
+            resolve(SeekTarget::Percentage { percent: 150.0 }, 0.0, 0.0),
+            Some((100.0, "absolute-percent"))
+        );
+    }
+
+    #[test]
+    fn chapters_are_left_to_mpv() {
+        assert_eq!(resolve(SeekTarget::Chapter { index: 1 }, 0.0, 60.0), None);
+        assert_eq!(resolve(SeekTarget::NextChapter, 0.0, 60.0), None);
+    }
+}
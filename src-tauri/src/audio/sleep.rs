@@ -0,0 +1,214 @@
+//! Sleep timer, which pauses playback after a while or at the end of a song or album.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Engine, Error, PlaybackEvent, QueueEntry};
+
+/// How long the volume takes to fade out before the timer pauses, if fading is asked for.
+const SLEEP_FADE: Duration = Duration::from_secs(10);
+
+/// The longest timer counting minutes, a day.
+const MAX_SLEEP: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SleepTarget {
+    Minutes { minutes: f64 },
+    EndOfTrack,
+    EndOfAlbum,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Sleep {
+    id: u64,
+    target: SleepTarget,
+    fade: bool,
+    /// When the timer goes off, for timers counting minutes.
+    deadline: Option<Instant>,
+    /// Whether the fade out already started.
+    fading: bool,
+}
+
+/// The sleep timer as reported to the frontend.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SleepStatus {
+    target: SleepTarget,
+    fade: bool,
+    /// Seconds until the timer goes off, for timers counting minutes.
+    remaining: Option<f64>,
+}
+
+impl Sleep {
+    pub(crate) fn status(&self) -> SleepStatus {
+        SleepStatus {
+            target: self.target,
+            fade: self.fade,
+            remaining: self.deadline.map(|deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .as_secs_f64()
+            }),
+        }
+    }
+}
+
+/// Whether a timer for `target` goes off between `current` and `next`.
+fn stops_before(
+    target: SleepTarget,
+    current: Option<&QueueEntry>,
+    next: Option<&QueueEntry>,
+) -> bool {
+    match target {
+        SleepTarget::Minutes { .. } => false,
+        SleepTarget::EndOfTrack => true,
+        SleepTarget::EndOfAlbum => match (current, next) {
+            (Some(current), Some(next)) => current.album.is_none() || current.album != next.album,
+            _ => true,
+        },
+    }
+}
+
+impl Engine {
+    pub fn sleep_status(&self) -> Option<SleepStatus> {
+        self.sleep.as_ref().map(Sleep::status)
+    }
+
+    fn set_sleep(&mut self, sleep: Option<Sleep>) {
+        if self.sleep.is_some_and(|sleep| sleep.fading) {
+            self.restore_volume();
+        }
+        self.sleep = sleep;
+        self.emit(PlaybackEvent::Sleep(self.sleep_status()));
+    }
+
+    pub fn cancel_sleep(&mut self) {
+        self.sleep_generation += 1;
+        self.set_sleep(None);
+    }
+
+    /// Called on every position update, to fade out ahead of the end of the song or album.
+    pub(crate) fn check_sleep(&mut self) {
+        let Some(sleep) = &mut self.sleep else {
+            return;
+        };
+        if !sleep.fade || sleep.fading {
+            return;
+        }
+        let ends_here = stops_before(sleep.target, self.current.as_ref(), self.queue.peek_next());
+        let remaining = self.status.duration - self.status.position;
+        if ends_here && self.status.duration > 0.0 && remaining <= SLEEP_FADE.as_secs_f64() {
+            sleep.fading = true;
+            let remaining = Duration::from_secs_f64(remaining.max(0.0));
+            self.ramp.start(
+                Arc::clone(&self.mpv),
+                self.volume.level,
+                0.0,
+                remaining,
+                |_| {},
+            );
+        }
+    }
+
+    /// Called when the queue moves on by itself to `next`, pausing if the timer goes off in
+    /// between, so that `next` waits at its start.
+    pub(crate) fn sleep_on_switch(&mut self, next: Option<&QueueEntry>) {
+        let Some(sleep) = &self.sleep else {
+            return;
+        };
+        if stops_before(sleep.target, self.current.as_ref(), next) {
+            self.go_to_sleep();
+        }
+    }
+
+    /// Pauses, along with the end of the song before which may still be crossfading.
+    fn go_to_sleep(&mut self) {
+        self.stop_tail();
+        if let Err(e) = self.mpv.set_property("pause", true) {
+            log::error!("failed to pause for sleep timer: {}", e);
+        }
+        self.set_sleep(None);
+    }
+
+    fn restore_volume(&mut self) {
+        self.ramp.settle(Arc::clone(&self.mpv), self.volume.level);
+    }
+}
+
+/// Sets the sleep timer of `engine`, replacing any earlier one.
+pub fn start_sleep(
+    engine: &Arc<RwLock<Engine>>,
+    target: SleepTarget,
+    fade: bool,
+) -> Result<(), Error> {
+    let deadline = match target {
+        SleepTarget::Minutes { minutes } => {
+            let deadline = Duration::try_from_secs_f64(minutes * 60.0)
+                .ok()
+                .filter(|timer| !timer.is_zero() && *timer <= MAX_SLEEP)
+                .and_then(|timer| Instant::now().checked_add(timer));
+            if deadline.is_none() {
+                return Err(Error::Audio(format!(
+                    "invalid sleep timer of {minutes} minutes"
+                )));
+            }
+            deadline
+        }
+        _ => None,
+    };
+    let mut guard = engine.write().unwrap();
+    guard.sleep_generation += 1;
+    let id = guard.sleep_generation;
+    guard.set_sleep(Some(Sleep {
+        id,
+        target,
+        fade,
+        deadline,
+        fading: false,
+    }));
+    drop(guard);
+
+    // a plain thread rather than the frontend, so the timer runs with the window hidden
+    if let Some(deadline) = deadline {
+        let engine = Arc::clone(engine);
+        std::thread::spawn(move || {
+            let fade_at = if fade {
+                deadline.checked_sub(SLEEP_FADE).unwrap_or(deadline)
+            } else {
+                deadline
+            };
+            std::thread::sleep(fade_at.saturating_duration_since(Instant::now()));
+            let mut guard = engine.write().unwrap();
+            if guard.sleep.map(|sleep| sleep.id) != Some(id) {
+                return;
+            }
+            if !fade || guard.status.paused {
+                guard.go_to_sleep();
+                return;
+            }
+
+            if let Some(sleep) = &mut guard.sleep {
+                sleep.fading = true;
+            }
+            let mpv = Arc::clone(&guard.mpv);
+            let level = guard.volume.level;
+            drop(guard);
+            let ramp_engine = Arc::clone(&engine);
+            engine
+                .read()
+                .unwrap()
+                .ramp
+                .fade_out(mpv, level, SLEEP_FADE, move |_| {
+                    let mut guard = ramp_engine.write().unwrap();
+                    if guard.sleep.map(|sleep| sleep.id) == Some(id) {
+                        guard.go_to_sleep();
+                    }
+                });
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use libmpv2::Mpv;
+use serde::{Deserialize, Serialize};
+
+use super::{Engine, Error, PlaybackEvent};
+
+pub const MIN_SPEED: f64 = 0.5;
+pub const MAX_SPEED: f64 = 3.0;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Speed {
+    pub(crate) rate: f64,
+    /// Keeps the pitch through mpv's `scaletempo2` filter, otherwise the audio is just
+    /// resampled and the pitch changes along with the speed.
+    pub(crate) pitch_correction: bool,
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Self {
+            rate: 1.0,
+            pitch_correction: true,
+        }
+    }
+}
+
+/// Where a speed set by the user is remembered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeedScope {
+    Song,
+    Folder,
+}
+
+impl Engine {
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Sets the playback speed, remembering it for the current song or its folder if
+    /// `remember` is given, which fails without a current song, and as the speed for
+    /// everything else otherwise.
+    pub fn set_speed(&mut self, rate: f64, remember: Option<SpeedScope>) -> Result<(), Error> {
+        let path = self.current.as_ref().map(|entry| entry.path.clone());
+        if remember.is_some() && path.is_none() {
+            return Err(Error::Audio("no song to remember the speed for".into()));
+        }
+        if self.output.bit_perfect && rate != 1.0 {
+            return Err(Error::Audio(
+                "bit-perfect output cannot change the speed".into(),
+            ));
+        }
+        self.change_speed(rate)?;
+        let rate = self.speed.rate;
+        self.remember(|settings| match (remember, path) {
+            (Some(scope), Some(path)) => settings.remember_speed(&path, scope, rate),
+            _ => settings.speed.rate = rate,
+        })
+    }
+
+    /// Forgets the speed remembered for the current song or its folder and goes back to the
+    /// speed it falls back on.
+    pub fn forget_speed(&mut self, scope: SpeedScope) -> Result<(), Error> {
+        let Some(path) = self.current.as_ref().map(|entry| entry.path.clone()) else {
+            return Ok(());
+        };
+        let mut rate = 1.0;
+        self.remember(|settings| {
+            settings.forget_speed(&path, scope);
+            rate = settings.speed_for(&path);
+        })?;
+        self.change_speed(rate)
+    }
+
+    /// Changes the speed without remembering it anywhere. Bit-perfect output stays at normal
+    /// speed, as any other resamples the audio.
+    pub(crate) fn change_speed(&mut self, rate: f64) -> Result<(), Error> {
+        self.speed.rate = match self.output.bit_perfect {
+            true => 1.0,
+            false => rate.clamp(MIN_SPEED, MAX_SPEED),
+        };
+        self.apply_speed(&self.mpv)?;
+        self.emit(PlaybackEvent::Speed(self.speed));
+        Ok(())
+    }
+
+    pub fn set_pitch_correction(&mut self, enabled: bool) -> Result<(), Error> {
+        self.speed.pitch_correction = enabled;
+        self.apply_speed(&self.mpv)?;
+        self.emit(PlaybackEvent::Speed(self.speed));
+        self.remember(|settings| settings.speed.pitch_correction = enabled)
+    }
+
+    pub(crate) fn apply_speed(&self, mpv: &Mpv) -> Result<(), Error> {
+        mpv.set_property("speed", self.speed.rate)
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        mpv.set_property("audio-pitch-correction", self.speed.pitch_correction)
+            .map_err(|e| Error::Audio(e.to_string()))
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use libmpv2::Mpv;
+use serde::{Deserialize, Serialize};
+
+use super::{Engine, Error, PlaybackEvent};
+
+/// Highest volume in percent, anything above 100 is amplified by mpv's soft volume.
+pub const MAX_VOLUME: f64 = 150.0;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Volume {
+    pub(crate) level: f64,
+    pub(crate) muted: bool,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            level: 100.0,
+            muted: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReplayGain {
+    pub(crate) mode: ReplayGainMode,
+    /// Extra gain in dB applied on top of the tags.
+    pub(crate) preamp: f64,
+    /// Lowers the gain when it would make the track clip.
+    pub(crate) prevent_clipping: bool,
+}
+
+impl Default for ReplayGain {
+    fn default() -> Self {
+        Self {
+            mode: ReplayGainMode::Off,
+            preamp: 0.0,
+            prevent_clipping: true,
+        }
+    }
+}
+
+impl Engine {
+    pub fn volume(&self) -> Volume {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, level: f64) -> Result<(), Error> {
+        if !level.is_finite() {
+            return Err(Error::Audio(format!("invalid volume {level}")));
+        }
+        self.volume.level = level.clamp(0.0, MAX_VOLUME);
+        self.ramp.settle(Arc::clone(&self.mpv), self.volume.level);
+        self.apply_volume()?;
+        self.emit_volume();
+        let volume = self.volume;
+        self.remember_later(|settings| settings.volume = volume);
+        Ok(())
+    }
+
+    pub fn set_muted(&mut self, muted: bool) -> Result<(), Error> {
+        self.volume.muted = muted;
+        self.apply_volume()?;
+        self.emit_volume();
+        let volume = self.volume;
+        self.remember(|settings| settings.volume = volume)
+    }
+
+    /// Mutes or unmutes, returning whether it is muted now.
+    pub fn toggle_mute(&mut self) -> Result<bool, Error> {
+        let muted = !self.volume.muted;
+        self.set_muted(muted)?;
+        Ok(muted)
+    }
+
+    pub(crate) fn apply_volume(&self) -> Result<(), Error> {
+        self.mpv
+            .set_property("volume", self.volume.level)
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        self.mpv
+            .set_property("mute", self.volume.muted)
+            .map_err(|e| Error::Audio(e.to_string()))
+    }
+
+    fn emit_volume(&self) {
+        self.emit(PlaybackEvent::Volume {
+            level: self.volume.level,
+            muted: self.volume.muted,
+        });
+    }
+
+    pub fn replay_gain(&self) -> ReplayGain {
+        self.replay_gain
+    }
+
+    pub fn set_replay_gain(&mut self, replay_gain: ReplayGain) -> Result<(), Error> {
+        self.replay_gain = replay_gain;
+        self.apply_replay_gain(&self.mpv)?;
+        self.emit(PlaybackEvent::ReplayGain(replay_gain));
+        self.remember(|settings| settings.replay_gain = replay_gain)
+    }
+
+    pub(crate) fn apply_replay_gain(&self, mpv: &Mpv) -> Result<(), Error> {
+        let mode = match self.replay_gain.mode {
+            ReplayGainMode::Off => "no",
+            ReplayGainMode::Track => "track",
+            ReplayGainMode::Album => "album",
+        };
+        mpv.set_property("replaygain", mode)
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        mpv.set_property("replaygain-preamp", self.replay_gain.preamp)
+            .map_err(|e| Error::Audio(e.to_string()))?;
+        // `replaygain-clip` allows clipping, so it is the inverse of what we store
+        mpv.set_property("replaygain-clip", !self.replay_gain.prevent_clipping)
+            .map_err(|e| Error::Audio(e.to_string()))
+    }
+}
@@ -0,0 +1,233 @@
+//! Audiobook mode: long songs, and songs in folders flagged as audiobooks, remember where
+//! they were left off and continue from there.
+
+use std::{path::PathBuf, sync::RwLock, time::Duration};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{
+    audio::{EndReason, PlaybackEvent},
+    context::Context,
+    library::{Error, LibraryState},
+    settings::{Settings, SettingsState},
+};
+
+/// How much playback may go by between saves of the position.
+const SAVE_INTERVAL: f64 = 10.0;
+
+/// Songs left this close to their end count as finished.
+const FINISH_MARGIN: f64 = 2.0;
+
+/// The song being tracked, if it counts as an audiobook.
+#[derive(Debug)]
+struct Tracked {
+    path: PathBuf,
+    audiobook: bool,
+    duration: f64,
+    position: f64,
+    saved: f64,
+}
+
+/// Follows playback and keeps the progress of audiobooks in the settings.
+pub fn spawn_tracker(ctx: Context) {
+    let mut events = ctx.engine.read().unwrap().subscribe();
+    tauri::async_runtime::spawn(async move {
+        let mut tracked: Option<Tracked> = None;
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            let settings: &RwLock<Settings> = &ctx.settings;
+            match event {
+                PlaybackEvent::TrackChanged { path, .. } => {
+                    // the previous song may have been left part way through
+                    if let Some(previous) = tracked.take() {
+                        leave(settings, previous);
+                    }
+                    let audiobook = settings.read().unwrap().is_audiobook(&path, None);
+                    tracked = Some(Tracked {
+                        path,
+                        audiobook,
+                        duration: 0.0,
+                        position: 0.0,
+                        saved: 0.0,
+                    });
+                }
+                PlaybackEvent::Duration { seconds } => {
+                    if let Some(tracked) = &mut tracked {
+                        tracked.duration = seconds;
+                        let duration = Duration::from_secs_f64(seconds.max(0.0));
+                        tracked.audiobook = settings
+                            .read()
+                            .unwrap()
+                            .is_audiobook(&tracked.path, Some(duration));
+                    }
+                }
+                PlaybackEvent::Position { seconds } => {
+                    if let Some(tracked) = &mut tracked {
+                        tracked.position = seconds;
+                        if (seconds - tracked.saved).abs() >= SAVE_INTERVAL {
+                            save(settings, tracked);
+                        }
+                    }
+                }
+                PlaybackEvent::Paused { paused: true } => {
+                    if let Some(tracked) = &mut tracked {
+                        save(settings, tracked);
+                    }
+                }
+                PlaybackEvent::FileEnded {
+                    reason: EndReason::Eof,
+                    ..
+                } => {
+                    // the next song may already be tracked by now, which `leave` leaves alone
+                    if let Some(previous) = tracked.take() {
+                        tracked = leave(settings, previous);
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+fn save(settings: &RwLock<Settings>, tracked: &mut Tracked) {
+    if !tracked.audiobook || tracked.position == tracked.saved {
+        return;
+    }
+    tracked.saved = tracked.position;
+    let mut settings = settings.write().unwrap();
+    settings.set_progress(tracked.path.clone(), tracked.position);
+    if let Err(e) = settings.save() {
+        log::error!("failed to save settings: {}", e);
+    }
+}
+
+/// Stops tracking a song, marking it as finished if it was played to the end. Songs which
+/// are not near their end are saved and handed back, as they are still playing.
+fn leave(settings: &RwLock<Settings>, mut tracked: Tracked) -> Option<Tracked> {
+    let ended = tracked.duration > 0.0 && tracked.position >= tracked.duration - FINISH_MARGIN;
+    if !ended {
+        save(settings, &mut tracked);
+        return Some(tracked);
+    }
+    if tracked.audiobook {
+        let mut settings = settings.write().unwrap();
+        settings.set_finished(tracked.path, true);
+        if let Err(e) = settings.save() {
+            log::error!("failed to save settings: {}", e);
+        }
+    }
+    None
+}
+
+#[derive(Debug, Serialize)]
+pub struct Progress {
+    id: usize,
+    /// Where the song was left off in seconds.
+    position: f64,
+    duration: f64,
+}
+
+/// Songs which were started but not finished, most complete first.
+#[tauri::command]
+pub fn audiobook_in_progress(
+    library: LibraryState<'_>,
+    settings: SettingsState<'_>,
+) -> crate::Result<Vec<Progress>> {
+    let library = library.read().unwrap();
+    let library = library.as_ref().ok_or(Error::NotLoaded)?;
+    let settings = settings.read().unwrap();
+
+    let mut items: Vec<Progress> = settings
+        .stats
+        .iter()
+        .filter(|(_, stats)| !stats.finished)
+        .filter_map(|(path, stats)| {
+            let position = stats.progress?;
+            let id = library.find(path)?;
+            let duration = library.files[id].metadata.properties().duration();
+            Some(Progress {
+                id,
+                position,
+                duration: duration.as_secs_f64(),
+            })
+        })
+        .collect();
+    items.sort_by(|a, b| {
+        let done = |item: &Progress| item.position / item.duration.max(1.0);
+        done(b).total_cmp(&done(a))
+    });
+    Ok(items)
+}
+
+/// Forgets where the song was left off and whether it was finished.
+#[tauri::command]
+pub fn audiobook_reset(
+    id: usize,
+    library: LibraryState<'_>,
+    settings: SettingsState<'_>,
+) -> crate::Result<()> {
+    let library = library.read().unwrap();
+    let library = library.as_ref().ok_or(Error::NotLoaded)?;
+    let file = library.files.get(id).ok_or(Error::InvalidId(id))?;
+
+    let mut settings = settings.write().unwrap();
+    if let Some(stats) = settings.stats.get_mut(&file.path) {
+        stats.progress = None;
+        stats.finished = false;
+    }
+    settings.save()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn audiobook_mark_finished(
+    id: usize,
+    library: LibraryState<'_>,
+    settings: SettingsState<'_>,
+) -> crate::Result<()> {
+    let library = library.read().unwrap();
+    let library = library.as_ref().ok_or(Error::NotLoaded)?;
+    let file = library.files.get(id).ok_or(Error::InvalidId(id))?;
+
+    let mut settings = settings.write().unwrap();
+    settings.set_finished(file.path.clone(), true);
+    settings.save()?;
+    Ok(())
+}
+
+/// Flags or unflags a folder as holding audiobooks.
+#[tauri::command]
+pub fn audiobook_set_folder(
+    path: String,
+    enabled: bool,
+    settings: SettingsState<'_>,
+) -> crate::Result<()> {
+    let path = PathBuf::from(path);
+    let mut settings = settings.write().unwrap();
+    let folders = &mut settings.audiobooks.folders;
+    folders.retain(|folder| folder != &path);
+    if enabled {
+        folders.push(path);
+    }
+    settings.save()?;
+    Ok(())
+}
+
+/// Sets the length from which any song is treated as an audiobook, `None` to only go by
+/// folders.
+#[tauri::command]
+pub fn audiobook_set_min_length(
+    minutes: Option<u64>,
+    settings: SettingsState<'_>,
+) -> crate::Result<()> {
+    let mut settings = settings.write().unwrap();
+    settings.audiobooks.min_length = minutes.map(|minutes| Duration::from_secs(minutes * 60));
+    settings.save()?;
+    Ok(())
+}
@@ -1,7 +1,18 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::{
+    audio::SeekTarget,
+    ipc::{self, Launch},
+};
+
+/// Exit code of a remote control command when no instance is running.
+pub(crate) const NOT_RUNNING: i32 = 2;
 
 /// A nice sensible music player which uses vim-like bindings.
 #[derive(Debug, Default, argh::FromArgs)]
+#[argh(error_code(2, "no instance is running to take a command."))]
 pub(crate) struct Opts {
     /// path to the configuration file to use.
     #[argh(option, short = 'c')]
@@ -10,4 +21,278 @@ pub(crate) struct Opts {
     /// path to the settings file to use.
     #[argh(option, short = 's')]
     pub(crate) settings_path: Option<PathBuf>,
+
+    /// run without a window, controlled only through the JSON-RPC socket.
+    #[argh(switch)]
+    pub(crate) headless: bool,
+
+    /// add the given songs and folders to the queue instead of playing them.
+    #[argh(switch, short = 'e')]
+    pub(crate) enqueue: bool,
+
+    #[argh(subcommand)]
+    pub(crate) command: Option<Command>,
+
+    /// songs or folders to play, handed to the running instance if there is one.
+    #[argh(positional)]
+    pub(crate) paths: Vec<PathBuf>,
+}
+
+/// Commands for an instance which is already running.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand)]
+pub(crate) enum Command {
+    Play(Play),
+    Pause(Pause),
+    Toggle(Toggle),
+    Next(Next),
+    Previous(Previous),
+    Seek(Seek),
+    Enqueue(Enqueue),
+    Status(Status),
+    Quit(Quit),
+}
+
+/// Resume playback.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "play")]
+pub(crate) struct Play {}
+
+/// Pause playback.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "pause")]
+pub(crate) struct Pause {}
+
+/// Pause or resume playback.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "toggle")]
+pub(crate) struct Toggle {}
+
+/// Skip to the next song in the queue.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "next")]
+pub(crate) struct Next {}
+
+/// Restart the song, or go back to the previous one near its start.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "previous")]
+pub(crate) struct Previous {}
+
+/// Seek within the current song.
+#[derive(Debug, argh::FromArgs)]
+#[argh(
+    subcommand,
+    name = "seek",
+    example = "ample seek +10",
+    example = "ample seek -- -10",
+    example = "ample seek 1:30",
+    example = "ample seek 50%"
+)]
+pub(crate) struct Seek {
+    /// seconds to skip with a sign, a timestamp as seconds or m:ss, or a percentage.
+    #[argh(positional, from_str_fn(parse_seek))]
+    pub(crate) target: SeekTarget,
+}
+
+/// Append songs of the open library to the queue.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "enqueue")]
+pub(crate) struct Enqueue {
+    /// songs or folders to enqueue.
+    #[argh(positional)]
+    pub(crate) paths: Vec<PathBuf>,
+}
+
+/// Show what is playing.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "status")]
+pub(crate) struct Status {
+    /// print the full status as JSON.
+    #[argh(switch)]
+    pub(crate) json: bool,
+}
+
+/// Close the running instance.
+#[derive(Debug, argh::FromArgs)]
+#[argh(subcommand, name = "quit")]
+pub(crate) struct Quit {}
+
+fn parse_seek(value: &str) -> Result<SeekTarget, String> {
+    let invalid = || format!("invalid seek target `{}`", value);
+    if let Some(percent) = value.strip_suffix('%') {
+        let percent = percent.parse().map_err(|_| invalid())?;
+        return Ok(SeekTarget::Percentage { percent });
+    }
+    if value.starts_with(['+', '-']) {
+        let seconds = value.parse().map_err(|_| invalid())?;
+        return Ok(SeekTarget::Relative { seconds });
+    }
+    let seconds = match value.split_once(':') {
+        Some((minutes, seconds)) => {
+            let minutes: f64 = minutes.parse().map_err(|_| invalid())?;
+            let seconds: f64 = seconds.parse().map_err(|_| invalid())?;
+            minutes * 60.0 + seconds
+        }
+        None => value.parse().map_err(|_| invalid())?,
+    };
+    Ok(SeekTarget::Absolute { seconds })
+}
+
+/// Sends `command` to the instance listening on `socket`, returning the exit code.
+pub(crate) fn remote(command: Command, socket: Option<&Path>) -> i32 {
+    let json = matches!(command, Command::Status(Status { json: true }));
+    let (method, params) = match command {
+        Command::Play(_) => ("playback.play", Value::Null),
+        Command::Pause(_) => ("playback.pause", Value::Null),
+        Command::Toggle(_) => ("playback.toggle", Value::Null),
+        Command::Next(_) => ("playback.next", Value::Null),
+        Command::Previous(_) => ("playback.previous", Value::Null),
+        Command::Seek(Seek { target }) => ("playback.seek", json!({ "target": target })),
+        Command::Enqueue(Enqueue { paths }) => {
+            ("queue.enqueue", json!({ "paths": absolute(&paths) }))
+        }
+        Command::Status(_) => ("playback.status", Value::Null),
+        Command::Quit(_) => ("app.quit", Value::Null),
+    };
+
+    let Some(socket) = socket else {
+        eprintln!("no settings directory to find the running instance in");
+        return NOT_RUNNING;
+    };
+    match ipc::call(socket, method, params) {
+        Ok(Ok(value)) => {
+            if method == "playback.status" {
+                print_status(&value, json);
+            }
+            0
+        }
+        Ok(Err(message)) => {
+            eprintln!("{}", message);
+            1
+        }
+        Err(e) => {
+            eprintln!("ample is not running: {}", e);
+            NOT_RUNNING
+        }
+    }
+}
+
+/// Hands the arguments of this launch to the instance listening on `socket`, returning
+/// whether there was one to take them.
+pub(crate) fn forward(socket: &Path, launch: &Launch) -> bool {
+    let (method, params) = launch.request();
+    match ipc::call(socket, method, params) {
+        Ok(Ok(_)) => true,
+        Ok(Err(message)) => {
+            eprintln!("{}", message);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Makes `paths` absolute, as the instance they are sent to most likely runs from another
+/// directory. Paths which cannot be, such as empty ones, are reported and left out.
+pub(crate) fn absolute(paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .filter_map(|path| match std::path::absolute(path) {
+            Ok(path) => Some(path),
+            Err(e) => {
+                eprintln!("skipping invalid path {:?}: {}", path, e);
+                None
+            }
+        })
+        .collect()
+}
+
+fn print_status(status: &serde_json::Value, json: bool) {
+    if json {
+        println!("{}", status);
+        return;
+    }
+    let Some(title) = status["title"].as_str() else {
+        println!("stopped");
+        return;
+    };
+    let state = if status["is_paused"].as_bool().unwrap_or_default() {
+        "paused"
+    } else {
+        "playing"
+    };
+    let song = match status["artist"].as_str() {
+        Some(artist) => format!("{} - {}", artist, title),
+        None => title.to_string(),
+    };
+    let time = |key: &str| {
+        let seconds = status[key].as_u64().unwrap_or_default() / 1000;
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    };
+    println!(
+        "{}: {} ({} / {})",
+        state,
+        song,
+        time("progress_frames"),
+        time("total_frames")
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{absolute, parse_seek};
+    use crate::audio::SeekTarget;
+
+    fn seek(value: &str) -> String {
+        // the targets compare through their JSON, which is also how they are sent
+        serde_json::to_string(&parse_seek(value).unwrap()).unwrap()
+    }
+
+    fn target(target: SeekTarget) -> String {
+        serde_json::to_string(&target).unwrap()
+    }
+
+    #[test]
+    fn signed_seconds_seek_relatively() {
+        assert_eq!(seek("+10"), target(SeekTarget::Relative { seconds: 10.0 }));
+        assert_eq!(seek("-10"), target(SeekTarget::Relative { seconds: -10.0 }));
+        assert_eq!(seek("-2.5"), target(SeekTarget::Relative { seconds: -2.5 }));
+    }
+
+    #[test]
+    fn timestamps_seek_absolutely() {
+        assert_eq!(seek("90"), target(SeekTarget::Absolute { seconds: 90.0 }));
+        assert_eq!(seek("1:30"), target(SeekTarget::Absolute { seconds: 90.0 }));
+        assert_eq!(
+            seek("0:05.5"),
+            target(SeekTarget::Absolute { seconds: 5.5 })
+        );
+    }
+
+    #[test]
+    fn percentages_seek_within_the_song() {
+        assert_eq!(
+            seek("50%"),
+            target(SeekTarget::Percentage { percent: 50.0 })
+        );
+    }
+
+    #[test]
+    fn malformed_targets_are_rejected() {
+        for value in ["", "abc", "+", "1:xx", "x:30", "%", "fifty%", "+10s"] {
+            assert_eq!(
+                parse_seek(value).unwrap_err(),
+                format!("invalid seek target `{}`", value)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_paths_are_left_out() {
+        let paths = absolute(&[PathBuf::new(), PathBuf::from("song.flac")]);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].is_absolute());
+        assert!(paths[0].ends_with("song.flac"));
+    }
 }
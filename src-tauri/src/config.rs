@@ -2,12 +2,33 @@ use std::{fs, path::PathBuf};
 
 use serde::Deserialize;
 
-#[derive(Debug, Clone, Copy, Deserialize)]
-pub(crate) struct Config {}
+use crate::audio::Preset;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    /// Overlap between songs in milliseconds, zero for none.
+    pub(crate) crossfade_ms: u64,
+    /// Fade around pause, play and seek in milliseconds, zero for none.
+    pub(crate) fade_ms: u64,
+    /// Equalizer presets in addition to the built-in ones.
+    pub(crate) equalizer_presets: Vec<Preset>,
+    /// Where to serve the MPD protocol, `host:port` or a Unix socket path. Off if unset.
+    pub(crate) mpd_address: Option<String>,
+    /// Unix socket path to serve the JSON-RPC API on, `ample.sock` next to the settings if
+    /// unset.
+    pub(crate) rpc_socket: Option<PathBuf>,
+}
 
 impl Default for Config {
     fn default() -> Self {
-        Self {}
+        Self {
+            crossfade_ms: 0,
+            fade_ms: 0,
+            equalizer_presets: Vec::new(),
+            mpd_address: None,
+            rpc_socket: None,
+        }
     }
 }
 
@@ -0,0 +1,164 @@
+//! The state playback and the library run on, shared by the window and every other way of
+//! controlling a running instance, so all of it works the same with and without a window.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use tokio::sync::broadcast;
+
+use crate::{
+    audio::{self, Engine, PlaybackEvent, QueuePayload},
+    audiobook,
+    config::Config,
+    library::Library,
+    mpd, session,
+    session::SessionStore,
+    settings::Settings,
+};
+
+/// Changes which do not come from the engine's own events.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Queue(QueuePayload),
+    Library,
+}
+
+/// What differs between running with a window and without one.
+pub trait Shell: Send + Sync {
+    /// Brings the window to the front, if there is one.
+    fn focus(&self);
+    /// Closes the instance, which saves the session on the way out.
+    fn quit(&self);
+}
+
+pub type ContextState<'a> = tauri::State<'a, Context>;
+
+#[derive(Clone)]
+pub struct Context {
+    pub(crate) settings: Arc<RwLock<Settings>>,
+    pub(crate) engine: Arc<RwLock<Engine>>,
+    pub(crate) library: Arc<RwLock<Option<Library>>>,
+    pub(crate) session: Arc<Mutex<SessionStore>>,
+    changes: broadcast::Sender<Change>,
+    shell: Arc<dyn Shell>,
+}
+
+impl Context {
+    pub fn new(
+        settings: Arc<RwLock<Settings>>,
+        engine: Engine,
+        session: SessionStore,
+        shell: Arc<dyn Shell>,
+    ) -> Self {
+        Self {
+            settings,
+            engine: Arc::new(RwLock::new(engine)),
+            library: Arc::new(RwLock::new(None)),
+            session: Arc::new(Mutex::new(session)),
+            changes: broadcast::channel(64).0,
+            shell,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Change> {
+        self.changes.subscribe()
+    }
+
+    fn send(&self, change: Change) {
+        // nobody listening is fine
+        let _ = self.changes.send(change);
+    }
+
+    /// Tells everyone listening about a change to the queue, after preloading its next entry.
+    pub(crate) fn queue_changed(&self, engine: &mut Engine) {
+        if let Err(e) = engine.preload_next() {
+            log::error!("failed to preload next queue entry: {}", e);
+        }
+        self.send(Change::Queue(engine.queue.payload()));
+    }
+
+    /// Applies a change to the queue and tells everyone listening about it, even if the
+    /// change only went through in part.
+    pub(crate) fn change_queue<T, E>(
+        &self,
+        f: impl FnOnce(&mut Engine) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut engine = self.engine.write().unwrap();
+        let result = f(&mut engine);
+        self.queue_changed(&mut engine);
+        result
+    }
+
+    /// Makes `library` the open one, without remembering it in the settings.
+    pub(crate) fn replace_library(&self, library: Library) {
+        self.library.write().unwrap().replace(library);
+        self.send(Change::Library);
+    }
+
+    /// Adds the songs of `collection` to the open library, or to an empty one if none is
+    /// open, so they can be played without taking the library's place.
+    pub(crate) fn add_to_library(&self, collection: Library) {
+        self.library
+            .write()
+            .unwrap()
+            .get_or_insert_with(Library::default)
+            .extend(collection);
+        self.send(Change::Library);
+    }
+
+    pub(crate) fn focus(&self) {
+        self.shell.focus();
+    }
+
+    pub(crate) fn quit(&self) {
+        self.shell.quit();
+    }
+
+    /// Starts everything which runs in the background, with or without a window. `socket`
+    /// is where the JSON-RPC API is served, which is also how a later launch finds this
+    /// instance.
+    pub(crate) fn spawn_services(
+        &self,
+        config: &Config,
+        socket: Option<PathBuf>,
+    ) -> Result<(), audio::Error> {
+        audio::spawn_event_loop(&self.engine)?;
+        follow_tracks(self);
+        session::spawn_autosave(self.clone());
+        audiobook::spawn_tracker(self.clone());
+        #[cfg(target_os = "linux")]
+        crate::mpris::spawn(self.clone());
+        if let Some(address) = config.mpd_address.clone() {
+            mpd::spawn(self.clone(), address);
+        }
+        #[cfg(unix)]
+        if let Some(socket) = socket {
+            crate::rpc::spawn(self.clone(), socket);
+        }
+        #[cfg(not(unix))]
+        if socket.is_some() {
+            log::warn!("the JSON-RPC socket is only supported on Unix");
+        }
+        Ok(())
+    }
+}
+
+/// Reports the moved queue position whenever another song starts.
+fn follow_tracks(ctx: &Context) {
+    let mut events = ctx.engine.read().unwrap().subscribe();
+    let ctx = ctx.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(PlaybackEvent::TrackChanged { .. }) => {}
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+
+            let engine = ctx.engine.read().unwrap();
+            ctx.send(Change::Queue(engine.queue.payload()));
+        }
+    });
+}
@@ -40,4 +40,7 @@ define_error!(
     IO => std::io::Error,
     Settings => crate::settings::SettingsInitError,
     Audio => crate::audio::Error,
+    Library => crate::library::Error,
+    Loudness => crate::loudness::Error,
+    Playlist => crate::playlist::Error,
 );
@@ -0,0 +1,92 @@
+//! Running without a window, controlled only through the sockets. Tauri is not started at
+//! all, everything runs on a runtime of our own.
+
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use tokio::sync::Notify;
+
+use crate::{
+    audio::Engine,
+    config::Config,
+    context::{Context, Shell},
+    ipc, library, session,
+    session::SessionStore,
+    settings::Settings,
+};
+
+/// The shell without a window, which only has a way to quit.
+#[derive(Default)]
+struct Headless {
+    quit: Notify,
+}
+
+impl Shell for Headless {
+    fn focus(&self) {}
+
+    fn quit(&self) {
+        self.quit.notify_one();
+    }
+}
+
+/// Logs our own messages to stderr, which is what the log plugin does with a window.
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info && metadata.target().starts_with("ample")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "[{}][{}] {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Runs until asked to quit, then saves the session.
+pub(crate) fn run(
+    settings: Arc<RwLock<Settings>>,
+    engine: Engine,
+    session: SessionStore,
+    config: Config,
+    socket: Option<PathBuf>,
+    launch: ipc::Launch,
+) {
+    if log::set_boxed_logger(Box::new(Logger)).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("error when starting async runtime");
+    // tasks spawned through Tauri's runtime end up on ours
+    tauri::async_runtime::set(runtime.handle().clone());
+
+    let shell = Arc::new(Headless::default());
+    let ctx = Context::new(settings, engine, session, shell.clone());
+    runtime.block_on(async {
+        ctx.spawn_services(&config, socket)
+            .expect("error when starting playback");
+        // songs given to play come with a collection of their own
+        if !matches!(launch, ipc::Launch::Open { .. }) {
+            library::open_last(ctx.clone());
+        }
+        ipc::launch(&ctx, launch);
+        shell.quit.notified().await;
+    });
+    session::save(&ctx);
+}
@@ -0,0 +1,211 @@
+//! Talking to a running instance, which a later launch and the remote control commands do
+//! through its JSON-RPC socket, the operations behind what they ask for, and binding the
+//! local sockets it is reached on.
+
+use std::path::PathBuf;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+use crate::{
+    audio::{PlaybackPayload, QueueEntry},
+    context::{Change, Context},
+    library::{self, Library},
+};
+
+#[cfg(unix)]
+mod socket;
+
+#[cfg(unix)]
+pub(crate) use socket::bind;
+#[cfg(unix)]
+pub use socket::call;
+
+/// What a launch asks of the instance, either of this one or of the one already running.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Launch {
+    /// Brings the window to the front.
+    Focus,
+    /// Replaces the queue with the songs at or below each of `paths` and plays them.
+    Open { paths: Vec<PathBuf> },
+    /// Appends the songs of the library at or below each of `paths` to the queue.
+    Enqueue { paths: Vec<PathBuf> },
+}
+
+impl Launch {
+    /// What a launch with `paths` asks for.
+    pub fn new(paths: Vec<PathBuf>, enqueue: bool) -> Self {
+        match (paths.is_empty(), enqueue) {
+            (true, _) => Launch::Focus,
+            (false, true) => Launch::Enqueue { paths },
+            (false, false) => Launch::Open { paths },
+        }
+    }
+
+    /// The JSON-RPC method and params which ask a running instance for the same.
+    pub(crate) fn request(&self) -> (&'static str, Value) {
+        match self {
+            Launch::Focus => ("window.focus", Value::Null),
+            Launch::Open { paths } => ("queue.open", json!({ "paths": paths })),
+            Launch::Enqueue { paths } => ("queue.enqueue", json!({ "paths": paths })),
+        }
+    }
+}
+
+/// What `playback.status` answers with.
+#[derive(Debug, Serialize)]
+struct Status {
+    #[serde(flatten)]
+    playback: PlaybackPayload,
+    title: Option<String>,
+    artist: Option<String>,
+}
+
+/// Without a socket to reach it through, there is never a running instance.
+#[cfg(not(unix))]
+pub fn call(
+    _path: &std::path::Path,
+    _method: &str,
+    _params: Value,
+) -> std::io::Result<Result<Value, String>> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Carries out what the launch of this instance asked for. Songs to play come with a
+/// collection of their own, songs to enqueue wait for a library to find them in.
+pub fn launch(ctx: &Context, launch: Launch) {
+    let paths = match launch {
+        Launch::Focus => return,
+        Launch::Open { paths } => {
+            let ctx = ctx.clone();
+            tauri::async_runtime::spawn(async move {
+                if let Err(e) = open(&ctx, &paths).await {
+                    log::error!("failed to open launch arguments: {}", e);
+                }
+            });
+            return;
+        }
+        Launch::Enqueue { paths } => paths,
+    };
+    let mut changes = ctx.subscribe();
+    let ctx = ctx.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(Change::Library) => break,
+                Ok(Change::Queue(_)) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+        if let Err(e) = enqueue(&ctx, &paths) {
+            log::error!("failed to enqueue launch arguments: {}", e);
+        }
+    });
+}
+
+/// Replaces the queue with the songs at or below `paths` and plays them, adding an ad-hoc
+/// collection of just them to the open library if they are not all in it.
+pub(crate) async fn open(ctx: &Context, paths: &[PathBuf]) -> crate::Result<()> {
+    // the library must not stay locked while another one is read
+    let found = songs_under(ctx.library.read().unwrap().as_ref(), paths);
+    let entries = match found {
+        Ok(entries) => entries,
+        Err(_) => {
+            ctx.add_to_library(Library::ad_hoc(paths.to_vec()).await?);
+            songs_under(ctx.library.read().unwrap().as_ref(), paths)?
+        }
+    };
+    ctx.change_queue(|engine| {
+        engine.queue.clear();
+        engine.queue.add(entries);
+        engine.jump(0)
+    })?;
+    Ok(())
+}
+
+/// Appends the songs of the open library at or below `paths` to the queue.
+pub(crate) fn enqueue(ctx: &Context, paths: &[PathBuf]) -> crate::Result<()> {
+    let entries = songs_under(ctx.library.read().unwrap().as_ref(), paths)?;
+    let mut engine = ctx.engine.write().unwrap();
+    engine.queue.add(entries);
+    ctx.queue_changed(&mut engine);
+    Ok(())
+}
+
+/// The playback status along with the title and artist of the current song.
+pub(crate) fn status(ctx: &Context) -> Value {
+    let (playback, _) = ctx.engine.read().unwrap().get_status();
+    let library = ctx.library.read().unwrap();
+    let file = library
+        .as_ref()
+        .zip(playback.song_id)
+        .and_then(|(library, id)| library.files.get(id));
+    let status = Status {
+        title: file.map(|file| file.title()),
+        artist: file.and_then(|file| file.artist()),
+        playback,
+    };
+    serde_json::to_value(status).unwrap_or_default()
+}
+
+/// The songs at or below each of `paths`, in library order per path.
+fn songs_under(
+    library: Option<&Library>,
+    paths: &[PathBuf],
+) -> Result<Vec<QueueEntry>, library::Error> {
+    let library = library.ok_or(library::Error::NotLoaded)?;
+    let mut entries = Vec::new();
+    for path in paths {
+        let before = entries.len();
+        entries.extend(
+            library
+                .files
+                .iter()
+                .enumerate()
+                .filter(|(_, file)| file.path.starts_with(path))
+                .map(|(id, file)| QueueEntry::new(id, file.path.clone(), file.album())),
+        );
+        if entries.len() == before {
+            return Err(library::Error::NotInLibrary(path.clone()));
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use serde_json::{json, Value};
+
+    use super::Launch;
+
+    #[test]
+    fn launch_without_paths_asks_for_focus() {
+        assert_eq!(Launch::new(vec![], false), Launch::Focus);
+        assert_eq!(Launch::new(vec![], true), Launch::Focus);
+    }
+
+    #[test]
+    fn launch_with_paths_opens_or_enqueues_them() {
+        let paths = vec![PathBuf::from("/music/album")];
+        assert_eq!(
+            Launch::new(paths.clone(), false),
+            Launch::Open {
+                paths: paths.clone()
+            }
+        );
+        assert_eq!(Launch::new(paths.clone(), true), Launch::Enqueue { paths });
+    }
+
+    #[test]
+    fn launches_are_forwarded_as_json_rpc_methods() {
+        assert_eq!(Launch::Focus.request(), ("window.focus", Value::Null));
+        let launch = Launch::new(vec!["/music/album".into()], false);
+        assert_eq!(
+            launch.request(),
+            ("queue.open", json!({ "paths": ["/music/album"] }))
+        );
+    }
+}
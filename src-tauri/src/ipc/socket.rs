@@ -0,0 +1,179 @@
+//! Both ends of the local sockets: binding them without taking one over from a running
+//! instance, and calling the JSON-RPC API, one call per connection.
+
+use std::{
+    io::{self, BufRead, Write},
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::Path,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// How long a call waits on the running instance before giving up on it.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize)]
+struct Response {
+    #[serde(default)]
+    result: Value,
+    error: Option<ErrorObject>,
+}
+
+#[derive(Deserialize)]
+struct ErrorObject {
+    message: String,
+}
+
+/// Calls `method` on the instance listening on `path`. Fails if there is no such instance,
+/// and answers with the error message if the call itself failed.
+pub fn call(path: &Path, method: &str, params: Value) -> io::Result<Result<Value, String>> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut response = String::new();
+    io::BufReader::new(stream).read_line(&mut response)?;
+    let response: Response = serde_json::from_str(&response)?;
+    Ok(match response.error {
+        Some(error) => Err(error.message),
+        None => Ok(response.result),
+    })
+}
+
+/// Listens on a Unix socket at `path`. A socket left behind by an earlier run is replaced,
+/// one a running instance still listens on is not.
+pub(crate) fn bind(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    match UnixStream::connect(path) {
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another instance", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            // nobody listens on it, but it must really be a socket to be ours to remove
+            if std::fs::symlink_metadata(path)?.file_type().is_socket() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Err(_) => {}
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        os::unix::net::UnixListener,
+        path::PathBuf,
+    };
+
+    use serde_json::{json, Value};
+
+    use super::{bind, call};
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ample-test-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Answers one call on `path` with `answer` applied to the request.
+    fn instance(
+        path: &PathBuf,
+        answer: impl FnOnce(Value) -> Value + Send + 'static,
+    ) -> std::thread::JoinHandle<()> {
+        let listener = UnixListener::bind(path).unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            let response = answer(serde_json::from_str(&line).unwrap()).to_string() + "\n";
+            (&stream).write_all(response.as_bytes()).unwrap();
+        })
+    }
+
+    #[test]
+    fn calls_reach_the_running_instance() {
+        let path = socket_path("forward");
+        let instance = instance(&path, |request| {
+            assert_eq!(request["method"], "queue.enqueue");
+            json!({ "jsonrpc": "2.0", "result": request["params"]["paths"], "id": request["id"] })
+        });
+
+        let result = call(
+            &path,
+            "queue.enqueue",
+            json!({ "paths": ["/music/song.flac"] }),
+        );
+        instance.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(result.unwrap(), Ok(json!(["/music/song.flac"])));
+    }
+
+    #[test]
+    fn failed_calls_answer_with_their_message() {
+        let path = socket_path("failed");
+        let instance = instance(&path, |request| {
+            json!({
+                "jsonrpc": "2.0",
+                "error": { "code": -32000, "message": "library not loaded" },
+                "id": request["id"],
+            })
+        });
+
+        let result = call(&path, "playback.play", Value::Null);
+        instance.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(result.unwrap(), Err("library not loaded".into()));
+    }
+
+    #[test]
+    fn calls_fail_without_a_running_instance() {
+        let path = socket_path("missing");
+        assert!(call(&path, "window.focus", Value::Null).is_err());
+    }
+
+    #[test]
+    fn binding_replaces_a_stale_socket() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let bound = tauri::async_runtime::block_on(async { bind(&path).map(drop) });
+        let _ = std::fs::remove_file(&path);
+        bound.unwrap();
+    }
+
+    #[test]
+    fn binding_leaves_a_running_instance_alone() {
+        let path = socket_path("taken");
+        let _running = UnixListener::bind(&path).unwrap();
+
+        let bound = tauri::async_runtime::block_on(async { bind(&path).map(drop) });
+        let still_there = path.exists();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(bound.unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+        assert!(still_there);
+    }
+
+    #[test]
+    fn binding_leaves_other_files_alone() {
+        let path = socket_path("file");
+        std::fs::write(&path, "").unwrap();
+
+        let bound = tauri::async_runtime::block_on(async { bind(&path).map(drop) });
+        let still_there = path.exists();
+        let _ = std::fs::remove_file(&path);
+        assert!(bound.is_err());
+        assert!(still_there);
+    }
+}
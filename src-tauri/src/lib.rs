@@ -2,15 +2,31 @@ use std::sync::{Arc, RwLock};
 
 use audio::Engine;
 use config::Config;
+use context::Context;
 use error::Result;
-use library::Library;
+use playlist::PlaylistStore;
+use session::SessionStore;
+use tauri::Manager;
 
 pub mod audio;
+pub mod audiobook;
 pub mod cli;
 pub mod config;
+pub mod context;
 pub mod error;
+mod headless;
+pub mod ipc;
 pub mod library;
+pub mod loudness;
+pub mod mpd;
+#[cfg(target_os = "linux")]
+pub mod mpris;
+pub mod playlist;
+#[cfg(unix)]
+pub mod rpc;
+pub mod session;
 pub mod settings;
+mod window;
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
@@ -23,23 +39,69 @@ pub fn run() {
     let cli::Opts {
         settings_path,
         config_path,
+        headless,
+        enqueue,
+        command,
+        paths,
     } = argh::from_env();
 
     let config = Config::load(config_path).unwrap_or_default();
 
+    // the JSON-RPC socket of the instance using these settings, which later launches and
+    // remote commands reach it through. Found without loading the settings, so a remote
+    // command does not leave any behind.
+    let socket = config.rpc_socket.clone().or_else(|| {
+        let settings_file = match &settings_path {
+            Some(path) => path.clone(),
+            None => settings::Settings::default_path().ok()?,
+        };
+        Some(settings_file.parent()?.join("ample.sock"))
+    });
+    if let Some(command) = command {
+        std::process::exit(cli::remote(command, socket.as_deref()));
+    }
+
     let settings = settings::Settings::load_or_create(settings_path)
         .expect("error when loading or creating settings");
 
-    let library = Option::<Library>::None;
+    let paths = cli::absolute(&paths);
+    let launch = ipc::Launch::new(paths, enqueue);
+    // a second launch hands its arguments over instead of playing alongside the first
+    if let Some(socket) = &socket {
+        if cli::forward(socket, &launch) {
+            return;
+        }
+    }
+
+    let playlists = PlaylistStore::load_or_create(settings.sibling_path("playlists.json"))
+        .expect("error when loading playlists");
+
+    let session = SessionStore::load(settings.sibling_path("session.json")).unwrap_or_else(|e| {
+        log::warn!("unable to load last session: {}", e);
+        SessionStore::default()
+    });
+
+    let settings = Arc::new(RwLock::new(settings));
+    let engine =
+        Engine::init(Arc::clone(&settings), &config).expect("error when initializing audio engine");
+
+    if headless {
+        headless::run(settings, engine, session, config, socket, launch);
+        return;
+    }
 
-    let engine = Engine::init().expect("error when initializing audio engine");
+    let shell = Arc::new(window::Window::default());
+    let ctx = Context::new(settings, engine, session, shell.clone());
 
     tauri::Builder::default()
         .plugin(tauri_plugin_dialog::init())
-        .manage(config)
-        .manage(RwLock::new(settings))
-        .manage(Arc::new(RwLock::new(engine)))
-        .manage(RwLock::new(library))
+        .manage(Arc::clone(&ctx.settings))
+        .manage(RwLock::new(playlists))
+        .manage(Arc::clone(&ctx.session))
+        .manage(Arc::clone(&ctx.engine))
+        .manage(Arc::clone(&ctx.library))
+        .manage(ctx.clone())
+        .manage(loudness::LoudnessJob::default())
         .plugin(
             tauri_plugin_log::Builder::new()
                 .level(log::LevelFilter::Info)
@@ -47,19 +109,105 @@ pub fn run() {
                 .build(),
         )
         .plugin(tauri_plugin_opener::init())
+        .setup(move |app| {
+            // the window is left out of the config's startup, as headless mode has none
+            tauri::WebviewWindowBuilder::from_config(app.handle(), &app.config().app.windows[0])?
+                .build()?;
+            shell.attach(app.handle().clone());
+            window::forward_events(app.handle().clone(), &ctx);
+            ctx.spawn_services(&config, socket)?;
+            // songs given to play take the place of the last session
+            if !matches!(launch, ipc::Launch::Open { .. }) {
+                session::restore_at_startup(&ctx);
+            }
+            ipc::launch(&ctx, launch);
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             greet,
             settings::settings_list_libraries,
             settings::settings_save,
+            settings::settings_set_resume_session,
+            audiobook::audiobook_in_progress,
+            audiobook::audiobook_reset,
+            audiobook::audiobook_mark_finished,
+            audiobook::audiobook_set_folder,
+            audiobook::audiobook_set_min_length,
             settings::settings_remove_library,
             library::library_open,
             library::library_list_songs,
+            library::library_rate_song,
+            library::library_bookmarks,
+            library::library_add_bookmark,
+            library::library_remove_bookmark,
+            loudness::loudness_scan,
+            loudness::loudness_cancel,
+            playlist::playlist_list,
+            playlist::playlist_create,
+            playlist::playlist_delete,
+            playlist::playlist_songs,
+            playlist::playlist_add_songs,
+            playlist::playlist_remove_entry,
+            playlist::playlist_import,
+            playlist::playlist_export,
+            playlist::smart::smart_playlist_list,
+            playlist::smart::smart_playlist_save,
+            playlist::smart::smart_playlist_remove,
+            playlist::smart::smart_playlist_songs,
+            playlist::smart::smart_playlist_enqueue,
             audio::song_start,
             audio::song_play,
             audio::song_pause,
+            audio::song_next,
+            audio::song_previous,
             audio::song_seek_forward,
             audio::song_seek_backward,
+            audio::song_seek,
+            audio::song_chapters,
+            audio::queue_list,
+            audio::queue_add,
+            audio::queue_insert,
+            audio::queue_remove,
+            audio::queue_move,
+            audio::queue_clear,
+            audio::queue_shuffle,
+            audio::queue_set_repeat,
+            audio::queue_set_shuffle,
+            audio::queue_jump,
+            audio::audio_set_volume,
+            audio::audio_toggle_mute,
+            audio::audio_set_replay_gain,
+            audio::equalizer_presets,
+            audio::equalizer_get,
+            audio::equalizer_select,
+            audio::equalizer_set_band,
+            audio::equalizer_set_preamp,
+            audio::equalizer_set_bypass,
+            audio::audio_set_speed,
+            audio::audio_forget_speed,
+            audio::audio_set_pitch_correction,
+            audio::audio_devices,
+            audio::audio_output,
+            audio::audio_set_output,
+            audio::audio_set_ab_loop,
+            audio::audio_cycle_ab_loop,
+            audio::song_jump_bookmark,
+            audio::sleep_start,
+            audio::sleep_cancel,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app, event| match event {
+            tauri::RunEvent::Exit => session::save(&app.state::<Context>()),
+            // file associations come as events on macOS rather than as arguments
+            #[cfg(target_os = "macos")]
+            tauri::RunEvent::Opened { urls } => {
+                let paths = urls
+                    .iter()
+                    .filter_map(|url| url.to_file_path().ok())
+                    .collect();
+                ipc::launch(&app.state::<Context>(), ipc::Launch::new(paths, false));
+            }
+            _ => {}
+        });
 }
@@ -1,40 +1,81 @@
 use std::{
     collections::{HashMap, HashSet, VecDeque},
     fmt,
-    path::PathBuf,
-    sync::RwLock,
-    time::Duration,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
 };
 
 use lofty::{
     file::{AudioFile, TaggedFile, TaggedFileExt},
     probe::Probe,
-    tag::Accessor,
+    tag::{Accessor, ItemKey},
 };
 use rayon::prelude::*;
 use serde::Serialize;
 
-use crate::settings::SettingsState;
+use crate::{
+    context::{Context, ContextState},
+    settings::{Bookmark, Settings, SettingsState, MAX_RATING},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("library not loaded")]
+    NotLoaded,
+    #[error("invalid song id {0}")]
+    InvalidId(usize),
+    #[error("{} is not in the library", .0.display())]
+    NotInLibrary(PathBuf),
+    #[error("invalid rating {0}, ratings go from 0 to {max}", max = MAX_RATING)]
+    InvalidRating(u8),
+}
 
 #[derive(Default, Debug)]
 pub struct Library {
     arena: HashMap<usize, DirTree>,
     pub(crate) files: Vec<File>,
+    /// How many of `files` were read with the library, sorted by path. Songs added later
+    /// follow them, so the ids of the others stay the same.
+    walked: usize,
 }
 
 impl Library {
     pub async fn walker(path: PathBuf) -> Result<Self, std::io::Error> {
-        let mut me = Self::default();
+        Self::collect(path.clone(), vec![path]).await
+    }
+
+    /// A collection of just the given songs and folders, for playing them without opening
+    /// or remembering a library.
+    pub async fn ad_hoc(paths: Vec<PathBuf>) -> Result<Self, std::io::Error> {
+        let root = common_ancestor(&paths);
+        Self::collect(root, paths).await
+    }
 
-        let root = DirTree::new(path);
-        me.arena.insert(0, root);
+    /// Reads the songs within `paths`, which are either `root` or files and folders below it.
+    async fn collect(root: PathBuf, paths: Vec<PathBuf>) -> Result<Self, std::io::Error> {
+        let mut me = Self::default();
+        me.arena.insert(0, DirTree::new(root.clone()));
 
-        let mut to_explore = VecDeque::from([0]);
+        let mut to_explore = VecDeque::new();
         let mut visited = HashSet::new();
         let mut children = vec![];
 
         let mut candidate_files = Vec::new();
 
+        for path in paths {
+            if path == root {
+                to_explore.push_back(0);
+            } else if tokio::fs::metadata(&path).await?.is_dir() {
+                let id = me.arena.len();
+                me.arena.insert(id, DirTree::new(path));
+                to_explore.push_back(id);
+            } else {
+                candidate_files.push(path.clone());
+                me.arena.get_mut(&0).unwrap().children.push(path);
+            }
+        }
+
         while let Some(current) = to_explore.pop_front() {
             let path = &me.arena[&current].path;
             if visited.contains(path) {
@@ -62,10 +103,12 @@ impl Library {
                 children.push(path);
             }
 
-            std::mem::swap(
-                &mut me.arena.get_mut(&current).unwrap().children,
-                &mut children,
-            );
+            // the root of an ad-hoc collection already has the files given next to folders
+            me.arena
+                .get_mut(&current)
+                .unwrap()
+                .children
+                .append(&mut children);
         }
 
         // Parallel processing of metadata
@@ -86,9 +129,62 @@ impl Library {
 
         me.files = files;
         me.files.sort_by(|a, b| a.path.cmp(&b.path));
+        // songs given both on their own and with their folder
+        me.files.dedup_by(|a, b| a.path == b.path);
+        me.walked = me.files.len();
 
         Ok(me)
     }
+
+    /// The directory the library was opened from.
+    pub(crate) fn root(&self) -> Option<&Path> {
+        self.arena.get(&0).map(|tree| tree.path.as_path())
+    }
+
+    /// Every directory within the library, including its root.
+    pub(crate) fn directories(&self) -> impl Iterator<Item = &Path> {
+        self.arena.values().map(|tree| tree.path.as_path())
+    }
+
+    /// Looks up the id of the file at `path`, relying on the walked files being sorted by
+    /// path.
+    pub(crate) fn find(&self, path: &Path) -> Option<usize> {
+        let (walked, added) = self.files.split_at(self.walked);
+        walked
+            .binary_search_by(|file| file.path.as_path().cmp(path))
+            .ok()
+            .or_else(|| {
+                let index = added.iter().position(|file| file.path == path)?;
+                Some(self.walked + index)
+            })
+    }
+
+    /// Adds the songs of `other` it does not have yet after its own.
+    pub(crate) fn extend(&mut self, other: Library) {
+        for file in other.files {
+            if self.find(&file.path).is_none() {
+                self.files.push(file);
+            }
+        }
+    }
+}
+
+/// The deepest directory containing all of `paths`.
+fn common_ancestor(paths: &[PathBuf]) -> PathBuf {
+    let mut dirs = paths.iter().map(|path| match path.is_dir() {
+        true => path.as_path(),
+        false => path.parent().unwrap_or(path),
+    });
+    let Some(first) = dirs.next() else {
+        return PathBuf::new();
+    };
+    dirs.fold(first.to_path_buf(), |common, dir| {
+        common
+            .ancestors()
+            .find(|ancestor| dir.starts_with(ancestor))
+            .unwrap_or_else(|| Path::new(""))
+            .to_path_buf()
+    })
 }
 
 #[derive(Debug)]
@@ -109,6 +205,7 @@ impl DirTree {
 pub(crate) struct File {
     pub(crate) path: PathBuf,
     pub(crate) metadata: TaggedFile,
+    pub(crate) added: Option<SystemTime>,
 }
 
 impl fmt::Debug for File {
@@ -126,23 +223,77 @@ pub enum FileInitError {
 impl File {
     pub fn new(path: PathBuf) -> Result<Self, FileInitError> {
         let metadata = Probe::open(&path)?.read()?;
-        Ok(Self { path, metadata })
+        let added = std::fs::metadata(&path)
+            .and_then(|meta| meta.created().or_else(|_| meta.modified()))
+            .ok();
+        Ok(Self {
+            path,
+            metadata,
+            added,
+        })
+    }
+
+    pub(crate) fn title(&self) -> String {
+        self.metadata
+            .primary_tag()
+            .and_then(|tag| tag.title())
+            .map(|title| title.to_string())
+            .unwrap_or_else(|| {
+                self.path
+                    .file_name()
+                    .unwrap_or_else(|| self.path.as_os_str())
+                    .to_string_lossy()
+                    .to_string()
+            })
+    }
+
+    pub(crate) fn album(&self) -> Option<String> {
+        self.metadata
+            .primary_tag()
+            .and_then(|tag| tag.album())
+            .map(|album| album.to_string())
+    }
+
+    pub(crate) fn album_artist(&self) -> Option<String> {
+        self.metadata
+            .primary_tag()
+            .and_then(|tag| tag.get_string(&ItemKey::AlbumArtist))
+            .map(|artist| artist.to_string())
+    }
+
+    pub(crate) fn artist(&self) -> Option<String> {
+        self.metadata
+            .primary_tag()
+            .and_then(|tag| tag.artist())
+            .map(|artist| artist.to_string())
+    }
+
+    pub(crate) fn duration(&self) -> Duration {
+        self.metadata.properties().duration()
+    }
+
+    pub(crate) fn song_data(&self, id: usize) -> SongData {
+        let mut data = SongData::new(id, self.title(), self.duration());
+        data.artist = self.artist();
+        data
     }
 }
 
-pub type LibraryState<'a> = tauri::State<'a, RwLock<Option<Library>>>;
+pub type LibraryState<'a> = tauri::State<'a, Arc<RwLock<Option<Library>>>>;
 
 #[tauri::command]
-pub async fn library_open(
-    path: String,
-    library: LibraryState<'_>,
-    settings: SettingsState<'_>,
-) -> crate::Result<()> {
-    let path: PathBuf = path.into();
-    let new_library = Library::walker(path.clone()).await?;
-    library.write().unwrap().replace(new_library);
+pub async fn library_open(path: String, context: ContextState<'_>) -> crate::Result<()> {
+    open(&context, path.into()).await
+}
 
-    let mut settings = settings.write().unwrap();
+/// Opens the library at `path`, restores the last session into it and remembers it as the
+/// most recent one.
+pub(crate) async fn open(ctx: &Context, path: PathBuf) -> crate::Result<()> {
+    let library = Library::walker(path.clone()).await?;
+    crate::session::restore(ctx, &library);
+    ctx.replace_library(library);
+
+    let mut settings = ctx.settings.write().unwrap();
     settings.libraries.retain(|p| {
         p.to_string_lossy().to_ascii_lowercase() != path.to_string_lossy().to_ascii_lowercase()
     });
@@ -154,6 +305,19 @@ pub async fn library_open(
     Ok(())
 }
 
+/// Opens the library used last, for when there is no window to pick one in.
+pub fn open_last(ctx: Context) {
+    let Some(path) = ctx.settings.read().unwrap().libraries.front().cloned() else {
+        log::warn!("no library to open, add one from the window first");
+        return;
+    };
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = open(&ctx, path).await {
+            log::error!("failed to open library: {}", e);
+        }
+    });
+}
+
 #[derive(Debug, Serialize)]
 pub struct SongData {
     id: usize,
@@ -175,38 +339,271 @@ impl SongData {
 
 #[tauri::command]
 pub fn library_list_songs(library: LibraryState<'_>) -> Vec<SongData> {
-    let library = library.read().unwrap();
-    let Some(library) = library.as_ref() else {
+    songs(library.read().unwrap().as_ref())
+}
+
+/// Every song of `library`, none if there is no library.
+pub(crate) fn songs(library: Option<&Library>) -> Vec<SongData> {
+    let Some(library) = library else {
         return vec![];
     };
     library
         .files
         .iter()
         .enumerate()
-        .map(|(id, file)| {
-            let mut data = SongData::new(
-                id,
-                file.path
-                    .file_name()
-                    .unwrap_or_else(|| file.path.as_os_str())
-                    .to_string_lossy()
-                    .to_string(),
-                file.metadata.properties().duration(),
-            );
+        .map(|(id, file)| file.song_data(id))
+        .collect()
+}
 
-            let Some(tag) = file.metadata.primary_tag() else {
-                return data;
-            };
+#[tauri::command]
+pub fn library_bookmarks(
+    id: usize,
+    library: LibraryState<'_>,
+    settings: SettingsState<'_>,
+) -> crate::Result<Vec<Bookmark>> {
+    bookmarks(&library, &settings, id)
+}
 
-            if let Some(title) = tag.title() {
-                data.title = title.to_string();
-            }
+#[tauri::command]
+pub fn library_add_bookmark(
+    id: usize,
+    name: String,
+    seconds: f64,
+    library: LibraryState<'_>,
+    settings: SettingsState<'_>,
+) -> crate::Result<()> {
+    add_bookmark(&library, &settings, id, name, seconds)
+}
 
-            if let Some(artist) = tag.artist() {
-                data.artist = Some(artist.to_string());
-            }
+#[tauri::command]
+pub fn library_remove_bookmark(
+    id: usize,
+    name: String,
+    library: LibraryState<'_>,
+    settings: SettingsState<'_>,
+) -> crate::Result<()> {
+    remove_bookmark(&library, &settings, id, &name)
+}
 
-            data
-        })
-        .collect()
+/// The bookmarks of the song `id`, in the order of their positions.
+pub(crate) fn bookmarks(
+    library: &RwLock<Option<Library>>,
+    settings: &RwLock<Settings>,
+    id: usize,
+) -> crate::Result<Vec<Bookmark>> {
+    let library = library.read().unwrap();
+    let file = library
+        .as_ref()
+        .ok_or(Error::NotLoaded)?
+        .files
+        .get(id)
+        .ok_or(Error::InvalidId(id))?;
+
+    let settings = settings.read().unwrap();
+    Ok(settings
+        .stats
+        .get(&file.path)
+        .map(|stats| stats.bookmarks.clone())
+        .unwrap_or_default())
+}
+
+/// Adds a bookmark at `seconds` into the song `id`, replacing one with the same name.
+pub(crate) fn add_bookmark(
+    library: &RwLock<Option<Library>>,
+    settings: &RwLock<Settings>,
+    id: usize,
+    name: String,
+    seconds: f64,
+) -> crate::Result<()> {
+    let library = library.read().unwrap();
+    let file = library
+        .as_ref()
+        .ok_or(Error::NotLoaded)?
+        .files
+        .get(id)
+        .ok_or(Error::InvalidId(id))?;
+
+    let mut settings = settings.write().unwrap();
+    let bookmark = Bookmark {
+        name,
+        seconds: seconds.max(0.0),
+    };
+    settings.add_bookmark(file.path.clone(), bookmark);
+    settings.save()?;
+    Ok(())
+}
+
+pub(crate) fn remove_bookmark(
+    library: &RwLock<Option<Library>>,
+    settings: &RwLock<Settings>,
+    id: usize,
+    name: &str,
+) -> crate::Result<()> {
+    let library = library.read().unwrap();
+    let file = library
+        .as_ref()
+        .ok_or(Error::NotLoaded)?
+        .files
+        .get(id)
+        .ok_or(Error::InvalidId(id))?;
+
+    let mut settings = settings.write().unwrap();
+    if let Some(stats) = settings.stats.get_mut(&file.path) {
+        stats.bookmarks.retain(|bookmark| bookmark.name != name);
+    }
+    settings.save()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn library_rate_song(
+    id: usize,
+    rating: u8,
+    library: LibraryState<'_>,
+    settings: SettingsState<'_>,
+) -> crate::Result<()> {
+    if rating > MAX_RATING {
+        return Err(Error::InvalidRating(rating).into());
+    }
+    let library = library.read().unwrap();
+    let library = library.as_ref().ok_or(Error::NotLoaded)?;
+    let file = library.files.get(id).ok_or(Error::InvalidId(id))?;
+
+    let mut settings = settings.write().unwrap();
+    settings.stats.entry(file.path.clone()).or_default().rating = Some(rating);
+    settings.save()?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::{Path, PathBuf};
+
+    use lofty::{
+        file::TaggedFileExt,
+        tag::{Accessor, Tag, TagType},
+    };
+
+    use super::{common_ancestor, File, Library};
+
+    /// A silent mono WAV file, which is enough for reading metadata.
+    fn write_wav(path: &Path) {
+        let data = [0u8; 1600];
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&data);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, wav).unwrap();
+    }
+
+    pub(crate) fn songs_dir(name: &str, songs: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ample-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for song in songs {
+            write_wav(&dir.join(song));
+        }
+        dir
+    }
+
+    /// A library of the silent `songs` in a fresh directory, which is returned along with it.
+    pub(crate) fn library(name: &str, songs: &[&str]) -> (PathBuf, Library) {
+        let dir = songs_dir(name, songs);
+        let library = tauri::async_runtime::block_on(Library::walker(dir.clone())).unwrap();
+        (dir, library)
+    }
+
+    /// Tags `file` as a song by `artist` on `album`, only in memory, which is where the tags
+    /// are read from.
+    pub(crate) fn tag(file: &mut File, artist: &str, album: &str) {
+        let mut tag = Tag::new(TagType::Id3v2);
+        tag.set_artist(artist.into());
+        tag.set_album(album.into());
+        file.metadata.insert_tag(tag);
+    }
+
+    fn paths(library: &Library, dir: &Path) -> Vec<PathBuf> {
+        library
+            .files
+            .iter()
+            .map(|file| file.path.strip_prefix(dir).unwrap().to_path_buf())
+            .collect()
+    }
+
+    #[test]
+    fn common_ancestor_is_the_deepest_shared_directory() {
+        let ancestor =
+            |paths: &[&str]| common_ancestor(&paths.iter().map(PathBuf::from).collect::<Vec<_>>());
+        assert_eq!(
+            ancestor(&["/music/a/1.flac", "/music/b/2.flac"]),
+            Path::new("/music")
+        );
+        assert_eq!(ancestor(&["/music/a/1.flac"]), Path::new("/music/a"));
+        assert_eq!(
+            ancestor(&["/music/a/1.flac", "/podcasts/1.mp3"]),
+            Path::new("/")
+        );
+        assert_eq!(ancestor(&[]), Path::new(""));
+    }
+
+    #[test]
+    fn common_ancestor_of_a_directory_is_itself() {
+        let dir = songs_dir("ancestor", &["album/1.wav"]);
+        let album = dir.join("album");
+        assert_eq!(common_ancestor(&[album.clone()]), album);
+        assert_eq!(common_ancestor(&[album.join("1.wav"), dir.clone()]), dir);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ad_hoc_collections_list_songs_given_twice_once() {
+        let dir = songs_dir("ad-hoc", &["album/1.wav", "album/2.wav"]);
+        let album = dir.join("album");
+        let library = tauri::async_runtime::block_on(Library::ad_hoc(vec![
+            album.join("1.wav"),
+            album.clone(),
+            album.join("2.wav"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            paths(&library, &dir),
+            [Path::new("album/1.wav"), Path::new("album/2.wav")]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn added_songs_keep_the_ids_of_the_library() {
+        let dir = songs_dir("extend", &["album/1.wav", "album/2.wav", "other/3.wav"]);
+        let mut library =
+            tauri::async_runtime::block_on(Library::walker(dir.join("album"))).unwrap();
+        let other = tauri::async_runtime::block_on(Library::ad_hoc(vec![
+            dir.join("other/3.wav"),
+            dir.join("album/2.wav"),
+        ]))
+        .unwrap();
+        library.extend(other);
+        assert_eq!(
+            paths(&library, &dir),
+            [
+                Path::new("album/1.wav"),
+                Path::new("album/2.wav"),
+                Path::new("other/3.wav")
+            ]
+        );
+        assert_eq!(library.find(&dir.join("album/2.wav")), Some(1));
+        assert_eq!(library.find(&dir.join("other/3.wav")), Some(2));
+        assert_eq!(library.find(&dir.join("other/4.wav")), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,535 @@
+//! Background EBU R128 loudness analysis which writes ReplayGain tags, so mpv's `replaygain`
+//! option has something to work with.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use ebur128::{EbuR128, Mode};
+use lofty::{
+    config::WriteOptions,
+    file::{AudioFile, FileType, TaggedFileExt},
+    probe::Probe,
+    tag::{ItemKey, Tag},
+};
+use rayon::prelude::*;
+use serde::Serialize;
+use symphonia::core::{
+    audio::SampleBuffer, codecs::DecoderOptions, errors::Error as SymphoniaError,
+    formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+use tauri::{Emitter, Manager};
+
+use crate::library::{self, LibraryState};
+
+/// ReplayGain 2.0 reference level in LUFS.
+const REPLAYGAIN_REFERENCE: f64 = -18.0;
+/// Reference level of the `R128_*_GAIN` tags used by Opus, in LUFS.
+const R128_REFERENCE: f64 = -23.0;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("a loudness scan is already running")]
+    AlreadyRunning,
+    #[error("no audio track found")]
+    NoAudioTrack,
+    #[error("unable to read file: {0}")]
+    Io(#[from] io::Error),
+    #[error("unable to decode file: {0}")]
+    Decode(#[from] SymphoniaError),
+    #[error("unable to measure loudness: {0}")]
+    Measure(#[from] ebur128::Error),
+    #[error("unable to write tags: {0}")]
+    Tag(#[from] lofty::error::LoftyError),
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS.
+    pub integrated: f64,
+    /// Linear true peak, 1.0 being full scale.
+    pub true_peak: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LoudnessResult {
+    pub id: usize,
+    pub track: Option<Loudness>,
+    pub album: Option<Loudness>,
+    pub error: Option<String>,
+}
+
+/// What tells albums apart: two discs in one folder are one album, two albums of the same
+/// name in different folders are not.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct AlbumKey {
+    album_artist: Option<String>,
+    album: String,
+    directory: PathBuf,
+}
+
+impl AlbumKey {
+    fn of(file: &library::File) -> Option<Self> {
+        Some(Self {
+            album_artist: file.album_artist(),
+            album: file.album()?,
+            directory: file.path.parent()?.to_path_buf(),
+        })
+    }
+}
+
+/// Songs which are scanned together, which are the songs of an album or, without an album,
+/// any songs.
+struct Group {
+    album: Option<AlbumKey>,
+    tracks: Vec<(usize, PathBuf)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct LoudnessProgress {
+    done: usize,
+    total: usize,
+    path: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct LoudnessFinished {
+    cancelled: bool,
+    dry_run: bool,
+    results: Vec<LoudnessResult>,
+}
+
+/// Measures a whole file, returning `None` if cancelled half way through.
+fn measure(path: &Path, cancel: &AtomicBool) -> Result<Option<EbuR128>, Error> {
+    let file = std::fs::File::open(path)?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+    let track = format.default_track().ok_or(Error::NoAudioTrack)?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut meter: Option<EbuR128> = None;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // symphonia reports the end of the stream as an unexpected eof
+            Err(SymphoniaError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphoniaError::DecodeError(e)) => {
+                log::warn!("skipping undecodable packet in {:?}: {}", path, e);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = *decoded.spec();
+        if meter.is_none() {
+            meter = Some(EbuR128::new(
+                spec.channels.count() as u32,
+                spec.rate,
+                Mode::I | Mode::TRUE_PEAK | Mode::HISTOGRAM,
+            )?);
+        }
+        let needed = decoded.capacity() * spec.channels.count();
+        if samples.as_ref().is_none_or(|buf| buf.capacity() < needed) {
+            samples = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+
+        let buf = samples.as_mut().unwrap();
+        buf.copy_interleaved_ref(decoded);
+        meter.as_mut().unwrap().add_frames_f32(buf.samples())?;
+    }
+
+    meter.map(Some).ok_or(Error::NoAudioTrack)
+}
+
+fn loudness(meter: &EbuR128) -> Result<Loudness, Error> {
+    let true_peak = (0..meter.channels())
+        .map(|channel| meter.true_peak(channel))
+        .try_fold(0.0_f64, |peak, channel_peak| {
+            channel_peak.map(|channel_peak| peak.max(channel_peak))
+        })?;
+    Ok(Loudness {
+        integrated: meter.loudness_global()?,
+        true_peak,
+    })
+}
+
+/// `R128_*_GAIN` tags are a Q7.8 fixed point number of dB.
+fn r128_gain(integrated: f64) -> String {
+    (((R128_REFERENCE - integrated) * 256.0).round() as i16).to_string()
+}
+
+/// `REPLAYGAIN_*_GAIN` tags are dB with two decimals and a unit.
+fn replaygain_gain(integrated: f64) -> String {
+    format!("{:.2} dB", REPLAYGAIN_REFERENCE - integrated)
+}
+
+fn write_tags(path: &Path, track: Loudness, album: Option<Loudness>) -> Result<(), Error> {
+    let mut file = Probe::open(path)?.read()?;
+    let is_opus = file.file_type() == FileType::Opus;
+    if file.primary_tag().is_none() {
+        file.insert_tag(Tag::new(file.primary_tag_type()));
+    }
+    let tag = file
+        .primary_tag_mut()
+        .expect("primary tag was just inserted");
+
+    if is_opus {
+        // opus players ignore `REPLAYGAIN_*` in favour of these, which have no peak
+        tag.insert_text(
+            ItemKey::Unknown("R128_TRACK_GAIN".into()),
+            r128_gain(track.integrated),
+        );
+        if let Some(album) = album {
+            tag.insert_text(
+                ItemKey::Unknown("R128_ALBUM_GAIN".into()),
+                r128_gain(album.integrated),
+            );
+        }
+    } else {
+        tag.insert_text(
+            ItemKey::ReplayGainTrackGain,
+            replaygain_gain(track.integrated),
+        );
+        tag.insert_text(
+            ItemKey::ReplayGainTrackPeak,
+            format!("{:.6}", track.true_peak),
+        );
+        if let Some(album) = album {
+            tag.insert_text(
+                ItemKey::ReplayGainAlbumGain,
+                replaygain_gain(album.integrated),
+            );
+            tag.insert_text(
+                ItemKey::ReplayGainAlbumPeak,
+                format!("{:.6}", album.true_peak),
+            );
+        }
+    }
+
+    file.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+/// Measures and tags one group after the other, so only the meters of one album are kept
+/// at a time. Tags are not written if `dry_run` is set.
+fn scan(
+    app: &tauri::AppHandle,
+    groups: Vec<Group>,
+    dry_run: bool,
+    cancel: &AtomicBool,
+) -> LoudnessFinished {
+    let total = groups.iter().map(|group| group.tracks.len()).sum();
+    let done = AtomicUsize::new(0);
+    let mut results = Vec::with_capacity(total);
+
+    for group in groups {
+        // the meters are only needed to measure an album as a whole
+        let measured: Vec<(Result<Loudness, Error>, Option<EbuR128>)> = group
+            .tracks
+            .par_iter()
+            .map(|(_, path)| {
+                let meter = measure(path, cancel);
+                let progress = LoudnessProgress {
+                    done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                    total,
+                    path: path.clone(),
+                };
+                if let Err(e) = app.emit("loudness_progress", progress) {
+                    log::error!("failed to emit loudness progress: {}", e);
+                }
+                match meter.and_then(|meter| meter.ok_or(Error::NoAudioTrack)) {
+                    Ok(meter) => (loudness(&meter), group.album.as_ref().map(|_| meter)),
+                    Err(e) => (Err(e), None),
+                }
+            })
+            .collect();
+
+        if cancel.load(Ordering::Relaxed) {
+            return LoudnessFinished {
+                cancelled: true,
+                dry_run,
+                results: vec![],
+            };
+        }
+
+        let album = album_loudness(&measured);
+        for ((id, path), (track, _)) in group.tracks.iter().zip(measured) {
+            let written = track.and_then(|track| {
+                if !dry_run {
+                    write_tags(path, track, album)?;
+                }
+                Ok(track)
+            });
+            results.push(match written {
+                Ok(track) => LoudnessResult {
+                    id: *id,
+                    track: Some(track),
+                    album,
+                    error: None,
+                },
+                Err(e) => {
+                    log::error!("loudness scan failed for {:?}: {}", path, e);
+                    LoudnessResult {
+                        id: *id,
+                        track: None,
+                        album: None,
+                        error: Some(e.to_string()),
+                    }
+                }
+            });
+        }
+    }
+
+    LoudnessFinished {
+        cancelled: false,
+        dry_run,
+        results,
+    }
+}
+
+/// The loudness of an album measured as one, if there are meters of it.
+fn album_loudness(measured: &[(Result<Loudness, Error>, Option<EbuR128>)]) -> Option<Loudness> {
+    let meters: Vec<&EbuR128> = measured
+        .iter()
+        .filter_map(|(_, meter)| meter.as_ref())
+        .collect();
+    if meters.is_empty() {
+        return None;
+    }
+    let integrated = EbuR128::loudness_global_multiple(meters.into_iter()).ok()?;
+    let true_peak = measured
+        .iter()
+        .filter_map(|(track, _)| track.as_ref().ok())
+        .map(|track| track.true_peak)
+        .fold(0.0, f64::max);
+    Some(Loudness {
+        integrated,
+        true_peak,
+    })
+}
+
+/// The songs with `ids` along with the rest of their albums, whose gain depends on all of
+/// them, grouped by album.
+fn groups(library: &library::Library, ids: Vec<usize>) -> crate::Result<Vec<Group>> {
+    let mut albums = BTreeSet::new();
+    let mut singles = Vec::new();
+    for id in ids {
+        let file = library.files.get(id).ok_or(library::Error::InvalidId(id))?;
+        match AlbumKey::of(file) {
+            Some(album) => {
+                albums.insert(album);
+            }
+            None => singles.push((id, file.path.clone())),
+        }
+    }
+
+    let mut tracks: BTreeMap<AlbumKey, Vec<(usize, PathBuf)>> = BTreeMap::new();
+    for (id, file) in library.files.iter().enumerate() {
+        if let Some(album) = AlbumKey::of(file).filter(|album| albums.contains(album)) {
+            tracks
+                .entry(album)
+                .or_default()
+                .push((id, file.path.clone()));
+        }
+    }
+
+    let mut groups: Vec<Group> = tracks
+        .into_iter()
+        .map(|(album, tracks)| Group {
+            album: Some(album),
+            tracks,
+        })
+        .collect();
+    if !singles.is_empty() {
+        groups.push(Group {
+            album: None,
+            tracks: singles,
+        });
+    }
+    Ok(groups)
+}
+
+/// Cancellation flag of the running scan, if there is one.
+#[derive(Debug, Default)]
+pub struct LoudnessJob(Mutex<Option<Arc<AtomicBool>>>);
+
+pub type LoudnessJobState<'a> = tauri::State<'a, LoudnessJob>;
+
+/// Clears the running scan once it is over, however it ended.
+struct Finish(tauri::AppHandle);
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        *self.0.state::<LoudnessJob>().0.lock().unwrap() = None;
+    }
+}
+
+/// Starts scanning the songs with the given ids, or the whole library if `ids` is not given.
+/// The rest of their albums is scanned as well. Progress is reported through
+/// `loudness_progress` events and the results through a `loudness_finished` event.
+#[tauri::command]
+pub fn loudness_scan(
+    ids: Option<Vec<usize>>,
+    dry_run: bool,
+    app: tauri::AppHandle,
+    library: LibraryState<'_>,
+    job: LoudnessJobState<'_>,
+) -> crate::Result<()> {
+    let library = library.read().unwrap();
+    let library = library.as_ref().ok_or(library::Error::NotLoaded)?;
+    let ids = ids.unwrap_or_else(|| (0..library.files.len()).collect());
+    let groups = groups(library, ids)?;
+
+    let mut running = job.0.lock().unwrap();
+    if running.is_some() {
+        return Err(Error::AlreadyRunning)?;
+    }
+    let cancel = Arc::new(AtomicBool::new(false));
+    *running = Some(Arc::clone(&cancel));
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let finish = Finish(app.clone());
+        let finished = scan(&app, groups, dry_run, &cancel);
+        drop(finish);
+        if let Err(e) = app.emit("loudness_finished", finished) {
+            log::error!("failed to emit loudness results: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn loudness_cancel(job: LoudnessJobState<'_>) {
+    if let Some(cancel) = job.0.lock().unwrap().as_ref() {
+        cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use ebur128::{EbuR128, Mode};
+
+    use super::{album_loudness, groups, r128_gain, replaygain_gain, Error, Group, Loudness};
+    use crate::library::tests::{library, tag};
+
+    /// A meter which measured two seconds of a 1 kHz tone at `amplitude`.
+    fn meter(amplitude: f32) -> EbuR128 {
+        let mut meter = EbuR128::new(1, 48000, Mode::I).unwrap();
+        let tone: Vec<f32> = (0..2 * 48000)
+            .map(|i| amplitude * (2.0 * PI * 1000.0 * i as f32 / 48000.0).sin())
+            .collect();
+        meter.add_frames_f32(&tone).unwrap();
+        meter
+    }
+
+    fn track(true_peak: f64) -> Result<Loudness, Error> {
+        Ok(Loudness {
+            integrated: 0.0,
+            true_peak,
+        })
+    }
+
+    #[test]
+    fn albums_are_measured_as_one() {
+        assert!(album_loudness(&[(track(0.5), None)]).is_none());
+
+        let alone = meter(0.5).loudness_global().unwrap();
+        let album = album_loudness(&[
+            (track(0.5), Some(meter(0.5))),
+            (track(0.7), Some(meter(0.5))),
+            (Err(Error::NoAudioTrack), None),
+        ])
+        .unwrap();
+        assert!((album.integrated - alone).abs() < 0.01);
+        assert_eq!(album.true_peak, 0.7);
+
+        // not so quiet that the relative gate leaves the quiet track out
+        let quiet = meter(0.25).loudness_global().unwrap();
+        let album = album_loudness(&[
+            (track(0.5), Some(meter(0.5))),
+            (track(0.25), Some(meter(0.25))),
+        ])
+        .unwrap();
+        assert!(quiet < album.integrated && album.integrated < alone);
+    }
+
+    #[test]
+    fn songs_are_scanned_with_the_rest_of_their_album() {
+        let (dir, mut library) = library(
+            "loudness-groups",
+            &["a/1.wav", "a/2.wav", "b/3.wav", "b/4.wav", "single.wav"],
+        );
+        tag(&mut library.files[0], "A", "One");
+        tag(&mut library.files[1], "A", "One");
+        // an album of the same name in another folder is another album
+        tag(&mut library.files[2], "A", "One");
+        tag(&mut library.files[3], "B", "Two");
+        let ids = |groups: &[Group]| -> Vec<Vec<usize>> {
+            groups
+                .iter()
+                .map(|group| group.tracks.iter().map(|(id, _)| *id).collect())
+                .collect()
+        };
+
+        assert_eq!(ids(&groups(&library, vec![0]).unwrap()), [vec![0, 1]]);
+        let scanned = groups(&library, vec![4, 2, 1, 0]).unwrap();
+        assert_eq!(ids(&scanned), [vec![0, 1], vec![2], vec![4]]);
+        let albums: Vec<_> = scanned.iter().map(|group| group.album.is_some()).collect();
+        assert_eq!(albums, [true, true, false]);
+        assert!(groups(&library, vec![9]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn r128_gains_are_q7_8_relative_to_minus_23() {
+        assert_eq!(r128_gain(-23.0), "0");
+        assert_eq!(r128_gain(-18.0), "-1280");
+        assert_eq!(r128_gain(-30.5), "1920");
+        // far too quiet to be represented, so as loud as it gets
+        assert_eq!(r128_gain(-200.0), i16::MAX.to_string());
+    }
+
+    #[test]
+    fn replaygain_gains_are_db_relative_to_minus_18() {
+        assert_eq!(replaygain_gain(-18.0), "0.00 dB");
+        assert_eq!(replaygain_gain(-9.0), "-9.00 dB");
+        assert_eq!(replaygain_gain(-23.5), "5.50 dB");
+    }
+}
@@ -0,0 +1,732 @@
+//! The MPD commands, mapped onto the library and the audio engine.
+
+use std::{
+    collections::BTreeSet,
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use super::{
+    protocol::{parse_filters, relative_uri, tag_value, write_song, Ack, AckCode, Filter, TAGS},
+    Shared,
+};
+use crate::{
+    audio::{Engine, QueueEntry, Repeat, SeekPrecision, SeekTarget, Shuffle},
+    context,
+    library::Library,
+};
+
+/// Commands this server understands, as reported by `commands`.
+const COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clear",
+    "close",
+    "commands",
+    "currentsong",
+    "delete",
+    "deleteid",
+    "find",
+    "idle",
+    "list",
+    "lsinfo",
+    "move",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistid",
+    "playlistinfo",
+    "plchanges",
+    "plchangesposid",
+    "previous",
+    "random",
+    "repeat",
+    "search",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "single",
+    "stats",
+    "status",
+    "stop",
+    "tagtypes",
+];
+
+pub(crate) struct Context<'a> {
+    pub(crate) core: &'a context::Context,
+    pub(crate) shared: &'a Shared,
+}
+
+impl Context<'_> {
+    fn engine(&self) -> &RwLock<Engine> {
+        &self.core.engine
+    }
+
+    /// Runs `f` with the library and its root directory, or fails if none is open.
+    fn with_library<T>(&self, f: impl FnOnce(&Library, &Path) -> Result<T, Ack>) -> Result<T, Ack> {
+        let library = self.core.library.read().unwrap();
+        let library = library
+            .as_ref()
+            .ok_or_else(|| Ack::new(AckCode::NoExist, "no library is open"))?;
+        let root = library.root().unwrap_or_else(|| Path::new(""));
+        f(library, root)
+    }
+
+    /// Applies a change to the queue and tells the frontend and idle clients about it.
+    fn change_queue<T>(&self, f: impl FnOnce(&mut Engine) -> Result<T, Ack>) -> Result<T, Ack> {
+        self.core.change_queue(f)
+    }
+}
+
+/// Runs the commands of a connection, which apart from tests are the real ones below.
+pub(crate) trait Execute {
+    /// Runs one command, returning the response without the final `OK`.
+    fn execute(&self, command: &str, args: &[String]) -> Result<String, Ack>;
+}
+
+impl Execute for Context<'_> {
+    fn execute(&self, command: &str, args: &[String]) -> Result<String, Ack> {
+        execute(self, command, args)
+    }
+}
+
+fn arg<T: std::str::FromStr>(args: &[String], index: usize) -> Result<T, Ack> {
+    let arg = args
+        .get(index)
+        .ok_or_else(|| Ack::arg("too few arguments"))?;
+    arg.parse()
+        .map_err(|_| Ack::arg(format!("invalid argument {arg:?}")))
+}
+
+fn optional_arg<T: std::str::FromStr>(args: &[String], index: usize) -> Result<Option<T>, Ack> {
+    match args.get(index) {
+        Some(_) => arg(args, index).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Parses `N` or `START:END` (with an open end) into a range of queue positions.
+fn range(arg: &str, len: usize) -> Result<std::ops::Range<usize>, Ack> {
+    let invalid = || Ack::arg(format!("invalid range {arg:?}"));
+    let (start, end) = match arg.split_once(':') {
+        Some((start, "")) => (start.parse().map_err(|_| invalid())?, len),
+        Some((start, end)) => (
+            start.parse().map_err(|_| invalid())?,
+            end.parse().map_err(|_| invalid())?,
+        ),
+        None => {
+            let index: usize = arg.parse().map_err(|_| invalid())?;
+            (index, index + 1)
+        }
+    };
+    if start > end || end > len {
+        return Err(Ack::arg("bad song index"));
+    }
+    Ok(start..end)
+}
+
+fn bool_arg(args: &[String]) -> Result<bool, Ack> {
+    match args.first().map(String::as_str) {
+        Some("0") => Ok(false),
+        Some("1") => Ok(true),
+        _ => Err(Ack::arg("expected 0 or 1")),
+    }
+}
+
+fn execute(ctx: &Context<'_>, command: &str, args: &[String]) -> Result<String, Ack> {
+    let mut out = String::new();
+    match command {
+        "ping" => {}
+        "commands" => {
+            for command in COMMANDS {
+                let _ = writeln!(out, "command: {}", command);
+            }
+        }
+        "notcommands" => {}
+        "tagtypes" => {
+            for tag in TAGS {
+                let _ = writeln!(out, "tagtype: {}", tag);
+            }
+        }
+        "outputs" => {
+            out.push_str("outputid: 0\noutputname: ample\nplugin: mpv\noutputenabled: 1\n");
+        }
+        "status" => status(ctx, &mut out),
+        "stats" => {
+            ctx.with_library(|library, _| {
+                let mut artists = BTreeSet::new();
+                let mut albums = BTreeSet::new();
+                let mut playtime = 0.0;
+                for file in &library.files {
+                    artists.extend(file.artist());
+                    albums.extend(file.album());
+                    playtime += file.duration().as_secs_f64();
+                }
+                let _ = writeln!(out, "artists: {}", artists.len());
+                let _ = writeln!(out, "albums: {}", albums.len());
+                let _ = writeln!(out, "songs: {}", library.files.len());
+                let _ = writeln!(out, "db_playtime: {}", playtime.round() as u64);
+                Ok(())
+            })?;
+            let _ = writeln!(out, "uptime: {}", ctx.shared.started.elapsed().as_secs());
+        }
+        "currentsong" => {
+            let engine = ctx.engine().read().unwrap();
+            let (status, _) = engine.get_status();
+            if let Some(id) = status.song_id {
+                ctx.with_library(|library, root| {
+                    if let Some(file) = library.files.get(id) {
+                        let queued = status.queue_index.zip(engine.queue.current());
+                        let queued = queued.map(|(pos, entry)| (pos, entry.queue_id));
+                        write_song(&mut out, file, root, queued);
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+
+        // playback
+        "play" => {
+            let position: Option<usize> = optional_arg(args, 0)?;
+            play(ctx, position)?;
+        }
+        "playid" => {
+            let id: Option<usize> = optional_arg(args, 0)?;
+            let position = id.map(|id| queue_position(ctx, id)).transpose()?;
+            play(ctx, position)?;
+        }
+        "pause" => {
+            let mut engine = ctx.engine().write().unwrap();
+            match args.first() {
+                None => engine.toggle()?,
+                Some(_) if bool_arg(args)? => engine.pause()?,
+                Some(_) => engine.play()?,
+            }
+        }
+        "stop" => ctx.engine().write().unwrap().stop()?,
+        "next" => {
+            ctx.engine().write().unwrap().next()?;
+        }
+        "previous" => ctx.engine().write().unwrap().previous()?,
+        "seek" => {
+            let position: usize = arg(args, 0)?;
+            seek(ctx, position, arg(args, 1)?)?;
+        }
+        "seekid" => {
+            let position = queue_position(ctx, arg(args, 0)?)?;
+            seek(ctx, position, arg(args, 1)?)?;
+        }
+        "seekcur" => {
+            let time = args.first().ok_or_else(|| Ack::arg("too few arguments"))?;
+            let seconds: f64 = time
+                .parse()
+                .map_err(|_| Ack::arg(format!("invalid time {time:?}")))?;
+            let target = if time.starts_with(['+', '-']) {
+                SeekTarget::Relative { seconds }
+            } else {
+                SeekTarget::Absolute { seconds }
+            };
+            ctx.engine()
+                .write()
+                .unwrap()
+                .seek(target, SeekPrecision::Exact)?;
+        }
+        "setvol" => {
+            let volume: u32 = arg(args, 0)?;
+            ctx.engine()
+                .write()
+                .unwrap()
+                .set_volume(volume.min(100) as f64)?;
+        }
+        "repeat" | "single" => {
+            let enabled = bool_arg(args)?;
+            let current = ctx.engine().read().unwrap().queue.repeat();
+            let repeat = match (command, enabled) {
+                ("repeat", true) if current == Repeat::One => Repeat::One,
+                ("repeat", true) => Repeat::All,
+                ("repeat", false) => Repeat::Off,
+                (_, true) => Repeat::One,
+                (_, false) if current == Repeat::One => Repeat::All,
+                (_, false) => current,
+            };
+            ctx.change_queue(|engine| Ok(engine.set_repeat(repeat)?))?;
+        }
+        "random" => {
+            let shuffle = if bool_arg(args)? {
+                Shuffle::Tracks
+            } else {
+                Shuffle::Off
+            };
+            ctx.change_queue(|engine| Ok(engine.set_shuffle(shuffle)?))?;
+        }
+
+        // queue
+        "add" | "addid" => {
+            let uri = args.first().ok_or_else(|| Ack::arg("too few arguments"))?;
+            let position: Option<usize> = optional_arg(args, 1)?;
+            let entries = ctx.with_library(|library, root| entries_under(library, root, uri))?;
+            if command == "addid" && entries.len() != 1 {
+                return Err(Ack::new(AckCode::NoExist, "addid needs a single song"));
+            }
+            let id = ctx.change_queue(|engine| {
+                match position {
+                    Some(position) => engine.queue.insert(position, entries)?,
+                    None => engine.queue.add(entries),
+                }
+                // queue ids count up, so the entry added last has the highest
+                Ok(engine
+                    .queue
+                    .entries()
+                    .iter()
+                    .map(|entry| entry.queue_id)
+                    .max())
+            })?;
+            if let (true, Some(id)) = (command == "addid", id) {
+                let _ = writeln!(out, "Id: {}", id);
+            }
+        }
+        "delete" => {
+            let len = ctx.engine().read().unwrap().queue.len();
+            let range = range(
+                args.first().ok_or_else(|| Ack::arg("too few arguments"))?,
+                len,
+            )?;
+            ctx.change_queue(|engine| {
+                for index in range.rev() {
+                    engine.queue.remove(index)?;
+                }
+                Ok(())
+            })?;
+        }
+        "deleteid" => {
+            let position = queue_position(ctx, arg(args, 0)?)?;
+            ctx.change_queue(|engine| {
+                engine.queue.remove(position)?;
+                Ok(())
+            })?;
+        }
+        "clear" => ctx.change_queue(|engine| {
+            engine.queue.clear();
+            Ok(())
+        })?,
+        "move" => {
+            let from: usize = arg(args, 0)?;
+            let to: usize = arg(args, 1)?;
+            ctx.change_queue(|engine| Ok(engine.queue.move_entry(from, to)?))?;
+        }
+        "playlistinfo" | "plchanges" => {
+            // there is no history of queue versions, so every change means the whole queue
+            let entries = queue(ctx);
+            let range = match (command, args.first()) {
+                ("playlistinfo", Some(arg)) => range(arg, entries.len())?,
+                _ => 0..entries.len(),
+            };
+            ctx.with_library(|library, root| {
+                for (pos, entry) in entries
+                    .iter()
+                    .enumerate()
+                    .skip(range.start)
+                    .take(range.len())
+                {
+                    if let Some(file) = library.files.get(entry.id) {
+                        write_song(&mut out, file, root, Some((pos, entry.queue_id)));
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        "playlistid" => {
+            let id: Option<usize> = optional_arg(args, 0)?;
+            let entries = queue(ctx);
+            ctx.with_library(|library, root| {
+                for (pos, entry) in entries.iter().enumerate() {
+                    if id.is_some_and(|id| id != entry.queue_id) {
+                        continue;
+                    }
+                    if let Some(file) = library.files.get(entry.id) {
+                        write_song(&mut out, file, root, Some((pos, entry.queue_id)));
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        "plchangesposid" => {
+            for (pos, entry) in queue(ctx).iter().enumerate() {
+                let _ = writeln!(out, "cpos: {}\nId: {}", pos, entry.queue_id);
+            }
+        }
+
+        // database
+        "find" | "search" => {
+            let filters = parse_filters(args)?;
+            let search = command == "search";
+            ctx.with_library(|library, root| {
+                for file in matching(library, root, &filters, search) {
+                    write_song(&mut out, file, root, None);
+                }
+                Ok(())
+            })?;
+        }
+        "list" => {
+            let (tag, rest) = args
+                .split_first()
+                .ok_or_else(|| Ack::arg("too few arguments"))?;
+            // `group` clauses are accepted but not grouped by
+            let rest = match rest
+                .iter()
+                .position(|arg| arg.eq_ignore_ascii_case("group"))
+            {
+                Some(index) => &rest[..index],
+                None => rest,
+            };
+            // the oldest syntax: `list album ARTIST`
+            let filters = match rest {
+                [artist] if tag.eq_ignore_ascii_case("album") && !artist.starts_with('(') => {
+                    parse_filters(&["artist".to_string(), artist.clone()])?
+                }
+                rest => parse_filters(rest)?,
+            };
+            let name = TAGS
+                .iter()
+                .find(|name| name.eq_ignore_ascii_case(tag))
+                .copied()
+                .unwrap_or(if tag.eq_ignore_ascii_case("file") {
+                    "file"
+                } else {
+                    ""
+                });
+            if name.is_empty() {
+                return Err(Ack::arg(format!("unknown tag type {tag:?}")));
+            }
+            ctx.with_library(|library, root| {
+                let values: BTreeSet<String> = matching(library, root, &filters, false)
+                    .filter_map(|file| tag_value(file, root, name))
+                    .collect();
+                for value in values {
+                    let _ = writeln!(out, "{}: {}", name, value);
+                }
+                Ok(())
+            })?;
+        }
+        "lsinfo" => {
+            let uri = args.first().map(String::as_str).unwrap_or("");
+            ctx.with_library(|library, root| lsinfo(&mut out, library, root, uri))?;
+        }
+        _ => {
+            return Err(Ack::new(
+                AckCode::Unknown,
+                format!("unknown command \"{command}\""),
+            ))
+        }
+    }
+    Ok(out)
+}
+
+fn status(ctx: &Context<'_>, out: &mut String) {
+    let engine = ctx.engine().read().unwrap();
+    let (status, _) = engine.get_status();
+    let state = if status.song_id.is_none() || status.is_finished {
+        "stop"
+    } else if status.is_paused {
+        "pause"
+    } else {
+        "play"
+    };
+    let repeat = engine.queue.repeat();
+
+    let _ = writeln!(out, "volume: {}", status.volume.level.round() as i64);
+    let _ = writeln!(out, "repeat: {}", u8::from(repeat != Repeat::Off));
+    let _ = writeln!(
+        out,
+        "random: {}",
+        u8::from(engine.queue.shuffle_mode() != Shuffle::Off)
+    );
+    let _ = writeln!(out, "single: {}", u8::from(repeat == Repeat::One));
+    let _ = writeln!(out, "consume: 0");
+    let _ = writeln!(out, "playlist: {}", ctx.shared.queue_version());
+    let _ = writeln!(out, "playlistlength: {}", engine.queue.len());
+    let _ = writeln!(out, "state: {}", state);
+    if let (Some(pos), Some(entry)) = (status.queue_index, engine.queue.current()) {
+        let _ = writeln!(out, "song: {}", pos);
+        let _ = writeln!(out, "songid: {}", entry.queue_id);
+    }
+    if state != "stop" {
+        let elapsed = status.progress_frames as f64 / 1000.0;
+        let duration = status.total_frames as f64 / 1000.0;
+        let _ = writeln!(out, "time: {}:{}", elapsed as u64, duration.round() as u64);
+        let _ = writeln!(out, "elapsed: {:.3}", elapsed);
+        let _ = writeln!(out, "duration: {:.3}", duration);
+    }
+    if let Some(next) = engine.queue.peek_next_index() {
+        let _ = writeln!(out, "nextsong: {}", next);
+        let _ = writeln!(out, "nextsongid: {}", engine.queue.entries()[next].queue_id);
+    }
+}
+
+fn queue(ctx: &Context<'_>) -> Vec<QueueEntry> {
+    ctx.engine().read().unwrap().queue.entries().to_vec()
+}
+
+/// Position of the queue entry with the song id `id`, which is its queue id.
+fn queue_position(ctx: &Context<'_>, id: usize) -> Result<usize, Ack> {
+    queue(ctx)
+        .iter()
+        .position(|entry| entry.queue_id == id)
+        .ok_or_else(|| Ack::new(AckCode::NoExist, "no such song"))
+}
+
+fn play(ctx: &Context<'_>, position: Option<usize>) -> Result<(), Ack> {
+    let mut engine = ctx.engine().write().unwrap();
+    let stopped = {
+        let (status, _) = engine.get_status();
+        status.song_id.is_none() || status.is_finished
+    };
+    match position {
+        Some(position) => engine.jump(position)?,
+        None if stopped && !engine.queue.is_empty() => {
+            let position = engine.queue.current_index().unwrap_or(0);
+            engine.jump(position)?;
+        }
+        None => {}
+    }
+    engine.play()?;
+    Ok(())
+}
+
+fn seek(ctx: &Context<'_>, position: usize, seconds: f64) -> Result<(), Ack> {
+    let mut engine = ctx.engine().write().unwrap();
+    if engine.queue.current_index() == Some(position) {
+        engine.seek(SeekTarget::Absolute { seconds }, SeekPrecision::Exact)?;
+    } else {
+        engine.jump(position)?;
+        engine.seek_when_loaded(seconds);
+    }
+    Ok(())
+}
+
+fn matching<'a>(
+    library: &'a Library,
+    root: &'a Path,
+    filters: &'a [Filter],
+    search: bool,
+) -> impl Iterator<Item = &'a crate::library::File> {
+    library.files.iter().filter(move |file| {
+        filters
+            .iter()
+            .all(|filter| filter.matches(file, root, search))
+    })
+}
+
+/// Queue entries for the song or all songs under the directory at `uri`.
+fn entries_under(library: &Library, root: &Path, uri: &str) -> Result<Vec<QueueEntry>, Ack> {
+    let path = root.join(uri.trim_matches('/'));
+    let entries: Vec<QueueEntry> = library
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| file.path.starts_with(&path))
+        .map(|(id, file)| QueueEntry::new(id, file.path.clone(), file.album()))
+        .collect();
+    if entries.is_empty() {
+        return Err(Ack::new(AckCode::NoExist, "no such directory or song"));
+    }
+    Ok(entries)
+}
+
+fn lsinfo(out: &mut String, library: &Library, root: &Path, uri: &str) -> Result<(), Ack> {
+    let path = root.join(uri.trim_matches('/'));
+    if let Some(id) = library.find(&path) {
+        write_song(out, &library.files[id], root, None);
+        return Ok(());
+    }
+    if !path.is_dir() || !path.starts_with(root) {
+        return Err(Ack::new(AckCode::NoExist, "no such directory"));
+    }
+
+    let directories: BTreeSet<PathBuf> = library
+        .directories()
+        .filter(|dir| dir.parent() == Some(path.as_path()))
+        .map(Path::to_path_buf)
+        .collect();
+    for dir in directories {
+        let _ = writeln!(out, "directory: {}", relative_uri(&dir, root));
+    }
+    for file in library
+        .files
+        .iter()
+        .filter(|file| file.path.parent() == Some(path.as_path()))
+    {
+        write_song(out, file, root, None);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::{atomic::AtomicU32, Arc, RwLock},
+        time::Instant,
+    };
+
+    use tokio::sync::broadcast;
+
+    use super::{execute, range, Context};
+    use crate::{
+        audio::Engine,
+        config::Config,
+        context::{self, Shell},
+        library::tests::{library, tag},
+        mpd::{protocol::split, Shared},
+        session::SessionStore,
+        settings::Settings,
+    };
+
+    struct NoWindow;
+
+    impl Shell for NoWindow {
+        fn focus(&self) {}
+        fn quit(&self) {}
+    }
+
+    /// A player whose library has an album of two songs by A and a single by B.
+    fn player(name: &str) -> (PathBuf, context::Context, Shared) {
+        let (dir, mut library) = library(name, &["album/1.wav", "album/2.wav", "single.wav"]);
+        tag(&mut library.files[0], "A", "Album");
+        tag(&mut library.files[1], "A", "Album");
+        tag(&mut library.files[2], "B", "Single");
+        let settings = Arc::new(RwLock::new(Settings::default()));
+        let engine = Engine::init(Arc::clone(&settings), &Config::default()).unwrap();
+        let core = context::Context::new(
+            settings,
+            engine,
+            SessionStore::default(),
+            Arc::new(NoWindow),
+        );
+        core.replace_library(library);
+        let shared = Shared {
+            started: Instant::now(),
+            queue_version: AtomicU32::new(1),
+            changes: broadcast::channel(4).0,
+        };
+        (dir, core, shared)
+    }
+
+    /// Runs the command `line`, which must succeed.
+    fn run(core: &context::Context, shared: &Shared, line: &str) -> String {
+        let words = split(line).unwrap();
+        let ctx = Context { core, shared };
+        execute(&ctx, &words[0], &words[1..]).unwrap()
+    }
+
+    /// The values of the lines of `out` starting with `key: `.
+    fn values<'a>(out: &'a str, key: &str) -> Vec<&'a str> {
+        out.lines()
+            .filter_map(|line| line.strip_prefix(key)?.strip_prefix(": "))
+            .collect()
+    }
+
+    #[test]
+    fn songs_are_found_listed_and_browsed() {
+        let (dir, core, shared) = player("mpd-database");
+        let run = |line| run(&core, &shared, line);
+
+        let found = run("find artist A");
+        assert_eq!(values(&found, "file"), ["album/1.wav", "album/2.wav"]);
+        assert_eq!(values(&found, "Album"), ["Album", "Album"]);
+        assert!(run("find artist a").is_empty());
+        assert_eq!(values(&run("search artist a"), "file").len(), 2);
+        assert_eq!(values(&run("search any sing"), "file"), ["single.wav"]);
+        assert_eq!(
+            values(&run(r#"find "(Album == \"Single\")""#), "Artist"),
+            ["B"]
+        );
+
+        assert_eq!(values(&run("list album"), "Album"), ["Album", "Single"]);
+        assert_eq!(values(&run("list album B"), "Album"), ["Single"]);
+        assert_eq!(
+            values(&run("list artist group album"), "Artist"),
+            ["A", "B"]
+        );
+
+        let root = run("lsinfo");
+        assert_eq!(values(&root, "directory"), ["album"]);
+        assert_eq!(values(&root, "file"), ["single.wav"]);
+        assert_eq!(
+            values(&run("lsinfo album"), "file"),
+            ["album/1.wav", "album/2.wav"]
+        );
+        assert_eq!(values(&run("lsinfo single.wav"), "Title"), ["single.wav"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn queue_entries_have_their_own_ids() {
+        let (dir, core, shared) = player("mpd-queue");
+        let run = |line| run(&core, &shared, line);
+
+        run("add album/1.wav");
+        run("add album");
+        run("add album/1.wav");
+        assert_eq!(run("addid single.wav 0"), "Id: 4\n");
+        let queue = run("playlistinfo");
+        assert_eq!(
+            values(&queue, "file"),
+            [
+                "single.wav",
+                "album/1.wav",
+                "album/1.wav",
+                "album/2.wav",
+                "album/1.wav"
+            ]
+        );
+        assert_eq!(values(&queue, "Id"), ["4", "0", "1", "2", "3"]);
+        assert_eq!(values(&queue, "Pos"), ["0", "1", "2", "3", "4"]);
+
+        // only the second of the three entries of the same song goes
+        run("deleteid 1");
+        assert_eq!(values(&run("playlistinfo"), "Id"), ["4", "0", "2", "3"]);
+        assert_eq!(values(&run("playlistid 3"), "Pos"), ["3"]);
+
+        let status = run("status");
+        assert_eq!(values(&status, "playlistlength"), ["4"]);
+        assert_eq!(values(&status, "state"), ["stop"]);
+        assert_eq!(values(&status, "volume"), ["100"]);
+        assert!(run("currentsong").is_empty());
+
+        run("playid 3");
+        let current = run("currentsong");
+        assert_eq!(values(&current, "file"), ["album/1.wav"]);
+        assert_eq!(values(&current, "Id"), ["3"]);
+        assert_eq!(values(&current, "Pos"), ["3"]);
+        let status = run("status");
+        assert_eq!(values(&status, "song"), ["3"]);
+        assert_eq!(values(&status, "songid"), ["3"]);
+        run("stop");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ranges_are_single_positions_or_spans() {
+        assert_eq!(range("2", 5).unwrap(), 2..3);
+        assert_eq!(range("1:3", 5).unwrap(), 1..3);
+        assert_eq!(range("3:", 5).unwrap(), 3..5);
+        assert_eq!(range("5:", 5).unwrap(), 5..5);
+    }
+
+    #[test]
+    fn ranges_must_lie_within_the_queue() {
+        assert!(range("5", 5).is_err());
+        assert!(range("2:6", 5).is_err());
+        assert!(range("3:1", 5).is_err());
+        assert!(range("6:", 5).is_err());
+        assert!(range("a:b", 5).is_err());
+    }
+}
@@ -0,0 +1,443 @@
+//! Optional server speaking the MPD protocol, so existing MPD clients can browse the library,
+//! manage the queue and control playback.
+
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines},
+    net::TcpListener,
+    sync::broadcast,
+};
+
+use crate::{
+    audio::PlaybackEvent,
+    context::{self, Change},
+};
+
+mod commands;
+mod protocol;
+
+use commands::{Context, Execute};
+use protocol::{split, Ack};
+
+/// The protocol version we claim, which is the one the supported subset comes from.
+const GREETING: &str = "OK MPD 0.23.0\n";
+
+/// Parts of the player `idle` waits on, by their MPD names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Subsystem {
+    Database,
+    Playlist,
+    Player,
+    Mixer,
+    Options,
+}
+
+const SUBSYSTEMS: [Subsystem; 5] = [
+    Subsystem::Database,
+    Subsystem::Playlist,
+    Subsystem::Player,
+    Subsystem::Mixer,
+    Subsystem::Options,
+];
+
+impl Subsystem {
+    fn name(self) -> &'static str {
+        match self {
+            Subsystem::Database => "database",
+            Subsystem::Playlist => "playlist",
+            Subsystem::Player => "player",
+            Subsystem::Mixer => "mixer",
+            Subsystem::Options => "options",
+        }
+    }
+}
+
+/// State shared by all client connections.
+pub(crate) struct Shared {
+    started: Instant,
+    /// Bumped on every queue change, reported as `playlist` by `status`.
+    queue_version: AtomicU32,
+    changes: broadcast::Sender<Subsystem>,
+}
+
+impl Shared {
+    pub(crate) fn queue_version(&self) -> u32 {
+        self.queue_version.load(Ordering::Relaxed)
+    }
+}
+
+/// Starts the server on `address`, which is a path for a Unix socket and `host:port`
+/// otherwise.
+pub fn spawn(core: context::Context, address: String) {
+    let shared = Arc::new(Shared {
+        started: Instant::now(),
+        queue_version: AtomicU32::new(1),
+        changes: broadcast::channel(64).0,
+    });
+    watch(&core, &shared);
+
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = listen(core, shared, &address).await {
+            log::error!("MPD server on {} stopped: {}", address, e);
+        }
+    });
+}
+
+async fn listen(core: context::Context, shared: Arc<Shared>, address: &str) -> std::io::Result<()> {
+    #[cfg(unix)]
+    if address.starts_with('/') {
+        let listener = crate::ipc::bind(std::path::Path::new(address))?;
+        log::info!("MPD server listening on {}", address);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            spawn_client(stream, core.clone(), Arc::clone(&shared));
+        }
+    }
+
+    let listener = TcpListener::bind(address).await?;
+    log::info!("MPD server listening on {}", address);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        spawn_client(stream, core.clone(), Arc::clone(&shared));
+    }
+}
+
+fn spawn_client<S>(stream: S, core: context::Context, shared: Arc<Shared>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    tauri::async_runtime::spawn(async move {
+        let ctx = Context {
+            core: &core,
+            shared: &shared,
+        };
+        if let Err(e) = serve(stream, &ctx, shared.changes.subscribe()).await {
+            log::warn!("MPD client disconnected: {}", e);
+        }
+    });
+}
+
+/// Turns engine events, queue and library changes into the subsystems `idle` reports.
+fn watch(core: &context::Context, shared: &Arc<Shared>) {
+    let mut events = core.engine.read().unwrap().subscribe();
+    let changes = shared.changes.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let subsystem = match events.recv().await {
+                Ok(
+                    PlaybackEvent::TrackChanged { .. }
+                    | PlaybackEvent::FileStarted
+                    | PlaybackEvent::FileEnded { .. }
+                    | PlaybackEvent::Paused { .. }
+                    | PlaybackEvent::Seeked { .. },
+                ) => Subsystem::Player,
+                Ok(PlaybackEvent::Volume { .. }) => Subsystem::Mixer,
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            // nobody idling is not an error
+            let _ = changes.send(subsystem);
+        }
+    });
+
+    let mut core_changes = core.subscribe();
+    let shared = Arc::clone(shared);
+    tauri::async_runtime::spawn(async move {
+        loop {
+            match core_changes.recv().await {
+                Ok(Change::Queue(_)) => {
+                    shared.queue_version.fetch_add(1, Ordering::Relaxed);
+                    // repeat and shuffle changes come through here as well
+                    let _ = shared.changes.send(Subsystem::Playlist);
+                    let _ = shared.changes.send(Subsystem::Options);
+                }
+                Ok(Change::Library) => {
+                    let _ = shared.changes.send(Subsystem::Database);
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+/// Changes which happened since the client last looked, including ones a lagging receiver
+/// missed, which are assumed to be anything.
+fn collect(changes: &mut broadcast::Receiver<Subsystem>, pending: &mut BTreeSet<Subsystem>) {
+    loop {
+        match changes.try_recv() {
+            Ok(subsystem) => {
+                pending.insert(subsystem);
+            }
+            Err(broadcast::error::TryRecvError::Lagged(_)) => pending.extend(SUBSYSTEMS),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Talks to one client, running its commands through `commands` and reporting `changes`
+/// to it while it idles.
+async fn serve<S, E>(
+    stream: S,
+    commands: &E,
+    mut changes: broadcast::Receiver<Subsystem>,
+) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    E: Execute,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    let mut pending = BTreeSet::new();
+
+    // the commands of an open command list, and whether each gets its own `list_OK`
+    let mut list: Option<(bool, Vec<Vec<String>>)> = None;
+
+    writer.write_all(GREETING.as_bytes()).await?;
+    while let Some(line) = lines.next_line().await? {
+        collect(&mut changes, &mut pending);
+        let words = match split(&line) {
+            Ok(words) => words,
+            Err(ack) => {
+                writer.write_all(ack.line(0, "").as_bytes()).await?;
+                continue;
+            }
+        };
+        let Some((command, args)) = words.split_first() else {
+            writer
+                .write_all(
+                    Ack::new(protocol::AckCode::Unknown, "No command given")
+                        .line(0, "")
+                        .as_bytes(),
+                )
+                .await?;
+            continue;
+        };
+
+        let response = match (command.as_str(), &mut list) {
+            ("command_list_begin", None) => {
+                list = Some((false, Vec::new()));
+                continue;
+            }
+            ("command_list_ok_begin", None) => {
+                list = Some((true, Vec::new()));
+                continue;
+            }
+            ("command_list_end", Some(_)) => {
+                let (ok_each, list_commands) = list.take().expect("command list is open");
+                run_list(commands, ok_each, &list_commands)
+            }
+            (_, Some((_, list_commands))) => {
+                list_commands.push(words.clone());
+                continue;
+            }
+            ("close", None) => return Ok(()),
+            ("idle", None) => match idle(&mut lines, &mut changes, &mut pending, args).await? {
+                Some(response) => response,
+                None => return Ok(()),
+            },
+            // `noidle` outside of `idle` has nothing to cancel
+            ("noidle", None) => continue,
+            (command, None) => match commands.execute(command, args) {
+                Ok(body) => body + "OK\n",
+                Err(ack) => ack.line(0, command),
+            },
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn run_list(commands: &impl Execute, ok_each: bool, list: &[Vec<String>]) -> String {
+    let mut response = String::new();
+    for (index, words) in list.iter().enumerate() {
+        let (command, args) = words.split_first().expect("empty lines are rejected");
+        match commands.execute(command, args) {
+            Ok(body) => {
+                response.push_str(&body);
+                if ok_each {
+                    response.push_str("list_OK\n");
+                }
+            }
+            Err(ack) => return response + &ack.line(index, command),
+        }
+    }
+    response + "OK\n"
+}
+
+/// Waits until one of the subsystems in `args` (or any, if empty) changes or the client
+/// sends `noidle`. Unknown subsystems are ignored unless there is nothing else. Returns
+/// `None` once the connection should be closed.
+async fn idle<R>(
+    lines: &mut Lines<BufReader<R>>,
+    changes: &mut broadcast::Receiver<Subsystem>,
+    pending: &mut BTreeSet<Subsystem>,
+    args: &[String],
+) -> std::io::Result<Option<String>>
+where
+    R: AsyncRead + Unpin,
+{
+    let wanted: Vec<Subsystem> = if args.is_empty() {
+        SUBSYSTEMS.to_vec()
+    } else {
+        SUBSYSTEMS
+            .into_iter()
+            .filter(|subsystem| args.iter().any(|arg| arg == subsystem.name()))
+            .collect()
+    };
+    if wanted.is_empty() {
+        let ack = Ack::arg(format!("Unrecognized idle event: {}", args.join(" ")));
+        return Ok(Some(ack.line(0, "idle")));
+    }
+
+    loop {
+        let changed: Vec<Subsystem> = pending
+            .iter()
+            .copied()
+            .filter(|subsystem| wanted.contains(subsystem))
+            .collect();
+        if !changed.is_empty() {
+            let mut response = String::new();
+            for subsystem in changed {
+                pending.remove(&subsystem);
+                response.push_str(&format!("changed: {}\n", subsystem.name()));
+            }
+            return Ok(Some(response + "OK\n"));
+        }
+
+        tokio::select! {
+            change = changes.recv() => match change {
+                Ok(subsystem) => {
+                    pending.insert(subsystem);
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => pending.extend(SUBSYSTEMS),
+                Err(broadcast::error::RecvError::Closed) => return Ok(None),
+            },
+            line = lines.next_line() => match line?.as_deref().map(str::trim) {
+                Some("noidle") => return Ok(Some("OK\n".into())),
+                // MPD closes the connection on anything else while idle
+                _ => return Ok(None),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, Lines, ReadHalf, WriteHalf},
+        sync::broadcast,
+    };
+
+    use super::{
+        commands::Execute,
+        protocol::{Ack, AckCode},
+        serve, Subsystem,
+    };
+
+    /// Knows `ping` and a `status` which is always stopped.
+    struct Fake;
+
+    impl Execute for Fake {
+        fn execute(&self, command: &str, _args: &[String]) -> Result<String, Ack> {
+            match command {
+                "ping" => Ok(String::new()),
+                "status" => Ok("state: stop\n".into()),
+                command => Err(Ack::new(
+                    AckCode::Unknown,
+                    format!("unknown command \"{command}\""),
+                )),
+            }
+        }
+    }
+
+    struct Client {
+        lines: Lines<BufReader<ReadHalf<DuplexStream>>>,
+        writer: WriteHalf<DuplexStream>,
+    }
+
+    impl Client {
+        async fn send(&mut self, lines: &str) {
+            self.writer.write_all(lines.as_bytes()).await.unwrap();
+        }
+
+        /// The lines up to and including the next `OK` or `ACK`.
+        async fn response(&mut self) -> String {
+            let mut response = String::new();
+            while let Some(line) = self.lines.next_line().await.unwrap() {
+                response.push_str(&line);
+                response.push('\n');
+                if line == "OK" || line.starts_with("ACK") {
+                    break;
+                }
+            }
+            response
+        }
+    }
+
+    #[test]
+    fn clients_are_served_the_protocol() {
+        tauri::async_runtime::block_on(async {
+            let (changes, _) = broadcast::channel(4);
+            let (client, server) = tokio::io::duplex(1024);
+            let server = tauri::async_runtime::spawn({
+                let changes = changes.subscribe();
+                async move { serve(server, &Fake, changes).await }
+            });
+            let (reader, writer) = tokio::io::split(client);
+            let mut client = Client {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            };
+
+            let greeting = client.lines.next_line().await.unwrap().unwrap();
+            assert_eq!(greeting, "OK MPD 0.23.0");
+
+            client
+                .send("command_list_ok_begin\nping\nstatus\ncommand_list_end\n")
+                .await;
+            assert_eq!(
+                client.response().await,
+                "list_OK\nstate: stop\nlist_OK\nOK\n"
+            );
+
+            client
+                .send("command_list_begin\nping\ndance\nstatus\ncommand_list_end\n")
+                .await;
+            assert_eq!(
+                client.response().await,
+                "ACK [5@1] {dance} unknown command \"dance\"\n"
+            );
+
+            client.send("idle\nnoidle\n").await;
+            assert_eq!(client.response().await, "OK\n");
+
+            client.send("idle mixer player\n").await;
+            changes.send(Subsystem::Player).unwrap();
+            assert_eq!(client.response().await, "changed: player\nOK\n");
+
+            client.send("idle sticker\n").await;
+            assert_eq!(
+                client.response().await,
+                "ACK [2@0] {idle} Unrecognized idle event: sticker\n"
+            );
+
+            client.send("dance\n").await;
+            assert_eq!(
+                client.response().await,
+                "ACK [5@0] {dance} unknown command \"dance\"\n"
+            );
+
+            client.send("close\n").await;
+            server.await.unwrap().unwrap();
+        });
+    }
+}
@@ -0,0 +1,367 @@
+//! The text side of the MPD protocol: splitting command lines, errors, filters and the
+//! key/value blocks songs are described with.
+
+use std::{fmt::Write, path::Path};
+
+use lofty::{
+    file::{AudioFile, TaggedFileExt},
+    tag::{Accessor, ItemKey},
+};
+
+use crate::library::File;
+
+/// Error codes from MPD's `ack.h`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum AckCode {
+    Arg = 2,
+    Unknown = 5,
+    NoExist = 50,
+    System = 52,
+}
+
+#[derive(Debug)]
+pub(crate) struct Ack {
+    pub(crate) code: AckCode,
+    pub(crate) message: String,
+}
+
+impl Ack {
+    pub(crate) fn new(code: AckCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn arg(message: impl Into<String>) -> Self {
+        Self::new(AckCode::Arg, message)
+    }
+
+    /// The response line, `index` being the position of the command in a command list.
+    pub(crate) fn line(&self, index: usize, command: &str) -> String {
+        format!(
+            "ACK [{}@{}] {{{}}} {}\n",
+            self.code as u8, index, command, self.message
+        )
+    }
+}
+
+impl<E: std::error::Error> From<E> for Ack {
+    fn from(error: E) -> Self {
+        Self::new(AckCode::System, error.to_string())
+    }
+}
+
+/// Splits a command line into its words, honouring double quotes and backslash escapes.
+pub(crate) fn split(line: &str) -> Result<Vec<String>, Ack> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(words);
+        };
+
+        let mut word = String::new();
+        if first == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => word.push(c),
+                        None => return Err(Ack::arg("unterminated escape")),
+                    },
+                    Some(c) => word.push(c),
+                    None => return Err(Ack::arg("missing closing '\"'")),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                word.push(c);
+            }
+        }
+        words.push(word);
+    }
+}
+
+/// Tags clients can ask for, by their MPD names.
+pub(crate) const TAGS: [&str; 7] = [
+    "Artist",
+    "Album",
+    "AlbumArtist",
+    "Title",
+    "Track",
+    "Genre",
+    "Date",
+];
+
+/// The value of the tag `name` (compared case-insensitively) of `file`. `root` is used for
+/// the pseudo tag `file`, the path relative to the library.
+pub(crate) fn tag_value(file: &File, root: &Path, name: &str) -> Option<String> {
+    if name.eq_ignore_ascii_case("file") {
+        return Some(relative_uri(&file.path, root));
+    }
+    let tag = file.metadata.primary_tag();
+    let value = match name.to_ascii_lowercase().as_str() {
+        "title" => return Some(file.title()),
+        "artist" => tag?.artist()?.to_string(),
+        "album" => tag?.album()?.to_string(),
+        "albumartist" => tag?.get_string(&ItemKey::AlbumArtist)?.to_string(),
+        "genre" => tag?.genre()?.to_string(),
+        "date" => tag?.year()?.to_string(),
+        "track" => tag?.track()?.to_string(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+pub(crate) fn relative_uri(path: &Path, root: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+/// Appends the description of `file` to `out`, with its queue position and id if it is
+/// in the queue.
+pub(crate) fn write_song(
+    out: &mut String,
+    file: &File,
+    root: &Path,
+    queued: Option<(usize, usize)>,
+) {
+    let _ = writeln!(out, "file: {}", relative_uri(&file.path, root));
+    for tag in TAGS {
+        if let Some(value) = tag_value(file, root, tag) {
+            let _ = writeln!(out, "{}: {}", tag, value);
+        }
+    }
+    let duration = file.metadata.properties().duration().as_secs_f64();
+    let _ = writeln!(out, "Time: {}", duration.round() as u64);
+    let _ = writeln!(out, "duration: {:.3}", duration);
+    if let Some((pos, id)) = queued {
+        let _ = writeln!(out, "Pos: {}", pos);
+        let _ = writeln!(out, "Id: {}", id);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Equals,
+    NotEquals,
+    Contains,
+}
+
+/// One condition of a `find`, `search` or `list` filter.
+#[derive(Debug)]
+pub(crate) struct Filter {
+    tag: String,
+    operator: Operator,
+    value: String,
+}
+
+impl Filter {
+    /// Whether `file` passes. `search` compares case-insensitively and by substring, like
+    /// MPD's `search` does compared to `find`.
+    pub(crate) fn matches(&self, file: &File, root: &Path, search: bool) -> bool {
+        let values: Vec<String> = if self.tag.eq_ignore_ascii_case("any") {
+            TAGS.iter()
+                .chain(&["file"])
+                .filter_map(|tag| tag_value(file, root, tag))
+                .collect()
+        } else {
+            tag_value(file, root, &self.tag).into_iter().collect()
+        };
+
+        let fold = |text: &str| {
+            if search {
+                text.to_lowercase()
+            } else {
+                text.to_string()
+            }
+        };
+        let wanted = fold(&self.value);
+        let found = values.iter().any(|value| {
+            let value = fold(value);
+            match self.operator {
+                Operator::Contains => value.contains(&wanted),
+                _ if search => value.contains(&wanted),
+                _ => value == wanted,
+            }
+        });
+        if self.operator == Operator::NotEquals {
+            !found
+        } else {
+            found
+        }
+    }
+}
+
+/// Parses filter arguments, either old style `TAG VALUE` pairs or expressions like
+/// `((Artist == 'x') AND (Album contains "y"))`.
+pub(crate) fn parse_filters(args: &[String]) -> Result<Vec<Filter>, Ack> {
+    match args {
+        [] => Ok(Vec::new()),
+        [expression] if expression.starts_with('(') => {
+            let mut filters = Vec::new();
+            parse_expression(expression.trim(), &mut filters)?;
+            Ok(filters)
+        }
+        _ if args.len() % 2 == 0 => Ok(args
+            .chunks(2)
+            .map(|pair| Filter {
+                tag: pair[0].clone(),
+                operator: Operator::Equals,
+                value: pair[1].clone(),
+            })
+            .collect()),
+        _ => Err(Ack::arg("incorrect number of filter arguments")),
+    }
+}
+
+fn parse_expression(expression: &str, filters: &mut Vec<Filter>) -> Result<(), Ack> {
+    let inner = expression
+        .strip_prefix('(')
+        .and_then(|rest| rest.strip_suffix(')'))
+        .ok_or_else(|| Ack::arg(format!("invalid filter expression {expression}")))?
+        .trim();
+
+    // `(A) AND (B) AND ...`, split at the top level parentheses
+    if inner.starts_with('(') {
+        let mut depth = 0;
+        let mut start = 0;
+        for (i, c) in inner.char_indices() {
+            match c {
+                '(' => {
+                    if depth == 0 {
+                        start = i;
+                    }
+                    depth += 1;
+                }
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        parse_expression(&inner[start..=i], filters)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        return Ok(());
+    }
+
+    let (tag, rest) = inner
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| Ack::arg(format!("invalid filter expression {expression}")))?;
+    let rest = rest.trim_start();
+    let (operator, value) = [
+        ("==", Operator::Equals),
+        ("!=", Operator::NotEquals),
+        ("contains", Operator::Contains),
+    ]
+    .into_iter()
+    .find_map(|(token, operator)| Some((operator, rest.strip_prefix(token)?.trim())))
+    .ok_or_else(|| Ack::arg(format!("unsupported filter operator in {expression}")))?;
+
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'');
+    let value = match quote {
+        Some(quote) => value
+            .strip_prefix(quote)
+            .and_then(|value| value.strip_suffix(quote))
+            .ok_or_else(|| Ack::arg(format!("unterminated value in {expression}")))?
+            .replace("\\", ""),
+        None => value.to_string(),
+    };
+
+    filters.push(Filter {
+        tag: tag.to_string(),
+        operator,
+        value,
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_filters, split, Ack, AckCode, Operator};
+
+    fn words(line: &str) -> Vec<String> {
+        split(line).unwrap()
+    }
+
+    #[test]
+    fn lines_split_at_whitespace_outside_quotes() {
+        assert_eq!(words("  play   3 "), ["play", "3"]);
+        assert_eq!(
+            words(r#"add "Some Artist/Some Album""#),
+            ["add", "Some Artist/Some Album"]
+        );
+        assert_eq!(words(r#"find "" x"#), ["find", "", "x"]);
+        assert!(words("").is_empty());
+    }
+
+    #[test]
+    fn quoted_words_unescape_backslashes() {
+        assert_eq!(
+            words(r#"find "(Artist == \"AC/DC\")" "a\\b""#),
+            ["find", r#"(Artist == "AC/DC")"#, r"a\b"]
+        );
+    }
+
+    #[test]
+    fn unterminated_quotes_are_rejected() {
+        assert!(split(r#"add "Some Album"#).is_err());
+        assert!(split(r#"add "Some Album\"#).is_err());
+    }
+
+    #[test]
+    fn filters_come_as_pairs() {
+        let args = ["Artist", "x", "Album", "y"].map(String::from);
+        let filters = parse_filters(&args).unwrap();
+        assert_eq!(filters.len(), 2);
+        assert_eq!(
+            (
+                filters[1].tag.as_str(),
+                filters[1].operator,
+                filters[1].value.as_str()
+            ),
+            ("Album", Operator::Equals, "y")
+        );
+        assert!(parse_filters(&["Artist".to_string()]).is_err());
+    }
+
+    #[test]
+    fn filters_come_as_expressions() {
+        let expression = r#"((Artist == 'AC/DC') AND (Album != "Live") AND (any contains 'rock'))"#;
+        let filters = parse_filters(&[expression.to_string()]).unwrap();
+        let parsed: Vec<_> = filters
+            .iter()
+            .map(|filter| (filter.tag.as_str(), filter.operator, filter.value.as_str()))
+            .collect();
+        assert_eq!(
+            parsed,
+            [
+                ("Artist", Operator::Equals, "AC/DC"),
+                ("Album", Operator::NotEquals, "Live"),
+                ("any", Operator::Contains, "rock"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_expressions_are_rejected() {
+        assert!(parse_filters(&["(Artist =~ 'x')".to_string()]).is_err());
+        assert!(parse_filters(&["(Artist == 'x)".to_string()]).is_err());
+        assert!(parse_filters(&["(Artist)".to_string()]).is_err());
+    }
+
+    #[test]
+    fn acks_name_code_position_and_command() {
+        assert_eq!(
+            Ack::new(AckCode::NoExist, "no such song").line(2, "deleteid"),
+            "ACK [50@2] {deleteid} no such song\n"
+        );
+        assert_eq!(Ack::arg("bad").line(0, ""), "ACK [2@0] {} bad\n");
+    }
+}
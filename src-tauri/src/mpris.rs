@@ -0,0 +1,719 @@
+//! MPRIS2 server, so desktop widgets, media keys and `playerctl` can see and control ample.
+//!
+//! It connects to the session bus from `DBUS_SESSION_BUS_ADDRESS`, so pointing that at a
+//! private `dbus-daemon` is enough to try it out in isolation.
+
+use std::{
+    collections::HashMap,
+    fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+
+use lofty::{
+    file::{AudioFile, TaggedFileExt},
+    picture::MimeType,
+    tag::{Accessor, ItemKey},
+};
+use tokio::sync::broadcast;
+use zbus::{
+    connection, fdo, interface,
+    object_server::{InterfaceRef, SignalEmitter},
+    zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value},
+    Connection,
+};
+
+use crate::{
+    audio::{self, Engine, PlaybackEvent, Repeat, Shuffle, MAX_SPEED, MIN_SPEED},
+    context::{Change, Context},
+    library::{File, Library},
+    playlist,
+};
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.ample";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// Image files next to a song which are used as its cover, before looking into its tags.
+const COVER_FILES: [&str; 6] = [
+    "cover.jpg",
+    "cover.png",
+    "folder.jpg",
+    "folder.png",
+    "front.jpg",
+    "front.png",
+];
+
+type Metadata = HashMap<String, OwnedValue>;
+
+fn owned<'a>(value: impl Into<Value<'a>>) -> OwnedValue {
+    OwnedValue::try_from(value.into()).expect("metadata holds no file descriptors")
+}
+
+fn track_id(id: usize) -> OwnedObjectPath {
+    OwnedObjectPath::try_from(format!("/org/ample/track/{}", id)).expect("valid object path")
+}
+
+fn parse_track_id(path: &ObjectPath<'_>) -> Option<usize> {
+    path.as_str()
+        .strip_prefix("/org/ample/track/")?
+        .parse()
+        .ok()
+}
+
+fn file_url(path: &Path) -> String {
+    format!(
+        "file://{}",
+        playlist::percent_encode(&path.to_string_lossy())
+    )
+}
+
+/// Finds a cover for `file`, extracting an embedded one to the cache directory if needed.
+fn art_url(file: &File) -> Option<String> {
+    if let Some(dir) = file.path.parent() {
+        if let Some(cover) = COVER_FILES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+        {
+            return Some(file_url(&cover));
+        }
+    }
+
+    let picture = file.metadata.primary_tag()?.pictures().first()?;
+    let extension = match picture.mime_type() {
+        Some(MimeType::Png) => "png",
+        Some(MimeType::Gif) => "gif",
+        Some(MimeType::Bmp) => "bmp",
+        _ => "jpg",
+    };
+    let mut hasher = DefaultHasher::new();
+    file.path.hash(&mut hasher);
+    let mut path: PathBuf = dirs::cache_dir()?;
+    path.push("ample");
+    path.push("art");
+    fs::create_dir_all(&path).ok()?;
+    path.push(format!("{:016x}.{}", hasher.finish(), extension));
+    if !path.exists() {
+        fs::write(&path, picture.data()).ok()?;
+    }
+    Some(file_url(&path))
+}
+
+fn metadata(id: usize, file: &File) -> Metadata {
+    let mut metadata = Metadata::new();
+    metadata.insert("mpris:trackid".into(), owned(track_id(id).into_inner()));
+    let length = file.metadata.properties().duration().as_micros() as i64;
+    metadata.insert("mpris:length".into(), owned(length));
+    metadata.insert("xesam:title".into(), owned(file.title()));
+    metadata.insert("xesam:url".into(), owned(file_url(&file.path)));
+    if let Some(tag) = file.metadata.primary_tag() {
+        if let Some(artist) = tag.artist() {
+            metadata.insert("xesam:artist".into(), owned(vec![artist.to_string()]));
+        }
+        if let Some(album) = tag.album() {
+            metadata.insert("xesam:album".into(), owned(album.to_string()));
+        }
+        if let Some(album_artist) = tag.get_string(&ItemKey::AlbumArtist) {
+            metadata.insert(
+                "xesam:albumArtist".into(),
+                owned(vec![album_artist.to_string()]),
+            );
+        }
+        if let Some(track) = tag.track() {
+            metadata.insert("xesam:trackNumber".into(), owned(track as i32));
+        }
+    }
+    if let Some(url) = art_url(file) {
+        metadata.insert("mpris:artUrl".into(), owned(url));
+    }
+    metadata
+}
+
+fn failed(error: impl ToString) -> fdo::Error {
+    fdo::Error::Failed(error.to_string())
+}
+
+/// Gives the interfaces access to the app state.
+struct Handle(Context);
+
+impl Handle {
+    fn context(&self) -> &Context {
+        &self.0
+    }
+
+    fn engine(&self) -> &RwLock<Engine> {
+        &self.0.engine
+    }
+
+    /// Applies a change to the queue and tells everyone listening about it.
+    fn change_queue(
+        &self,
+        f: impl FnOnce(&mut Engine) -> Result<(), audio::Error>,
+    ) -> fdo::Result<()> {
+        self.0.change_queue(f).map_err(failed)
+    }
+
+    fn with_library<T>(&self, f: impl FnOnce(&Library) -> T) -> Option<T> {
+        self.0.library.read().unwrap().as_ref().map(f)
+    }
+}
+
+struct Root(Handle);
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {
+        self.0.context().focus();
+    }
+
+    fn quit(&self) {
+        self.0.context().quit();
+    }
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> String {
+        "ample".into()
+    }
+
+    #[zbus(property)]
+    fn desktop_entry(&self) -> String {
+        "ample".into()
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["file".into()]
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        [
+            "audio/mpeg",
+            "audio/flac",
+            "audio/ogg",
+            "audio/opus",
+            "audio/mp4",
+            "audio/x-wav",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+}
+
+struct Player(Handle);
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    fn next(&self) -> fdo::Result<()> {
+        self.0.engine().write().unwrap().next().map_err(failed)?;
+        Ok(())
+    }
+
+    fn previous(&self) -> fdo::Result<()> {
+        self.0.engine().write().unwrap().previous().map_err(failed)
+    }
+
+    fn pause(&self) -> fdo::Result<()> {
+        self.0.engine().write().unwrap().pause().map_err(failed)
+    }
+
+    fn play_pause(&self) -> fdo::Result<()> {
+        self.0.engine().write().unwrap().toggle().map_err(failed)
+    }
+
+    fn stop(&self) -> fdo::Result<()> {
+        self.0.engine().write().unwrap().stop().map_err(failed)
+    }
+
+    fn play(&self) -> fdo::Result<()> {
+        self.0.engine().write().unwrap().play().map_err(failed)
+    }
+
+    /// Seeks by `offset` microseconds.
+    fn seek(&self, offset: i64) -> fdo::Result<()> {
+        let seconds = offset as f64 / 1_000_000.0;
+        self.0
+            .engine()
+            .write()
+            .unwrap()
+            .seek(
+                audio::SeekTarget::Relative { seconds },
+                audio::SeekPrecision::Exact,
+            )
+            .map_err(failed)
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        let mut engine = self.0.engine().write().unwrap();
+        // the spec asks to ignore requests for tracks which are no longer current
+        if parse_track_id(&track_id) != engine.get_status().0.song_id || position < 0 {
+            return Ok(());
+        }
+        let seconds = position as f64 / 1_000_000.0;
+        engine
+            .seek(
+                audio::SeekTarget::Absolute { seconds },
+                audio::SeekPrecision::Exact,
+            )
+            .map_err(failed)
+    }
+
+    fn open_uri(&self, uri: String) -> fdo::Result<()> {
+        let path = playlist::location_to_path(&uri)
+            .ok_or_else(|| fdo::Error::NotSupported(format!("unsupported uri {uri}")))?;
+        let entry = self
+            .0
+            .with_library(|library| {
+                let id = library.find(&path)?;
+                Some(audio::QueueEntry::new(
+                    id,
+                    path.clone(),
+                    library.files[id].album(),
+                ))
+            })
+            .flatten()
+            .ok_or_else(|| fdo::Error::InvalidArgs(format!("{uri} is not in the library")))?;
+
+        let mut engine = self.0.engine().write().unwrap();
+        engine.queue.detach();
+        engine.start(entry).map_err(failed)
+    }
+
+    #[zbus(signal)]
+    async fn seeked(emitter: &SignalEmitter<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        let (status, _) = self.0.engine().read().unwrap().get_status();
+        if status.song_id.is_none() || status.is_finished {
+            "Stopped"
+        } else if status.is_paused {
+            "Paused"
+        } else {
+            "Playing"
+        }
+        .into()
+    }
+
+    #[zbus(property)]
+    fn loop_status(&self) -> String {
+        match self.0.engine().read().unwrap().queue.repeat() {
+            Repeat::Off => "None",
+            Repeat::One => "Track",
+            Repeat::All => "Playlist",
+        }
+        .into()
+    }
+
+    #[zbus(property)]
+    fn set_loop_status(&mut self, status: String) -> fdo::Result<()> {
+        let repeat = match status.as_str() {
+            "None" => Repeat::Off,
+            "Track" => Repeat::One,
+            "Playlist" => Repeat::All,
+            _ => return Err(fdo::Error::InvalidArgs(status)),
+        };
+        self.0.change_queue(|engine| engine.set_repeat(repeat))
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        self.0.engine().read().unwrap().speed().rate
+    }
+
+    #[zbus(property)]
+    fn set_rate(&mut self, rate: f64) -> fdo::Result<()> {
+        // a rate of zero means pausing, according to the spec
+        if rate <= 0.0 {
+            return self.pause();
+        }
+        self.0
+            .engine()
+            .write()
+            .unwrap()
+            .set_speed(rate, None)
+            .map_err(failed)
+    }
+
+    #[zbus(property)]
+    fn shuffle(&self) -> bool {
+        self.0.engine().read().unwrap().queue.shuffle_mode() != Shuffle::Off
+    }
+
+    #[zbus(property)]
+    fn set_shuffle(&mut self, shuffle: bool) -> fdo::Result<()> {
+        let shuffle = if shuffle {
+            Shuffle::Tracks
+        } else {
+            Shuffle::Off
+        };
+        self.0.change_queue(|engine| engine.set_shuffle(shuffle))
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> Metadata {
+        let Some(id) = self.0.engine().read().unwrap().get_status().0.song_id else {
+            let mut metadata = Metadata::new();
+            let no_track = ObjectPath::from_static_str_unchecked(NO_TRACK);
+            metadata.insert("mpris:trackid".into(), owned(no_track));
+            return metadata;
+        };
+        self.0
+            .with_library(|library| library.files.get(id).map(|file| metadata(id, file)))
+            .flatten()
+            .unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.0.engine().read().unwrap().volume().level / 100.0
+    }
+
+    #[zbus(property)]
+    fn set_volume(&mut self, volume: f64) -> fdo::Result<()> {
+        self.0
+            .engine()
+            .write()
+            .unwrap()
+            .set_volume(volume.max(0.0) * 100.0)
+            .map_err(failed)
+    }
+
+    /// Position in microseconds, which clients poll rather than being told about.
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        let (status, _) = self.0.engine().read().unwrap().get_status();
+        status.progress_frames as i64 * 1000
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        MIN_SPEED
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        MAX_SPEED
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        self.0.engine().read().unwrap().queue.peek_next().is_some()
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        self.0
+            .engine()
+            .read()
+            .unwrap()
+            .get_status()
+            .0
+            .song_id
+            .is_some()
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        self.0
+            .engine()
+            .read()
+            .unwrap()
+            .get_status()
+            .0
+            .song_id
+            .is_some()
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+struct TrackList(Handle);
+
+impl TrackList {
+    fn track_ids(&self) -> Vec<OwnedObjectPath> {
+        let engine = self.0.engine().read().unwrap();
+        engine
+            .queue
+            .entries()
+            .iter()
+            .map(|entry| track_id(entry.id))
+            .collect()
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.TrackList")]
+impl TrackList {
+    fn get_tracks_metadata(&self, track_ids: Vec<OwnedObjectPath>) -> Vec<Metadata> {
+        self.0
+            .with_library(|library| {
+                track_ids
+                    .iter()
+                    .filter_map(|path| {
+                        let id = parse_track_id(path)?;
+                        library.files.get(id).map(|file| metadata(id, file))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn add_track(
+        &self,
+        _uri: String,
+        _after: ObjectPath<'_>,
+        _set_as_current: bool,
+    ) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(
+            "the track list is read-only".into(),
+        ))
+    }
+
+    fn remove_track(&self, _track_id: ObjectPath<'_>) -> fdo::Result<()> {
+        Err(fdo::Error::NotSupported(
+            "the track list is read-only".into(),
+        ))
+    }
+
+    fn go_to(&self, track_id: ObjectPath<'_>) -> fdo::Result<()> {
+        let Some(id) = parse_track_id(&track_id) else {
+            return Ok(());
+        };
+        let mut engine = self.0.engine().write().unwrap();
+        let Some(index) = engine
+            .queue
+            .entries()
+            .iter()
+            .position(|entry| entry.id == id)
+        else {
+            return Ok(());
+        };
+        engine.jump(index).map_err(failed)
+    }
+
+    #[zbus(signal)]
+    async fn track_list_replaced(
+        emitter: &SignalEmitter<'_>,
+        tracks: Vec<OwnedObjectPath>,
+        current_track: OwnedObjectPath,
+    ) -> zbus::Result<()>;
+
+    #[zbus(property(emits_changed_signal = "invalidates"))]
+    fn tracks(&self) -> Vec<OwnedObjectPath> {
+        self.track_ids()
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_edit_tracks(&self) -> bool {
+        false
+    }
+}
+
+/// Starts the server in the background, logging instead of failing if there is no session
+/// bus to connect to.
+pub fn spawn(ctx: Context) {
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = serve(ctx).await {
+            log::warn!("unable to start MPRIS server: {}", e);
+        }
+    });
+}
+
+async fn serve(ctx: Context) -> zbus::Result<()> {
+    let mut events = ctx.engine.read().unwrap().subscribe();
+    let connection = connect(&ctx, connection::Builder::session()?).await?;
+    let object_server = connection.object_server();
+    let player = object_server.interface::<_, Player>(OBJECT_PATH).await?;
+
+    listen_to_queue(&ctx, &connection);
+
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        };
+        if let Err(e) = announce(&player, event).await {
+            log::error!("failed to send MPRIS signal: {}", e);
+        }
+    }
+}
+
+/// Claims our name on the bus `builder` connects to and serves the interfaces there.
+async fn connect(ctx: &Context, builder: connection::Builder<'_>) -> zbus::Result<Connection> {
+    builder
+        .name(BUS_NAME)?
+        .serve_at(OBJECT_PATH, Root(Handle(ctx.clone())))?
+        .serve_at(OBJECT_PATH, Player(Handle(ctx.clone())))?
+        .serve_at(OBJECT_PATH, TrackList(Handle(ctx.clone())))?
+        .build()
+        .await
+}
+
+/// Sends the signals for the properties which `event` changes.
+async fn announce(player: &InterfaceRef<Player>, event: PlaybackEvent) -> zbus::Result<()> {
+    let emitter = player.signal_emitter();
+    let iface = player.get().await;
+    match event {
+        PlaybackEvent::TrackChanged { .. } => {
+            iface.metadata_changed(emitter).await?;
+            iface.playback_status_changed(emitter).await?;
+            iface.can_go_next_changed(emitter).await?;
+            iface.can_go_previous_changed(emitter).await?;
+            iface.can_play_changed(emitter).await?;
+        }
+        PlaybackEvent::FileStarted
+        | PlaybackEvent::FileEnded { .. }
+        | PlaybackEvent::Paused { .. } => {
+            iface.playback_status_changed(emitter).await?;
+        }
+        PlaybackEvent::Seeked { seconds } => {
+            Player::seeked(emitter, (seconds * 1_000_000.0) as i64).await?;
+        }
+        PlaybackEvent::Volume { .. } => iface.volume_changed(emitter).await?,
+        PlaybackEvent::Speed(_) => iface.rate_changed(emitter).await?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Queue changes do not come with the engine's events, so they are followed separately.
+fn listen_to_queue(ctx: &Context, connection: &Connection) {
+    let mut changes = ctx.subscribe();
+    let connection = connection.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            match changes.recv().await {
+                Ok(Change::Queue(_)) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Ok(Change::Library) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+            if let Err(e) = queue_changed(&connection).await {
+                log::error!("failed to send MPRIS signal: {}", e);
+            }
+        }
+    });
+}
+
+async fn queue_changed(connection: &Connection) -> zbus::Result<()> {
+    let object_server = connection.object_server();
+    let player = object_server.interface::<_, Player>(OBJECT_PATH).await?;
+    let tracks = object_server.interface::<_, TrackList>(OBJECT_PATH).await?;
+
+    let emitter = player.signal_emitter();
+    let iface = player.get().await;
+    iface.loop_status_changed(emitter).await?;
+    iface.shuffle_changed(emitter).await?;
+    iface.can_go_next_changed(emitter).await?;
+
+    let ids = tracks.get().await.track_ids();
+    let current = match iface.metadata().get("mpris:trackid") {
+        Some(id) => OwnedObjectPath::try_from(id.clone())?,
+        None => OwnedObjectPath::try_from(NO_TRACK)?,
+    };
+    TrackList::track_list_replaced(tracks.signal_emitter(), ids, current).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Command, Stdio},
+        sync::{Arc, RwLock},
+    };
+
+    use zbus::{connection, zvariant::OwnedObjectPath, Proxy};
+
+    use super::{connect, Metadata, BUS_NAME, NO_TRACK, OBJECT_PATH};
+    use crate::{
+        audio::Engine,
+        config::Config,
+        context::{Context, Shell},
+        session::SessionStore,
+        settings::Settings,
+    };
+
+    struct NoWindow;
+
+    impl Shell for NoWindow {
+        fn focus(&self) {}
+
+        fn quit(&self) {}
+    }
+
+    /// Needs `dbus-daemon` and libmpv, so it only runs when asked for with `--ignored`.
+    #[test]
+    #[ignore]
+    fn the_player_is_visible_on_the_bus() {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address=1"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("dbus-daemon is installed");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+
+        let settings = Arc::new(RwLock::new(Settings::default()));
+        let engine = Engine::init(Arc::clone(&settings), &Config::default()).unwrap();
+        let ctx = Context::new(
+            settings,
+            engine,
+            SessionStore::default(),
+            Arc::new(NoWindow),
+        );
+
+        let found = tauri::async_runtime::block_on(async {
+            let address = address.trim();
+            let _server = connect(&ctx, connection::Builder::address(address)?).await?;
+            let client = connection::Builder::address(address)?.build().await?;
+            let player = Proxy::new(
+                &client,
+                BUS_NAME,
+                OBJECT_PATH,
+                "org.mpris.MediaPlayer2.Player",
+            )
+            .await?;
+            let status: String = player.get_property("PlaybackStatus").await?;
+            let metadata: Metadata = player.get_property("Metadata").await?;
+            zbus::Result::Ok((status, metadata))
+        });
+        daemon.kill().unwrap();
+
+        let (status, mut metadata) = found.unwrap();
+        assert_eq!(status, "Stopped");
+        let track = OwnedObjectPath::try_from(metadata.remove("mpris:trackid").unwrap()).unwrap();
+        assert_eq!(track.as_str(), NO_TRACK);
+    }
+}
@@ -0,0 +1,118 @@
+//! M3U and extended M3U (`#EXTM3U` / `#EXTINF`) playlists. `.m3u8` files are the same format
+//! but always UTF-8, which is also what we write for both.
+
+use std::time::Duration;
+
+use super::Entry;
+
+pub(crate) fn parse(contents: &str) -> Vec<Entry> {
+    let mut entries = vec![];
+    let mut info: Option<(Option<Duration>, Option<String>)> = None;
+
+    for line in contents.lines() {
+        let line = line.trim().trim_start_matches('\u{feff}');
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            let (duration, title) = extinf.split_once(',').unwrap_or((extinf, ""));
+            // the duration may be followed by attributes such as `tvg-id="..."`
+            let duration = duration
+                .split_whitespace()
+                .next()
+                .and_then(|secs| secs.parse::<f64>().ok())
+                .filter(|secs| *secs >= 0.0)
+                .map(Duration::from_secs_f64);
+            let title = Some(title.trim().to_string()).filter(|title| !title.is_empty());
+            info = Some((duration, title));
+            continue;
+        }
+
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let (duration, title) = info.take().unwrap_or_default();
+        entries.push(Entry {
+            location: line.to_string(),
+            title,
+            duration,
+        });
+    }
+
+    entries
+}
+
+pub(crate) fn write(entries: &[Entry]) -> String {
+    let mut out = String::from("#EXTM3U\n");
+    for entry in entries {
+        let duration = entry
+            .duration
+            .map_or(-1, |duration| duration.as_secs() as i64);
+        out.push_str(&format!(
+            "#EXTINF:{},{}\n{}\n",
+            duration,
+            entry.title.as_deref().unwrap_or_default(),
+            entry.location
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse, write};
+    use crate::playlist::Entry;
+
+    #[test]
+    fn extended_info_belongs_to_the_next_location() {
+        let contents = "\u{feff}#EXTM3U\r\n\
+            #EXTINF:123 tvg-id=\"x\",Artist - Title\r\n\
+            Album/01 Song.flac\r\n\
+            \r\n\
+            # a comment\r\n\
+            #EXTINF:-1,\r\n\
+            http://radio.example/stream\r\n\
+            /music/plain.mp3\r\n";
+        assert_eq!(
+            parse(contents),
+            [
+                Entry {
+                    location: "Album/01 Song.flac".into(),
+                    title: Some("Artist - Title".into()),
+                    duration: Some(Duration::from_secs(123)),
+                },
+                Entry {
+                    location: "http://radio.example/stream".into(),
+                    title: None,
+                    duration: None,
+                },
+                Entry {
+                    location: "/music/plain.mp3".into(),
+                    title: None,
+                    duration: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn written_playlists_read_back_the_same() {
+        let entries = vec![
+            Entry {
+                location: "Album/01 Song.flac".into(),
+                title: Some("Song, with a comma".into()),
+                duration: Some(Duration::from_secs(200)),
+            },
+            Entry {
+                location: "/music/other.mp3".into(),
+                title: None,
+                duration: None,
+            },
+        ];
+        assert_eq!(parse(&write(&entries)), entries);
+    }
+}
@@ -0,0 +1,558 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::RwLock,
+    time::Duration,
+};
+
+use lofty::file::AudioFile;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    library::{self, LibraryState, SongData},
+    settings::SettingsState,
+};
+
+mod m3u;
+mod pls;
+pub mod smart;
+mod xspf;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("no playlist named {0:?}")]
+    NotFound(String),
+    #[error("a playlist named {0:?} already exists")]
+    AlreadyExists(String),
+    #[error("playlist has no entry at index {0}")]
+    InvalidEntry(usize),
+    #[error("unsupported playlist format: {0:?}")]
+    UnknownFormat(PathBuf),
+    #[error("unable to read or write playlists: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unable to parse playlists: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// A single entry as it appears in a playlist file, before being resolved to a path.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Entry {
+    pub(crate) location: String,
+    pub(crate) title: Option<String>,
+    pub(crate) duration: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    M3u,
+    Pls,
+    Xspf,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Result<Self, Error> {
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("m3u" | "m3u8") => Ok(Format::M3u),
+            Some("pls") => Ok(Format::Pls),
+            Some("xspf") => Ok(Format::Xspf),
+            _ => Err(Error::UnknownFormat(path.to_path_buf())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Playlist {
+    pub(crate) name: String,
+    pub(crate) entries: Vec<PathBuf>,
+}
+
+/// User playlists, persisted in their own file next to the settings.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PlaylistStore {
+    pub(crate) playlists: Vec<Playlist>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl PlaylistStore {
+    pub fn load_or_create(path: Option<PathBuf>) -> Result<Self, Error> {
+        let mut store: Self = match &path {
+            Some(path) if path.exists() => serde_json::from_str(&fs::read_to_string(path)?)?,
+            _ => Self::default(),
+        };
+        store.path = path;
+        Ok(store)
+    }
+
+    pub(crate) fn save(&self) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            fs::write(path, serde_json::to_string_pretty(self)?)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<&Playlist, Error> {
+        self.playlists
+            .iter()
+            .find(|playlist| playlist.name == name)
+            .ok_or_else(|| Error::NotFound(name.to_string()))
+    }
+
+    pub(crate) fn get_mut(&mut self, name: &str) -> Result<&mut Playlist, Error> {
+        self.playlists
+            .iter_mut()
+            .find(|playlist| playlist.name == name)
+            .ok_or_else(|| Error::NotFound(name.to_string()))
+    }
+
+    /// Returns `name`, or `name (2)`, `name (3)`, ... if it is already taken.
+    fn unique_name(&self, name: &str) -> String {
+        let taken = |candidate: &str| self.playlists.iter().any(|p| p.name == candidate);
+        if !taken(name) {
+            return name.to_string();
+        }
+        (2..)
+            .map(|n| format!("{} ({})", name, n))
+            .find(|candidate| !taken(candidate.as_str()))
+            .unwrap()
+    }
+}
+
+pub(crate) fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = text
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+pub(crate) fn percent_encode(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' | b':' => {
+                out.push(byte as char)
+            }
+            b'\\' if cfg!(windows) => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+/// Turns a playlist location into a path, or `None` if it points to something other than a
+/// local file (e.g. an `http://` stream).
+pub(crate) fn location_to_path(location: &str) -> Option<PathBuf> {
+    if let Some(path) = location.strip_prefix("file://") {
+        let path = percent_decode(path.strip_prefix("localhost").unwrap_or(path));
+        // `file:///C:/Music/...` on windows
+        if cfg!(windows) && path.get(2..3) == Some(":") {
+            return Some(PathBuf::from(&path[1..]));
+        }
+        return Some(PathBuf::from(path));
+    }
+
+    if location.contains("://") {
+        return None;
+    }
+
+    if cfg!(windows) {
+        Some(PathBuf::from(location))
+    } else {
+        // playlists written on windows use backslashes
+        Some(PathBuf::from(location.replace('\\', "/")))
+    }
+}
+
+/// Lexically removes `.` and `..` components so resolved paths match the ones in the library.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            component => out.push(component),
+        }
+    }
+    out
+}
+
+fn resolve(location: &str, base: &Path, roots: &[&Path]) -> Option<PathBuf> {
+    let path = location_to_path(location)?;
+
+    if path.is_absolute() {
+        if path.is_file() {
+            return Some(normalize(&path));
+        }
+
+        // the playlist was made on another machine or before the library moved, so look for the
+        // longest tail of the path which exists under one of the library roots
+        let components: Vec<_> = path
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        return (0..components.len())
+            .flat_map(|skip| {
+                let tail: PathBuf = components[skip..].iter().collect();
+                roots.iter().map(move |root| root.join(&tail))
+            })
+            .find(|candidate| candidate.is_file())
+            .map(|candidate| normalize(&candidate));
+    }
+
+    std::iter::once(base)
+        .chain(roots.iter().copied())
+        .map(|dir| dir.join(&path))
+        .find(|candidate| candidate.is_file())
+        .map(|candidate| normalize(&candidate))
+}
+
+/// Expresses `path` relative to the directory `base`, if they share a root.
+fn relative_to(path: &Path, base: &Path) -> Option<PathBuf> {
+    let mut path_components = path.components().peekable();
+    let mut base_components = base.components().peekable();
+
+    while let (Some(a), Some(b)) = (path_components.peek(), base_components.peek()) {
+        if a != b {
+            break;
+        }
+        path_components.next();
+        base_components.next();
+    }
+
+    if matches!(
+        path_components.peek(),
+        Some(Component::Prefix(_) | Component::RootDir)
+    ) {
+        return None;
+    }
+
+    let mut relative: PathBuf = base_components.map(|_| Component::ParentDir).collect();
+    relative.extend(path_components);
+    Some(relative)
+}
+
+pub type PlaylistStoreState<'a> = tauri::State<'a, RwLock<PlaylistStore>>;
+
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    name: String,
+    resolved: usize,
+    unresolved: Vec<String>,
+}
+
+#[tauri::command]
+pub fn playlist_list(store: PlaylistStoreState<'_>) -> Vec<String> {
+    store
+        .read()
+        .unwrap()
+        .playlists
+        .iter()
+        .map(|playlist| playlist.name.clone())
+        .collect()
+}
+
+#[tauri::command]
+pub fn playlist_create(name: String, store: PlaylistStoreState<'_>) -> crate::Result<()> {
+    let mut store = store.write().unwrap();
+    if store.get(&name).is_ok() {
+        return Err(Error::AlreadyExists(name))?;
+    }
+    store.playlists.push(Playlist {
+        name,
+        entries: vec![],
+    });
+    store.save()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn playlist_delete(name: String, store: PlaylistStoreState<'_>) -> crate::Result<()> {
+    let mut store = store.write().unwrap();
+    store.playlists.retain(|playlist| playlist.name != name);
+    store.save()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn playlist_songs(
+    name: String,
+    store: PlaylistStoreState<'_>,
+    library: LibraryState<'_>,
+) -> crate::Result<Vec<SongData>> {
+    let library = library.read().unwrap();
+    let library = library.as_ref().ok_or(library::Error::NotLoaded)?;
+    let store = store.read().unwrap();
+
+    // entries outside of the currently opened library are skipped
+    Ok(store
+        .get(&name)?
+        .entries
+        .iter()
+        .filter_map(|path| library.find(path))
+        .map(|id| library.files[id].song_data(id))
+        .collect())
+}
+
+#[tauri::command]
+pub fn playlist_add_songs(
+    name: String,
+    ids: Vec<usize>,
+    store: PlaylistStoreState<'_>,
+    library: LibraryState<'_>,
+) -> crate::Result<()> {
+    let library = library.read().unwrap();
+    let library = library.as_ref().ok_or(library::Error::NotLoaded)?;
+    let paths = ids
+        .into_iter()
+        .map(|id| {
+            library
+                .files
+                .get(id)
+                .map(|file| file.path.clone())
+                .ok_or(library::Error::InvalidId(id))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut store = store.write().unwrap();
+    store.get_mut(&name)?.entries.extend(paths);
+    store.save()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn playlist_remove_entry(
+    name: String,
+    index: usize,
+    store: PlaylistStoreState<'_>,
+) -> crate::Result<()> {
+    let mut store = store.write().unwrap();
+    let playlist = store.get_mut(&name)?;
+    if index >= playlist.entries.len() {
+        return Err(Error::InvalidEntry(index))?;
+    }
+    playlist.entries.remove(index);
+    store.save()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn playlist_import(
+    path: String,
+    store: PlaylistStoreState<'_>,
+    settings: SettingsState<'_>,
+) -> crate::Result<ImportReport> {
+    let path = PathBuf::from(path);
+    let format = Format::from_path(&path)?;
+
+    // plain `.m3u` files from older players are often latin-1 rather than utf-8
+    let contents = String::from_utf8(fs::read(&path)?)
+        .unwrap_or_else(|e| e.into_bytes().into_iter().map(char::from).collect());
+    let entries = match format {
+        Format::M3u => m3u::parse(&contents),
+        Format::Pls => pls::parse(&contents),
+        Format::Xspf => xspf::parse(&contents),
+    };
+
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+    let settings = settings.read().unwrap();
+    let roots: Vec<&Path> = settings.libraries.iter().map(PathBuf::as_path).collect();
+
+    let mut resolved = vec![];
+    let mut unresolved = vec![];
+    for entry in entries {
+        match resolve(&entry.location, base, &roots) {
+            Some(path) => resolved.push(path),
+            None => unresolved.push(entry.location),
+        }
+    }
+    drop(settings);
+
+    if !unresolved.is_empty() {
+        log::warn!(
+            "{} entries of playlist {:?} could not be resolved",
+            unresolved.len(),
+            path
+        );
+    }
+
+    let mut store = store.write().unwrap();
+    let name = store.unique_name(
+        &path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "Imported".to_string()),
+    );
+    let report = ImportReport {
+        name: name.clone(),
+        resolved: resolved.len(),
+        unresolved,
+    };
+    store.playlists.push(Playlist {
+        name,
+        entries: resolved,
+    });
+    store.save()?;
+
+    Ok(report)
+}
+
+#[tauri::command]
+pub fn playlist_export(
+    name: String,
+    path: String,
+    relative: bool,
+    store: PlaylistStoreState<'_>,
+    library: LibraryState<'_>,
+) -> crate::Result<()> {
+    let path = PathBuf::from(path);
+    let format = Format::from_path(&path)?;
+    let base = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let store = store.read().unwrap();
+    let playlist = store.get(&name)?;
+    let library = library.read().unwrap();
+
+    let entries: Vec<Entry> = playlist
+        .entries
+        .iter()
+        .map(|entry| {
+            let location = if relative {
+                relative_to(entry, base).unwrap_or_else(|| entry.clone())
+            } else {
+                entry.clone()
+            };
+            let file = library
+                .as_ref()
+                .and_then(|library| library.find(entry).map(|id| &library.files[id]));
+            Entry {
+                location: location.to_string_lossy().to_string(),
+                title: file.map(|file| file.title()),
+                duration: file.map(|file| file.metadata.properties().duration()),
+            }
+        })
+        .collect();
+
+    let contents = match format {
+        Format::M3u => m3u::write(&entries),
+        Format::Pls => pls::write(&entries),
+        Format::Xspf => xspf::write(&playlist.name, &entries),
+    };
+    fs::write(&path, contents)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{location_to_path, percent_decode, percent_encode, relative_to, resolve};
+
+    #[test]
+    fn file_urls_become_paths() {
+        assert_eq!(
+            location_to_path("file:///music/Some%20Album/01%20Song.flac"),
+            Some(PathBuf::from("/music/Some Album/01 Song.flac"))
+        );
+        assert_eq!(
+            location_to_path("file://localhost/music/song.flac"),
+            Some(PathBuf::from("/music/song.flac"))
+        );
+        assert_eq!(
+            location_to_path("Album/song.flac"),
+            Some(PathBuf::from("Album/song.flac"))
+        );
+        assert_eq!(location_to_path("http://radio.example/stream"), None);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn windows_separators_are_turned_around() {
+        assert_eq!(
+            location_to_path(r"..\Album\song.flac"),
+            Some(PathBuf::from("../Album/song.flac"))
+        );
+    }
+
+    #[test]
+    fn percent_encoding_round_trips() {
+        let path = "/music/AC/DC & Friends/#1 100%.flac";
+        let encoded = percent_encode(path);
+        assert_eq!(encoded, "/music/AC/DC%20%26%20Friends/%231%20100%25.flac");
+        assert_eq!(percent_decode(&encoded), path);
+        // a stray percent sign is kept as it is
+        assert_eq!(percent_decode("100%"), "100%");
+    }
+
+    #[test]
+    fn paths_are_made_relative_to_the_playlist() {
+        let base = Path::new("/music/playlists");
+        assert_eq!(
+            relative_to(Path::new("/music/Album/song.flac"), base),
+            Some(PathBuf::from("../Album/song.flac"))
+        );
+        assert_eq!(
+            relative_to(Path::new("/music/playlists/song.flac"), base),
+            Some(PathBuf::from("song.flac"))
+        );
+        assert_eq!(
+            relative_to(Path::new("/other/song.flac"), base),
+            Some(PathBuf::from("../../other/song.flac"))
+        );
+    }
+
+    #[test]
+    fn locations_resolve_against_the_playlist_and_the_libraries() {
+        let dir = std::env::temp_dir().join(format!("ample-test-playlist-{}", std::process::id()));
+        let music = dir.join("music");
+        let playlists = dir.join("playlists");
+        std::fs::create_dir_all(music.join("Album")).unwrap();
+        std::fs::create_dir_all(&playlists).unwrap();
+        let song = music.join("Album").join("song.flac");
+        std::fs::write(&song, b"").unwrap();
+        let roots = [music.as_path()];
+
+        // next to the playlist, with `..` taken out so it matches the library
+        assert_eq!(
+            resolve("../music/Album/./song.flac", &playlists, &roots),
+            Some(song.clone())
+        );
+        // within one of the libraries
+        assert_eq!(
+            resolve("Album/song.flac", &playlists, &roots),
+            Some(song.clone())
+        );
+        // written on another machine, found by the end of its path
+        let elsewhere = dir.join("elsewhere").join("Album").join("song.flac");
+        assert_eq!(
+            resolve(&elsewhere.to_string_lossy(), &playlists, &roots),
+            Some(song.clone())
+        );
+        assert_eq!(resolve("Album/missing.flac", &playlists, &roots), None);
+        assert_eq!(
+            resolve("http://radio.example/stream", &playlists, &roots),
+            None
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
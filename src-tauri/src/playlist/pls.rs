@@ -0,0 +1,121 @@
+//! PLS playlists, an INI-like format with numbered `FileN` / `TitleN` / `LengthN` keys.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use super::Entry;
+
+pub(crate) fn parse(contents: &str) -> Vec<Entry> {
+    let mut entries: BTreeMap<u32, Entry> = BTreeMap::new();
+
+    for line in contents.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_ascii_lowercase();
+        let value = value.trim();
+
+        let (field, index) = if let Some(index) = key.strip_prefix("file") {
+            ("file", index)
+        } else if let Some(index) = key.strip_prefix("title") {
+            ("title", index)
+        } else if let Some(index) = key.strip_prefix("length") {
+            ("length", index)
+        } else {
+            continue;
+        };
+        let Ok(index) = index.parse::<u32>() else {
+            continue;
+        };
+
+        let entry = entries.entry(index).or_insert_with(|| Entry {
+            location: String::new(),
+            title: None,
+            duration: None,
+        });
+        match field {
+            "file" => entry.location = value.to_string(),
+            "title" => entry.title = Some(value.to_string()).filter(|title| !title.is_empty()),
+            _ => {
+                entry.duration = value
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|secs| *secs >= 0)
+                    .map(|secs| Duration::from_secs(secs as u64))
+            }
+        }
+    }
+
+    entries
+        .into_values()
+        .filter(|entry| !entry.location.is_empty())
+        .collect()
+}
+
+pub(crate) fn write(entries: &[Entry]) -> String {
+    let mut out = String::from("[playlist]\n");
+    for (index, entry) in entries.iter().enumerate() {
+        let index = index + 1;
+        out.push_str(&format!("File{}={}\n", index, entry.location));
+        if let Some(title) = &entry.title {
+            out.push_str(&format!("Title{}={}\n", index, title));
+        }
+        let length = entry
+            .duration
+            .map_or(-1, |duration| duration.as_secs() as i64);
+        out.push_str(&format!("Length{}={}\n", index, length));
+    }
+    out.push_str(&format!("NumberOfEntries={}\nVersion=2\n", entries.len()));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse, write};
+    use crate::playlist::Entry;
+
+    #[test]
+    fn entries_are_put_together_by_number() {
+        let contents = "[playlist]\n\
+            Title2=Second\n\
+            file2=Album\\02 Second.flac\n\
+            File1 = first.mp3\n\
+            Length1=-1\n\
+            LENGTH2=42\n\
+            Title3=Nowhere\n\
+            NumberOfEntries=3\n";
+        assert_eq!(
+            parse(contents),
+            [
+                Entry {
+                    location: "first.mp3".into(),
+                    title: None,
+                    duration: None,
+                },
+                Entry {
+                    location: r"Album\02 Second.flac".into(),
+                    title: Some("Second".into()),
+                    duration: Some(Duration::from_secs(42)),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn written_playlists_read_back_the_same() {
+        let entries = vec![
+            Entry {
+                location: "/music/Album/01 Song.flac".into(),
+                title: Some("Song = Title".into()),
+                duration: Some(Duration::from_secs(200)),
+            },
+            Entry {
+                location: "http://radio.example/stream".into(),
+                title: None,
+                duration: None,
+            },
+        ];
+        assert_eq!(parse(&write(&entries)), entries);
+    }
+}
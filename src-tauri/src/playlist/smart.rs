@@ -0,0 +1,556 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use lofty::{
+    file::{AudioFile, TaggedFileExt},
+    tag::Accessor,
+};
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+use crate::{
+    audio::QueueEntry,
+    context::ContextState,
+    library::{self, File, Library, LibraryState, SongData},
+    settings::{Settings, SettingsState, SongStats, MAX_RATING},
+};
+
+/// A named, saved query over the library which is evaluated every time it is listed or played,
+/// so it always reflects the currently opened library.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmartPlaylist {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) matching: Matching,
+    #[serde(default)]
+    pub(crate) rules: Vec<Rule>,
+    #[serde(default)]
+    pub(crate) sort: Option<Sort>,
+    #[serde(default)]
+    pub(crate) limit: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Matching {
+    #[default]
+    All,
+    Any,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "field", rename_all = "snake_case")]
+pub enum Rule {
+    Tag {
+        key: TagKey,
+        op: TextOp,
+        value: String,
+    },
+    Path {
+        op: TextOp,
+        value: String,
+    },
+    Duration {
+        op: Compare,
+        seconds: u64,
+    },
+    PlayCount {
+        op: Compare,
+        value: u32,
+    },
+    Rating {
+        op: Compare,
+        value: u8,
+    },
+    /// Compares against how many days ago the file was added.
+    DateAdded {
+        op: Compare,
+        days_ago: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TagKey {
+    Title,
+    Artist,
+    Album,
+    Genre,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextOp {
+    Is,
+    IsNot,
+    Contains,
+    StartsWith,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compare {
+    Less,
+    Equal,
+    Greater,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Sort {
+    pub(crate) key: SortKey,
+    #[serde(default)]
+    pub(crate) descending: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    Title,
+    Artist,
+    Album,
+    Duration,
+    PlayCount,
+    Rating,
+    DateAdded,
+    Path,
+}
+
+impl SmartPlaylist {
+    /// Fails for rules which no song could ever match.
+    fn check(&self) -> Result<(), library::Error> {
+        for rule in &self.rules {
+            if let Rule::Rating { value, .. } = rule {
+                if *value > MAX_RATING {
+                    return Err(library::Error::InvalidRating(*value));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TextOp {
+    fn matches(self, haystack: &str, needle: &str) -> bool {
+        let haystack = haystack.to_lowercase();
+        let needle = needle.to_lowercase();
+        match self {
+            TextOp::Is => haystack == needle,
+            TextOp::IsNot => haystack != needle,
+            TextOp::Contains => haystack.contains(&needle),
+            TextOp::StartsWith => haystack.starts_with(&needle),
+        }
+    }
+}
+
+impl Compare {
+    fn matches<T: Ord>(self, lhs: T, rhs: T) -> bool {
+        lhs.cmp(&rhs)
+            == match self {
+                Compare::Less => Ordering::Less,
+                Compare::Equal => Ordering::Equal,
+                Compare::Greater => Ordering::Greater,
+            }
+    }
+}
+
+fn tag_value(file: &File, key: TagKey) -> Option<String> {
+    let tag = file.metadata.primary_tag()?;
+    let value = match key {
+        TagKey::Title => tag.title(),
+        TagKey::Artist => tag.artist(),
+        TagKey::Album => tag.album(),
+        TagKey::Genre => tag.genre(),
+    };
+    value.map(|value| value.to_string())
+}
+
+fn days_since(time: SystemTime) -> u64 {
+    SystemTime::now()
+        .duration_since(time)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+        / (60 * 60 * 24)
+}
+
+impl Rule {
+    fn matches(&self, file: &File, stats: Option<&SongStats>) -> bool {
+        match self {
+            Rule::Tag { key, op, value } => match tag_value(file, *key) {
+                Some(tag) => op.matches(&tag, value),
+                None => matches!(op, TextOp::IsNot),
+            },
+            Rule::Path { op, value } => op.matches(&file.path.to_string_lossy(), value),
+            Rule::Duration { op, seconds } => {
+                op.matches(file.metadata.properties().duration().as_secs(), *seconds)
+            }
+            Rule::PlayCount { op, value } => {
+                op.matches(stats.map_or(0, |stats| stats.play_count), *value)
+            }
+            Rule::Rating { op, value } => {
+                op.matches(stats.and_then(|stats| stats.rating).unwrap_or(0), *value)
+            }
+            Rule::DateAdded { op, days_ago } => match file.added {
+                Some(added) => op.matches(days_since(added), *days_ago),
+                None => false,
+            },
+        }
+    }
+}
+
+impl SmartPlaylist {
+    /// Returns the ids of the songs in `library` matching this playlist, sorted and limited.
+    pub(crate) fn evaluate(
+        &self,
+        library: &Library,
+        stats: &HashMap<PathBuf, SongStats>,
+    ) -> Vec<usize> {
+        let mut ids: Vec<usize> = library
+            .files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| {
+                let stats = stats.get(&file.path);
+                match self.matching {
+                    Matching::All => self.rules.iter().all(|rule| rule.matches(file, stats)),
+                    Matching::Any => {
+                        self.rules.is_empty()
+                            || self.rules.iter().any(|rule| rule.matches(file, stats))
+                    }
+                }
+            })
+            .map(|(id, _)| id)
+            .collect();
+
+        if let Some(sort) = self.sort {
+            ids.sort_by(|&a, &b| {
+                let (a, b) = (&library.files[a], &library.files[b]);
+                let (a_stats, b_stats) = (stats.get(&a.path), stats.get(&b.path));
+                let ordering = match sort.key {
+                    SortKey::Title => a.title().cmp(&b.title()),
+                    SortKey::Artist => {
+                        tag_value(a, TagKey::Artist).cmp(&tag_value(b, TagKey::Artist))
+                    }
+                    SortKey::Album => tag_value(a, TagKey::Album).cmp(&tag_value(b, TagKey::Album)),
+                    SortKey::Duration => a
+                        .metadata
+                        .properties()
+                        .duration()
+                        .cmp(&b.metadata.properties().duration()),
+                    SortKey::PlayCount => a_stats
+                        .map(|stats| stats.play_count)
+                        .cmp(&b_stats.map(|stats| stats.play_count)),
+                    SortKey::Rating => a_stats
+                        .and_then(|stats| stats.rating)
+                        .cmp(&b_stats.and_then(|stats| stats.rating)),
+                    SortKey::DateAdded => a.added.cmp(&b.added),
+                    SortKey::Path => a.path.cmp(&b.path),
+                };
+                if sort.descending {
+                    ordering.reverse()
+                } else {
+                    ordering
+                }
+            });
+        }
+
+        if let Some(limit) = self.limit {
+            ids.truncate(limit);
+        }
+
+        ids
+    }
+}
+
+/// The smart playlist called `name`.
+fn find(settings: &Settings, name: String) -> Result<&SmartPlaylist, Error> {
+    settings
+        .smart_playlists
+        .iter()
+        .find(|playlist| playlist.name == name)
+        .ok_or(Error::NotFound(name))
+}
+
+#[tauri::command]
+pub fn smart_playlist_list(settings: SettingsState<'_>) -> Vec<SmartPlaylist> {
+    settings.read().unwrap().smart_playlists.clone()
+}
+
+#[tauri::command]
+pub fn smart_playlist_save(
+    playlist: SmartPlaylist,
+    settings: SettingsState<'_>,
+) -> crate::Result<()> {
+    playlist.check()?;
+    let mut settings = settings.write().unwrap();
+    match settings
+        .smart_playlists
+        .iter_mut()
+        .find(|existing| existing.name == playlist.name)
+    {
+        Some(existing) => *existing = playlist,
+        None => settings.smart_playlists.push(playlist),
+    }
+    settings.save()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn smart_playlist_remove(name: String, settings: SettingsState<'_>) -> crate::Result<()> {
+    let mut settings = settings.write().unwrap();
+    settings
+        .smart_playlists
+        .retain(|playlist| playlist.name != name);
+    settings.save()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn smart_playlist_songs(
+    name: String,
+    library: LibraryState<'_>,
+    settings: SettingsState<'_>,
+) -> crate::Result<Vec<SongData>> {
+    let library = library.read().unwrap();
+    let library = library.as_ref().ok_or(library::Error::NotLoaded)?;
+
+    let settings = settings.read().unwrap();
+    Ok(find(&settings, name)?
+        .evaluate(library, &settings.stats)
+        .into_iter()
+        .map(|id| library.files[id].song_data(id))
+        .collect())
+}
+
+/// Adds the songs of the smart playlist called `name` to the queue, as they are right now.
+#[tauri::command]
+pub fn smart_playlist_enqueue(name: String, context: ContextState<'_>) -> crate::Result<()> {
+    let entries: Vec<QueueEntry> = {
+        let library = context.library.read().unwrap();
+        let library = library.as_ref().ok_or(library::Error::NotLoaded)?;
+        let settings = context.settings.read().unwrap();
+        find(&settings, name)?
+            .evaluate(library, &settings.stats)
+            .into_iter()
+            .map(|id| {
+                let file = &library.files[id];
+                QueueEntry::new(id, file.path.clone(), file.album())
+            })
+            .collect()
+    };
+    context.change_queue(|engine| {
+        engine.queue.add(entries);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use super::{Compare, Matching, Rule, SmartPlaylist, Sort, SortKey, TagKey, TextOp};
+    use crate::{
+        library::{
+            tests::{library, tag},
+            Library,
+        },
+        settings::SongStats,
+    };
+
+    fn playlist(matching: Matching, rules: Vec<Rule>) -> SmartPlaylist {
+        SmartPlaylist {
+            name: "Favourites".into(),
+            matching,
+            rules,
+            sort: None,
+            limit: None,
+        }
+    }
+
+    fn rated(value: u8) -> SmartPlaylist {
+        playlist(
+            Matching::All,
+            vec![Rule::Rating {
+                op: Compare::Greater,
+                value,
+            }],
+        )
+    }
+
+    /// Two songs by A, played three times and rated 5 and played once and unrated, and a
+    /// song by B which was never played.
+    fn songs(name: &str) -> (PathBuf, Library, HashMap<PathBuf, SongStats>) {
+        let (dir, mut library) = library(name, &["a/1.wav", "a/2.wav", "b/3.wav"]);
+        tag(&mut library.files[0], "A", "One");
+        tag(&mut library.files[1], "A", "One");
+        tag(&mut library.files[2], "B", "Two");
+        let stats = HashMap::from([
+            (
+                library.files[0].path.clone(),
+                SongStats {
+                    play_count: 3,
+                    rating: Some(5),
+                    ..SongStats::default()
+                },
+            ),
+            (
+                library.files[1].path.clone(),
+                SongStats {
+                    play_count: 1,
+                    ..SongStats::default()
+                },
+            ),
+        ]);
+        (dir, library, stats)
+    }
+
+    #[test]
+    fn songs_match_each_rule() {
+        let (dir, library, stats) = songs("smart-rules");
+        let matching = |rule: Rule| playlist(Matching::All, vec![rule]).evaluate(&library, &stats);
+
+        assert_eq!(
+            matching(Rule::Tag {
+                key: TagKey::Artist,
+                op: TextOp::Is,
+                value: "a".into(),
+            }),
+            [0, 1]
+        );
+        assert_eq!(
+            matching(Rule::Tag {
+                key: TagKey::Album,
+                op: TextOp::IsNot,
+                value: "One".into(),
+            }),
+            [2]
+        );
+        // the title is not tagged, which only `is not` matches
+        assert_eq!(
+            matching(Rule::Tag {
+                key: TagKey::Title,
+                op: TextOp::IsNot,
+                value: "x".into(),
+            }),
+            [0, 1, 2]
+        );
+        assert_eq!(
+            matching(Rule::Path {
+                op: TextOp::Contains,
+                value: "3.WAV".into(),
+            }),
+            [2]
+        );
+        assert_eq!(
+            matching(Rule::Path {
+                op: TextOp::StartsWith,
+                value: dir.join("a").to_string_lossy().into(),
+            }),
+            [0, 1]
+        );
+        assert_eq!(
+            matching(Rule::Duration {
+                op: Compare::Less,
+                seconds: 1,
+            }),
+            [0, 1, 2]
+        );
+        assert!(matching(Rule::Duration {
+            op: Compare::Greater,
+            seconds: 0,
+        })
+        .is_empty());
+        assert_eq!(
+            matching(Rule::PlayCount {
+                op: Compare::Equal,
+                value: 0,
+            }),
+            [2]
+        );
+        assert_eq!(
+            matching(Rule::PlayCount {
+                op: Compare::Greater,
+                value: 0,
+            }),
+            [0, 1]
+        );
+        // unrated songs, with or without stats, count as rated 0
+        assert_eq!(
+            matching(Rule::Rating {
+                op: Compare::Less,
+                value: 1,
+            }),
+            [1, 2]
+        );
+        assert_eq!(
+            matching(Rule::Rating {
+                op: Compare::Equal,
+                value: 5,
+            }),
+            [0]
+        );
+        assert!(matching(Rule::DateAdded {
+            op: Compare::Greater,
+            days_ago: 0,
+        })
+        .is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn songs_match_all_or_any_rules() {
+        let (dir, library, stats) = songs("smart-matching");
+        let rules = vec![
+            Rule::Tag {
+                key: TagKey::Artist,
+                op: TextOp::Is,
+                value: "B".into(),
+            },
+            Rule::PlayCount {
+                op: Compare::Greater,
+                value: 2,
+            },
+        ];
+        let all = playlist(Matching::All, rules.clone());
+        assert!(all.evaluate(&library, &stats).is_empty());
+        let any = playlist(Matching::Any, rules);
+        assert_eq!(any.evaluate(&library, &stats), [0, 2]);
+
+        // without rules everything matches either way
+        for matching in [Matching::All, Matching::Any] {
+            let everything = playlist(matching, Vec::new());
+            assert_eq!(everything.evaluate(&library, &stats), [0, 1, 2]);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn songs_are_sorted_and_limited() {
+        let (dir, library, stats) = songs("smart-sort");
+        let sorted = |key: SortKey, descending: bool, limit: Option<usize>| {
+            let mut playlist = playlist(Matching::All, Vec::new());
+            playlist.sort = Some(Sort { key, descending });
+            playlist.limit = limit;
+            playlist.evaluate(&library, &stats)
+        };
+
+        assert_eq!(sorted(SortKey::PlayCount, false, None), [2, 1, 0]);
+        assert_eq!(sorted(SortKey::PlayCount, true, None), [0, 1, 2]);
+        assert_eq!(sorted(SortKey::PlayCount, true, Some(2)), [0, 1]);
+        // unrated songs come first, in library order
+        assert_eq!(sorted(SortKey::Rating, false, None), [1, 2, 0]);
+        assert_eq!(sorted(SortKey::Artist, true, None), [2, 0, 1]);
+        assert_eq!(sorted(SortKey::Path, true, Some(1)), [2]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rating_rules_stay_within_the_ratings() {
+        assert!(rated(0).check().is_ok());
+        assert!(rated(5).check().is_ok());
+        assert!(rated(6).check().is_err());
+    }
+}
@@ -0,0 +1,232 @@
+//! XSPF ("spiff") XML playlists. Only `<location>`, `<title>` and `<duration>` of each
+//! `<track>` are understood, which is all other players put in there in practice.
+
+use std::{path::Path, time::Duration};
+
+use super::{percent_decode, percent_encode, Entry};
+
+/// Finds the first `<name>...</name>` element in `haystack`, returning its inner text and
+/// everything after the closing tag.
+fn element<'a>(haystack: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let open = format!("<{}", name);
+    let close = format!("</{}>", name);
+
+    let mut offset = 0;
+    loop {
+        let start = offset + haystack[offset..].find(&open)?;
+        let after = &haystack[start + open.len()..];
+        // make sure `<track` does not match `<trackList>`
+        match after.chars().next() {
+            Some('>') => {
+                let inner = &after[1..];
+                let end = inner.find(&close)?;
+                return Some((&inner[..end], &inner[end + close.len()..]));
+            }
+            Some(c) if c.is_whitespace() || c == '/' => {
+                let gt = after.find('>')?;
+                if after[..gt].ends_with('/') {
+                    return Some(("", &after[gt + 1..]));
+                }
+                let inner = &after[gt + 1..];
+                let end = inner.find(&close)?;
+                return Some((&inner[..end], &inner[end + close.len()..]));
+            }
+            _ => offset = start + open.len(),
+        }
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';') else {
+            break;
+        };
+        let decoded = match &rest[1..semi] {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => entity
+                .strip_prefix("#x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+pub(crate) fn parse(contents: &str) -> Vec<Entry> {
+    let mut entries = vec![];
+    let Some((mut track_list, _)) = element(contents, "trackList") else {
+        return entries;
+    };
+
+    while let Some((track, rest)) = element(track_list, "track") {
+        track_list = rest;
+
+        let Some((location, _)) = element(track, "location") else {
+            continue;
+        };
+        let location = unescape(location.trim());
+        // relative locations are URI references, absolute ones are kept as URIs
+        let location = if location.contains("://") {
+            location
+        } else {
+            percent_decode(&location)
+        };
+
+        let title = element(track, "title")
+            .map(|(title, _)| unescape(title.trim()))
+            .filter(|title| !title.is_empty());
+        let duration = element(track, "duration")
+            .and_then(|(duration, _)| duration.trim().parse::<u64>().ok())
+            .map(Duration::from_millis);
+
+        entries.push(Entry {
+            location,
+            title,
+            duration,
+        });
+    }
+
+    entries
+}
+
+pub(crate) fn write(name: &str, entries: &[Entry]) -> String {
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    out.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+    out.push_str(&format!(
+        "  <title>{}</title>\n  <trackList>\n",
+        escape(name)
+    ));
+    for entry in entries {
+        out.push_str("    <track>\n");
+        let location = if entry.location.contains("://") {
+            entry.location.clone()
+        } else if Path::new(&entry.location).is_absolute() {
+            let location = percent_encode(&entry.location);
+            if location.starts_with('/') {
+                format!("file://{}", location)
+            } else {
+                format!("file:///{}", location)
+            }
+        } else {
+            percent_encode(&entry.location)
+        };
+        out.push_str(&format!(
+            "      <location>{}</location>\n",
+            escape(&location)
+        ));
+        if let Some(title) = &entry.title {
+            out.push_str(&format!("      <title>{}</title>\n", escape(title)));
+        }
+        if let Some(duration) = entry.duration {
+            out.push_str(&format!(
+                "      <duration>{}</duration>\n",
+                duration.as_millis()
+            ));
+        }
+        out.push_str("    </track>\n");
+    }
+    out.push_str("  </trackList>\n</playlist>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::{parse, write};
+    use crate::playlist::{location_to_path, Entry};
+
+    #[test]
+    fn tracks_are_read_with_escapes_decoded() {
+        let contents = r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <title>Ignored</title>
+  <trackList>
+    <track>
+      <location>Album/Rock%20&amp;%20Roll.flac</location>
+      <title>Rock &amp; Roll &#x2013; Live</title>
+      <duration>61500</duration>
+    </track>
+    <track><title>No location</title></track>
+    <track>
+      <location>file:///music/a%20b.flac</location>
+      <title/>
+    </track>
+  </trackList>
+</playlist>"#;
+        assert_eq!(
+            parse(contents),
+            [
+                Entry {
+                    location: "Album/Rock & Roll.flac".into(),
+                    title: Some("Rock & Roll \u{2013} Live".into()),
+                    duration: Some(Duration::from_millis(61500)),
+                },
+                Entry {
+                    location: "file:///music/a%20b.flac".into(),
+                    title: None,
+                    duration: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn written_playlists_read_back_the_same() {
+        let relative = Entry {
+            location: "Album/<Odd> & 'Quoted' #1.flac".into(),
+            title: Some("Title & \"Quotes\"".into()),
+            duration: Some(Duration::from_millis(1234)),
+        };
+        let written = write("Mix & Match", &[relative]);
+        let read = parse(&written);
+        assert_eq!(
+            read,
+            [Entry {
+                location: "Album/<Odd> & 'Quoted' #1.flac".into(),
+                title: Some("Title & \"Quotes\"".into()),
+                duration: Some(Duration::from_millis(1234)),
+            }]
+        );
+
+        // absolute paths are written as file URLs
+        let absolute = Entry {
+            location: "/music/a b.flac".into(),
+            title: None,
+            duration: None,
+        };
+        let read = parse(&write("Absolute", &[absolute]));
+        assert_eq!(
+            location_to_path(&read[0].location),
+            Some(PathBuf::from("/music/a b.flac"))
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! The methods of the API, doing what the Tauri commands of the same operation do.
+
+use std::path::PathBuf;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use super::RpcError;
+use crate::{
+    audio::{self, Engine, Repeat, SeekPrecision, SeekTarget, Shuffle},
+    context::Context,
+    ipc,
+    library::{self, SongData},
+};
+
+#[derive(Deserialize)]
+struct Id {
+    id: usize,
+}
+
+#[derive(Deserialize)]
+struct Ids {
+    ids: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct Index {
+    index: usize,
+}
+
+#[derive(Deserialize)]
+struct Insert {
+    index: usize,
+    ids: Vec<usize>,
+}
+
+#[derive(Deserialize)]
+struct Move {
+    from: usize,
+    to: usize,
+}
+
+#[derive(Deserialize)]
+struct Bookmark {
+    id: usize,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct AddBookmark {
+    id: usize,
+    name: String,
+    seconds: f64,
+}
+
+#[derive(Deserialize)]
+struct Name {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct Query {
+    query: String,
+}
+
+#[derive(Deserialize)]
+struct Open {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct Paths {
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct Seek {
+    target: SeekTarget,
+    precision: Option<SeekPrecision>,
+}
+
+#[derive(Deserialize)]
+struct Volume {
+    level: f64,
+}
+
+#[derive(Deserialize)]
+struct SetRepeat {
+    repeat: Repeat,
+}
+
+#[derive(Deserialize)]
+struct SetShuffle {
+    shuffle: Shuffle,
+}
+
+/// Reads the params of a method, by name or by position.
+pub(super) fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    let params = match params {
+        Value::Null => Value::Object(Default::default()),
+        params => params,
+    };
+    serde_json::from_value(params).map_err(RpcError::invalid_params)
+}
+
+fn reply<T: Serialize>(result: crate::Result<T>) -> Result<Value, RpcError> {
+    Ok(serde_json::to_value(result?).unwrap_or_default())
+}
+
+pub(super) async fn call(ctx: &Context, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "library.list" => reply(Ok(library::songs(ctx.library.read().unwrap().as_ref()))),
+        "library.search" => {
+            let Query { query } = parse_params(params)?;
+            reply(Ok(search(ctx, &query)))
+        }
+        "library.open" => {
+            let Open { path } = parse_params(params)?;
+            reply(library::open(ctx, path.into()).await)
+        }
+        "library.bookmarks" => {
+            let Id { id } = parse_params(params)?;
+            reply(library::bookmarks(&ctx.library, &ctx.settings, id))
+        }
+        "library.add_bookmark" => {
+            let AddBookmark { id, name, seconds } = parse_params(params)?;
+            reply(library::add_bookmark(
+                &ctx.library,
+                &ctx.settings,
+                id,
+                name,
+                seconds,
+            ))
+        }
+        "library.remove_bookmark" => {
+            let Bookmark { id, name } = parse_params(params)?;
+            reply(library::remove_bookmark(
+                &ctx.library,
+                &ctx.settings,
+                id,
+                &name,
+            ))
+        }
+
+        "queue.open" => {
+            let Paths { paths } = parse_params(params)?;
+            reply(ipc::open(ctx, &paths).await)
+        }
+        "queue.enqueue" => {
+            let Paths { paths } = parse_params(params)?;
+            reply(ipc::enqueue(ctx, &paths))
+        }
+        "queue.list" => reply(Ok(ctx.engine.read().unwrap().queue.payload())),
+        "queue.add" => {
+            let Ids { ids } = parse_params(params)?;
+            let entries = audio::queue_entries(ids, &ctx.library)?;
+            reply(ctx.change_queue(|engine| {
+                engine.queue.add(entries);
+                Ok(())
+            }))
+        }
+        "queue.insert" => {
+            let Insert { index, ids } = parse_params(params)?;
+            let entries = audio::queue_entries(ids, &ctx.library)?;
+            reply(ctx.change_queue(|engine| Ok(engine.queue.insert(index, entries)?)))
+        }
+        "queue.remove" => {
+            let Index { index } = parse_params(params)?;
+            reply(ctx.change_queue(|engine| Ok(engine.queue.remove(index)?)))
+        }
+        "queue.move" => {
+            let Move { from, to } = parse_params(params)?;
+            reply(ctx.change_queue(|engine| Ok(engine.queue.move_entry(from, to)?)))
+        }
+        "queue.clear" => reply(ctx.change_queue(|engine| {
+            engine.queue.clear();
+            Ok(())
+        })),
+        "queue.shuffle" => reply(ctx.change_queue(|engine| {
+            engine.queue.shuffle();
+            Ok(())
+        })),
+        "queue.jump" => {
+            let Index { index } = parse_params(params)?;
+            reply(with_engine(ctx, |engine| engine.jump(index)))
+        }
+        "queue.set_repeat" => {
+            let SetRepeat { repeat } = parse_params(params)?;
+            reply(ctx.change_queue(|engine| Ok(engine.set_repeat(repeat)?)))
+        }
+        "queue.set_shuffle" => {
+            let SetShuffle { shuffle } = parse_params(params)?;
+            reply(ctx.change_queue(|engine| Ok(engine.set_shuffle(shuffle)?)))
+        }
+
+        "playback.status" => Ok(ipc::status(ctx)),
+        "playback.start" => {
+            let Id { id } = parse_params(params)?;
+            reply(audio::start_song(ctx, id).map(drop))
+        }
+        "playback.play" => reply(with_engine(ctx, Engine::play)),
+        "playback.pause" => reply(with_engine(ctx, Engine::pause)),
+        "playback.toggle" => reply(with_engine(ctx, Engine::toggle)),
+        "playback.next" => reply(with_engine(ctx, Engine::next)),
+        "playback.previous" => reply(with_engine(ctx, Engine::previous)),
+        "playback.seek" => {
+            let Seek { target, precision } = parse_params(params)?;
+            reply(with_engine(ctx, |engine| {
+                engine.seek(target, precision.unwrap_or_default())
+            }))
+        }
+        "playback.jump_bookmark" => {
+            let Name { name } = parse_params(params)?;
+            reply(with_engine(ctx, |engine| engine.jump_to_bookmark(&name)))
+        }
+        "playback.chapters" => reply(ctx.engine.read().unwrap().chapters().map_err(Into::into)),
+        "playback.set_volume" => {
+            let Volume { level } = parse_params(params)?;
+            reply(with_engine(ctx, |engine| engine.set_volume(level)))
+        }
+        "playback.toggle_mute" => reply(with_engine(ctx, Engine::toggle_mute)),
+
+        "window.focus" => {
+            ctx.focus();
+            Ok(Value::Null)
+        }
+        "app.quit" => {
+            ctx.quit();
+            Ok(Value::Null)
+        }
+
+        method => Err(RpcError::method_not_found(method)),
+    }
+}
+
+/// Runs `f` on the engine.
+fn with_engine<T>(
+    ctx: &Context,
+    f: impl FnOnce(&mut Engine) -> Result<T, audio::Error>,
+) -> crate::Result<T> {
+    f(&mut ctx.engine.write().unwrap()).map_err(Into::into)
+}
+
+/// Songs whose title, artist or album contains `query`, ignoring case.
+fn search(ctx: &Context, query: &str) -> Vec<SongData> {
+    let library = ctx.library.read().unwrap();
+    let Some(library) = library.as_ref() else {
+        return vec![];
+    };
+    let query = query.to_lowercase();
+    let matches =
+        |value: Option<String>| value.is_some_and(|value| value.to_lowercase().contains(&query));
+    library
+        .files
+        .iter()
+        .enumerate()
+        .filter(|(_, file)| {
+            matches(Some(file.title())) || matches(file.artist()) || matches(file.album())
+        })
+        .map(|(id, file)| file.song_data(id))
+        .collect()
+}
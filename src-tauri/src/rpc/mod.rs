@@ -0,0 +1,608 @@
+//! Optional JSON-RPC 2.0 API on a Unix socket, one request, batch or notification per line.
+//! The methods are listed in `methods.rs` and described in `docs/json-rpc.md`.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::UnixStream,
+    sync::broadcast,
+};
+
+use crate::{
+    audio::PlaybackEvent,
+    context::{Change, Context},
+};
+
+mod methods;
+
+/// Kinds of events a client can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Topic {
+    /// Everything the engine reports, except the position.
+    Playback,
+    /// The position within the current song, which changes many times a second.
+    Position,
+    Queue,
+    Library,
+}
+
+#[derive(Debug, Clone)]
+struct Event {
+    topic: Topic,
+    payload: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    /// Missing for notifications, which get no response, unlike an id of `null`.
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Value>,
+}
+
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    #[serde(flatten)]
+    outcome: Outcome,
+    id: Value,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Outcome {
+    Result(Value),
+    Error(RpcError),
+}
+
+impl Response {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        Self {
+            jsonrpc: "2.0",
+            outcome: match outcome {
+                Ok(value) => Outcome::Result(value),
+                Err(error) => Outcome::Error(error),
+            },
+            id,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    fn parse_error(error: serde_json::Error) -> Self {
+        Self::new(-32700, format!("parse error: {}", error))
+    }
+
+    fn invalid_request(message: impl std::fmt::Display) -> Self {
+        Self::new(-32600, format!("invalid request: {}", message))
+    }
+
+    fn method_not_found(method: &str) -> Self {
+        Self::new(-32601, format!("method not found: {}", method))
+    }
+
+    fn invalid_params(error: serde_json::Error) -> Self {
+        Self::new(-32602, format!("invalid params: {}", error))
+    }
+}
+
+/// Failures of the operations themselves, with the error the frontend would get as data.
+impl From<crate::Error> for RpcError {
+    fn from(error: crate::Error) -> Self {
+        Self {
+            code: -32000,
+            message: error.message.clone(),
+            data: serde_json::to_value(&error).ok(),
+        }
+    }
+}
+
+/// The subscriptions of one connection.
+#[derive(Default)]
+struct Subscriptions {
+    next_id: u64,
+    topics: BTreeMap<u64, Vec<Topic>>,
+    /// Only while there are subscriptions, so events do not pile up unread.
+    events: Option<broadcast::Receiver<Event>>,
+}
+
+#[derive(Deserialize)]
+struct SubscribeParams {
+    /// Every topic if empty.
+    #[serde(default)]
+    topics: Vec<Topic>,
+}
+
+#[derive(Deserialize)]
+struct UnsubscribeParams {
+    subscription: u64,
+}
+
+impl Subscriptions {
+    fn subscribe(
+        &mut self,
+        events: &broadcast::Sender<Event>,
+        params: Value,
+    ) -> Result<Value, RpcError> {
+        let SubscribeParams { mut topics } = methods::parse_params(params)?;
+        if topics.is_empty() {
+            topics = vec![
+                Topic::Playback,
+                Topic::Position,
+                Topic::Queue,
+                Topic::Library,
+            ];
+        }
+        self.next_id += 1;
+        self.topics.insert(self.next_id, topics);
+        self.events.get_or_insert_with(|| events.subscribe());
+        Ok(json!(self.next_id))
+    }
+
+    fn unsubscribe(&mut self, params: Value) -> Result<Value, RpcError> {
+        let UnsubscribeParams { subscription } = methods::parse_params(params)?;
+        let removed = self.topics.remove(&subscription).is_some();
+        if self.topics.is_empty() {
+            self.events = None;
+        }
+        Ok(json!(removed))
+    }
+
+    async fn next(&mut self) -> Result<Event, broadcast::error::RecvError> {
+        match &mut self.events {
+            Some(events) => events.recv().await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// The notifications `event` turns into, one per subscription which wants it.
+    fn notifications(&self, event: &Event) -> Vec<Value> {
+        self.topics
+            .iter()
+            .filter(|(_, topics)| topics.contains(&event.topic))
+            .map(|(subscription, _)| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "event",
+                    "params": {
+                        "subscription": subscription,
+                        "topic": event.topic,
+                        "event": event.payload,
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+/// Starts serving the API on the Unix socket at `path`.
+pub fn spawn(ctx: Context, path: PathBuf) {
+    let events = watch(&ctx);
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = listen(ctx, events, &path).await {
+            log::error!("JSON-RPC server on {} stopped: {}", path.display(), e);
+        }
+    });
+}
+
+/// Gathers playback, queue and library changes into one stream of events.
+fn watch(ctx: &Context) -> broadcast::Sender<Event> {
+    let (sender, _) = broadcast::channel(256);
+
+    let mut playback = ctx.engine.read().unwrap().subscribe();
+    let events = sender.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let event = match playback.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let topic = match event {
+                PlaybackEvent::Position { .. } => Topic::Position,
+                _ => Topic::Playback,
+            };
+            let payload = serde_json::to_value(&event).unwrap_or_default();
+            // nobody listening is not an error
+            let _ = events.send(Event { topic, payload });
+        }
+    });
+
+    let mut changes = ctx.subscribe();
+    let events = sender.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let event = match changes.recv().await {
+                Ok(Change::Queue(queue)) => Event {
+                    topic: Topic::Queue,
+                    payload: serde_json::to_value(queue).unwrap_or_default(),
+                },
+                Ok(Change::Library) => Event {
+                    topic: Topic::Library,
+                    payload: Value::Null,
+                },
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let _ = events.send(event);
+        }
+    });
+
+    sender
+}
+
+async fn listen(
+    ctx: Context,
+    events: broadcast::Sender<Event>,
+    path: &Path,
+) -> std::io::Result<()> {
+    let listener = crate::ipc::bind(path)?;
+    log::info!("JSON-RPC server listening on {}", path.display());
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let ctx = ctx.clone();
+        let events = events.clone();
+        tauri::async_runtime::spawn(async move {
+            let ctx = &ctx;
+            let call =
+                |method: String, params| async move { methods::call(ctx, &method, params).await };
+            if let Err(e) = serve(stream, &call, &events).await {
+                log::warn!("JSON-RPC client disconnected: {}", e);
+            }
+        });
+    }
+}
+
+/// Answers the requests of one client, running the methods other than the subscriptions
+/// through `call`.
+async fn serve<C, F>(
+    stream: UnixStream,
+    call: &C,
+    events: &broadcast::Sender<Event>,
+) -> std::io::Result<()>
+where
+    C: Fn(String, Value) -> F,
+    F: Future<Output = Result<Value, RpcError>>,
+{
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut subscriptions = Subscriptions::default();
+
+    loop {
+        let messages = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) => handle_line(call, events, &mut subscriptions, &line)
+                    .await
+                    .into_iter()
+                    .collect(),
+                None => return Ok(()),
+            },
+            event = subscriptions.next() => match event {
+                Ok(event) => subscriptions.notifications(&event),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("JSON-RPC client missed {} events", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        };
+        for message in messages {
+            let mut message = serde_json::to_string(&message)?;
+            message.push('\n');
+            writer.write_all(message.as_bytes()).await?;
+        }
+    }
+}
+
+/// Answers a line holding a request or a batch of them, if anything needs an answer.
+async fn handle_line<C, F>(
+    call: &C,
+    events: &broadcast::Sender<Event>,
+    subscriptions: &mut Subscriptions,
+    line: &str,
+) -> Option<Value>
+where
+    C: Fn(String, Value) -> F,
+    F: Future<Output = Result<Value, RpcError>>,
+{
+    let message: Value = match serde_json::from_str(line) {
+        Ok(message) => message,
+        Err(e) => {
+            return Some(json!(Response::new(
+                Value::Null,
+                Err(RpcError::parse_error(e))
+            )))
+        }
+    };
+
+    let Value::Array(batch) = message else {
+        let response = handle(call, events, subscriptions, message).await?;
+        return Some(json!(response));
+    };
+    if batch.is_empty() {
+        let error = RpcError::invalid_request("empty batch");
+        return Some(json!(Response::new(Value::Null, Err(error))));
+    }
+    let mut responses = Vec::new();
+    for message in batch {
+        responses.extend(handle(call, events, subscriptions, message).await);
+    }
+    // a batch of only notifications gets no answer at all
+    (!responses.is_empty()).then(|| json!(responses))
+}
+
+async fn handle<C, F>(
+    call: &C,
+    events: &broadcast::Sender<Event>,
+    subscriptions: &mut Subscriptions,
+    message: Value,
+) -> Option<Response>
+where
+    C: Fn(String, Value) -> F,
+    F: Future<Output = Result<Value, RpcError>>,
+{
+    let request: Request = match serde_json::from_value(message) {
+        Ok(request) => request,
+        Err(e) => {
+            return Some(Response::new(
+                Value::Null,
+                Err(RpcError::invalid_request(e)),
+            ))
+        }
+    };
+    if request.jsonrpc != "2.0" {
+        let error = RpcError::invalid_request("jsonrpc must be \"2.0\"");
+        return Some(Response::new(request.id.unwrap_or_default(), Err(error)));
+    }
+
+    let outcome = match request.method.as_str() {
+        "subscribe" => subscriptions.subscribe(events, request.params),
+        "unsubscribe" => subscriptions.unsubscribe(request.params),
+        _ => call(request.method, request.params).await,
+    };
+    request.id.map(|id| Response::new(id, outcome))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+        net::{
+            unix::{OwnedReadHalf, OwnedWriteHalf},
+            UnixStream,
+        },
+        sync::broadcast,
+    };
+
+    use super::{handle_line, serve, Event, Request, Response, RpcError, Subscriptions, Topic};
+
+    /// Stands in for the methods of a running instance.
+    async fn echo(method: String, params: Value) -> Result<Value, RpcError> {
+        match method.as_str() {
+            "echo" => Ok(params),
+            method => Err(RpcError::method_not_found(method)),
+        }
+    }
+
+    fn answer(line: &str) -> Option<Value> {
+        let events = broadcast::channel(4).0;
+        let mut subscriptions = Subscriptions::default();
+        tauri::async_runtime::block_on(handle_line(&echo, &events, &mut subscriptions, line))
+    }
+
+    #[test]
+    fn notifications_are_told_apart_from_null_ids() {
+        let notification: Request =
+            serde_json::from_value(json!({ "jsonrpc": "2.0", "method": "playback.play" })).unwrap();
+        assert_eq!(notification.id, None);
+
+        let request: Request = serde_json::from_value(
+            json!({ "jsonrpc": "2.0", "method": "playback.play", "id": null }),
+        )
+        .unwrap();
+        assert_eq!(request.id, Some(Value::Null));
+    }
+
+    #[test]
+    fn responses_carry_either_result_or_error() {
+        assert_eq!(
+            json!(Response::new(json!(1), Ok(json!([])))),
+            json!({ "jsonrpc": "2.0", "result": [], "id": 1 })
+        );
+        assert_eq!(
+            json!(Response::new(
+                json!("a"),
+                Err(RpcError::method_not_found("dance"))
+            )),
+            json!({
+                "jsonrpc": "2.0",
+                "error": { "code": -32601, "message": "method not found: dance" },
+                "id": "a",
+            })
+        );
+    }
+
+    #[test]
+    fn failed_operations_keep_their_error_kind() {
+        let error = RpcError::from(crate::Error::from(crate::library::Error::NotLoaded));
+        assert_eq!(
+            json!(error),
+            json!({
+                "code": -32000,
+                "message": "library not loaded",
+                "data": { "kind": "library", "message": "library not loaded" },
+            })
+        );
+    }
+
+    #[test]
+    fn batches_answer_every_request_but_the_notifications() {
+        let line = json!([
+            { "jsonrpc": "2.0", "method": "echo", "params": [1], "id": 1 },
+            { "jsonrpc": "2.0", "method": "echo", "params": [2] },
+            { "jsonrpc": "2.0", "method": "dance", "id": 2 },
+        ]);
+        assert_eq!(
+            answer(&line.to_string()),
+            Some(json!([
+                { "jsonrpc": "2.0", "result": [1], "id": 1 },
+                {
+                    "jsonrpc": "2.0",
+                    "error": { "code": -32601, "message": "method not found: dance" },
+                    "id": 2,
+                },
+            ]))
+        );
+    }
+
+    #[test]
+    fn batches_of_notifications_get_no_answer() {
+        let line = json!([
+            { "jsonrpc": "2.0", "method": "echo" },
+            { "jsonrpc": "2.0", "method": "dance" },
+        ]);
+        assert_eq!(answer(&line.to_string()), None);
+    }
+
+    #[test]
+    fn empty_batches_are_invalid() {
+        let response = answer("[]").unwrap();
+        assert_eq!(response["error"]["code"], -32600);
+        assert_eq!(response["id"], Value::Null);
+    }
+
+    #[test]
+    fn unparsable_lines_are_parse_errors() {
+        let response = answer("{ \"jsonrpc\": ").unwrap();
+        assert_eq!(response["error"]["code"], -32700);
+        assert_eq!(response["id"], Value::Null);
+    }
+
+    #[test]
+    fn events_reach_only_subscriptions_to_their_topic() {
+        let events = broadcast::channel(4).0;
+        let mut subscriptions = Subscriptions::default();
+        let queue = subscriptions
+            .subscribe(&events, json!({ "topics": ["queue"] }))
+            .unwrap();
+        let all = subscriptions.subscribe(&events, Value::Null).unwrap();
+
+        let position = Event {
+            topic: Topic::Position,
+            payload: json!({ "position": 1.5 }),
+        };
+        let notifications = subscriptions.notifications(&position);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["params"]["subscription"], all);
+        assert_eq!(notifications[0]["params"]["topic"], "position");
+
+        let changed = Event {
+            topic: Topic::Queue,
+            payload: Value::Null,
+        };
+        let subscribers: Vec<_> = subscriptions
+            .notifications(&changed)
+            .iter()
+            .map(|notification| notification["params"]["subscription"].clone())
+            .collect();
+        assert_eq!(subscribers, [queue, all]);
+    }
+
+    async fn ask(
+        writer: &mut OwnedWriteHalf,
+        lines: &mut Lines<BufReader<OwnedReadHalf>>,
+        request: Value,
+    ) -> Value {
+        writer
+            .write_all((request.to_string() + "\n").as_bytes())
+            .await
+            .unwrap();
+        next(lines).await
+    }
+
+    async fn next(lines: &mut Lines<BufReader<OwnedReadHalf>>) -> Value {
+        let line = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn clients_get_answers_and_the_events_they_subscribed_to() {
+        tauri::async_runtime::block_on(async {
+            let events = broadcast::channel(4).0;
+            let (client, server) = UnixStream::pair().unwrap();
+            let server = tauri::async_runtime::spawn({
+                let events = events.clone();
+                async move { serve(server, &echo, &events).await }
+            });
+            let (reader, mut writer) = client.into_split();
+            let mut lines = BufReader::new(reader).lines();
+
+            let answer = ask(
+                &mut writer,
+                &mut lines,
+                json!({ "jsonrpc": "2.0", "method": "echo", "params": "hi", "id": 1 }),
+            )
+            .await;
+            assert_eq!(answer, json!({ "jsonrpc": "2.0", "result": "hi", "id": 1 }));
+
+            let subscribed = ask(
+                &mut writer,
+                &mut lines,
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "subscribe",
+                    "params": { "topics": ["library"] },
+                    "id": 2,
+                }),
+            )
+            .await;
+            let subscription = subscribed["result"].clone();
+            events
+                .send(Event {
+                    topic: Topic::Library,
+                    payload: Value::Null,
+                })
+                .unwrap();
+            let notification = next(&mut lines).await;
+            assert_eq!(notification["method"], "event");
+            assert_eq!(notification["params"]["subscription"], subscription);
+            assert_eq!(notification["params"]["topic"], "library");
+
+            drop(writer);
+            server.await.unwrap().unwrap();
+        });
+    }
+}
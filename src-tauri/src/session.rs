@@ -0,0 +1,247 @@
+//! Snapshot of the playback session, saved next to the settings so the queue and position
+//! survive a restart.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio::{Engine, QueueEntry},
+    context::Context,
+    library::{self, Library},
+};
+
+/// How often the session is saved while the app runs.
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("unable to read or write session: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unable to parse session: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SessionEntry {
+    path: PathBuf,
+    album: Option<String>,
+}
+
+impl SessionEntry {
+    fn new(entry: &QueueEntry) -> Self {
+        Self {
+            path: entry.path.clone(),
+            album: entry.album.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    queue: Vec<SessionEntry>,
+    /// Index of the playing song within the queue, if it was played from there.
+    index: Option<usize>,
+    /// The playing song, which may also have been started on its own.
+    current: Option<SessionEntry>,
+    /// Position within the current entry in seconds.
+    position: f64,
+}
+
+impl Session {
+    fn capture(engine: &Engine) -> Self {
+        Self {
+            queue: engine
+                .queue
+                .entries()
+                .iter()
+                .map(SessionEntry::new)
+                .collect(),
+            index: engine.queue.current_index(),
+            current: engine.current().map(SessionEntry::new),
+            position: engine.position(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SessionStore {
+    path: Option<PathBuf>,
+    /// The session of every library by its root, as last written.
+    sessions: BTreeMap<PathBuf, Session>,
+    /// Libraries whose session was read at startup but has not been restored yet.
+    pending: BTreeSet<PathBuf>,
+}
+
+pub type SessionState<'a> = tauri::State<'a, Arc<Mutex<SessionStore>>>;
+
+impl SessionStore {
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Error> {
+        let sessions: BTreeMap<PathBuf, Session> = match &path {
+            Some(path) if path.exists() => serde_json::from_str(&fs::read_to_string(path)?)?,
+            _ => BTreeMap::new(),
+        };
+        Ok(Self {
+            path,
+            pending: sessions.keys().cloned().collect(),
+            sessions,
+        })
+    }
+
+    fn save(&mut self, root: &Path, session: Session) -> Result<(), Error> {
+        // a session which was never restored would be overwritten by an empty one
+        if self.pending.contains(root) || self.sessions.get(root) == Some(&session) {
+            return Ok(());
+        }
+        self.sessions.insert(root.to_path_buf(), session);
+        if let Some(path) = &self.path {
+            fs::write(path, serde_json::to_string_pretty(&self.sessions)?)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_from(&mut self, root: &Path, engine: &Engine) {
+        if let Err(e) = self.save(root, Session::capture(engine)) {
+            log::error!("failed to save session: {}", e);
+        }
+    }
+
+    /// Whether the library at `root` has a session waiting to be restored.
+    fn is_pending(&self, root: &Path) -> bool {
+        self.pending.contains(root)
+    }
+
+    /// The session of the library at `root`, the first time it is asked for.
+    fn take(&mut self, root: &Path) -> Option<Session> {
+        if !self.pending.remove(root) {
+            return None;
+        }
+        self.sessions.get(root).cloned()
+    }
+}
+
+/// Saves the session and the play counts every now and then, so not too much is lost if the
+/// app is killed.
+pub fn spawn_autosave(ctx: Context) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(AUTOSAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            save(&ctx);
+        }
+    });
+}
+
+pub fn save(ctx: &Context) {
+    if let Err(e) = ctx.settings.read().unwrap().flush() {
+        log::error!("failed to save settings: {}", e);
+    }
+    let library = ctx.library.read().unwrap();
+    let Some(root) = library
+        .as_ref()
+        .and_then(Library::root)
+        .map(Path::to_path_buf)
+    else {
+        return;
+    };
+    drop(library);
+    let engine = ctx.engine.read().unwrap();
+    ctx.session.lock().unwrap().save_from(&root, &engine);
+}
+
+/// Opens the library used last if its session is waiting to be restored, so playback is
+/// back as it was right after startup.
+pub(crate) fn restore_at_startup(ctx: &Context) {
+    let settings = ctx.settings.read().unwrap();
+    let Some(root) = settings.libraries.front().cloned() else {
+        return;
+    };
+    if settings.disable_resume {
+        return;
+    }
+    drop(settings);
+    if ctx.session.lock().unwrap().is_pending(&root) {
+        library::open_last(ctx.clone());
+    }
+}
+
+/// Restores the session saved for `library`, paused at the saved position, the first time
+/// the library is opened. Does nothing if something was started in the meantime or
+/// resuming is disabled.
+pub(crate) fn restore(ctx: &Context, library: &Library) {
+    let Some(root) = library.root() else {
+        return;
+    };
+    let Some(session) = ctx.session.lock().unwrap().take(root) else {
+        return;
+    };
+    if ctx.settings.read().unwrap().disable_resume {
+        return;
+    }
+
+    // files which are gone are dropped, and with the current one its position
+    let find = |saved: SessionEntry| {
+        Some(QueueEntry::new(
+            library.find(&saved.path)?,
+            saved.path,
+            saved.album,
+        ))
+    };
+    let mut index = None;
+    let mut entries = Vec::with_capacity(session.queue.len());
+    for (i, saved) in session.queue.into_iter().enumerate() {
+        let Some(entry) = find(saved) else {
+            continue;
+        };
+        if session.index == Some(i) {
+            index = Some(entries.len());
+        }
+        entries.push(entry);
+    }
+    let current = session.current.and_then(find);
+
+    let mut engine = ctx.engine.write().unwrap();
+    if !engine.queue.is_empty() || engine.current().is_some() {
+        return;
+    }
+    if let Err(e) = engine.resume(entries, index, current, session.position) {
+        log::error!("failed to restore session: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{Session, SessionStore};
+
+    #[test]
+    fn sessions_wait_for_their_own_library() {
+        let mut store = SessionStore::default();
+        let first = Path::new("/music");
+        let second = Path::new("/podcasts");
+        let saved = Session {
+            position: 12.0,
+            ..Session::default()
+        };
+        store.sessions.insert(first.to_path_buf(), saved.clone());
+        store.pending.insert(first.to_path_buf());
+
+        // opening another library neither restores nor overwrites the first one's session
+        assert_eq!(store.take(second), None);
+        store.save(second, Session::default()).unwrap();
+        store.save(first, Session::default()).unwrap();
+        assert_eq!(store.sessions[first], saved);
+
+        assert_eq!(store.take(first), Some(saved));
+        assert_eq!(store.take(first), None);
+        store.save(first, Session::default()).unwrap();
+        assert_eq!(store.sessions[first], Session::default());
+    }
+}
@@ -1,16 +1,92 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     path::{Path, PathBuf},
-    sync::RwLock,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    audio::{Equalizer, Output, Repeat, ReplayGain, Shuffle, Speed, SpeedScope, Volume},
+    playlist::smart::SmartPlaylist,
+};
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Settings {
     pub(crate) libraries: VecDeque<PathBuf>,
+    #[serde(default)]
+    pub(crate) smart_playlists: Vec<SmartPlaylist>,
+    #[serde(default)]
+    pub(crate) stats: HashMap<PathBuf, SongStats>,
+    #[serde(default)]
+    pub(crate) repeat: Repeat,
+    #[serde(default)]
+    pub(crate) shuffle: Shuffle,
+    #[serde(default)]
+    pub(crate) volume: Volume,
+    #[serde(default)]
+    pub(crate) replay_gain: ReplayGain,
+    #[serde(default)]
+    pub(crate) equalizer: Equalizer,
+    /// Speed of songs which have none remembered for themselves or their folders.
+    #[serde(default)]
+    pub(crate) speed: Speed,
+    #[serde(default)]
+    pub(crate) output: Output,
+    /// Speeds remembered for single songs or whole folders.
+    #[serde(default)]
+    pub(crate) speeds: HashMap<PathBuf, f64>,
+    #[serde(default)]
+    pub(crate) audiobooks: Audiobooks,
+    /// Skips restoring the queue and position of the last session at startup.
+    #[serde(default)]
+    pub(crate) disable_resume: bool,
     #[serde(skip)]
     pub(crate) path: Option<PathBuf>,
+    /// Set by changes which are saved along with the next ones rather than right away.
+    #[serde(skip)]
+    unsaved: AtomicBool,
+}
+
+/// The highest rating a song can have, zero being unrated.
+pub(crate) const MAX_RATING: u8 = 5;
+
+/// Per-song bookkeeping which is not stored in the file tags themselves.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SongStats {
+    #[serde(default)]
+    pub(crate) play_count: u32,
+    #[serde(default)]
+    pub(crate) rating: Option<u8>,
+    /// Named positions within the song, ordered by time.
+    #[serde(default)]
+    pub(crate) bookmarks: Vec<Bookmark>,
+    /// Where an audiobook was left off in seconds.
+    #[serde(default)]
+    pub(crate) progress: Option<f64>,
+    /// Whether an audiobook was played to the end.
+    #[serde(default)]
+    pub(crate) finished: bool,
+}
+
+/// Which songs remember where they were left off.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Audiobooks {
+    /// Everything within these folders.
+    pub(crate) folders: Vec<PathBuf>,
+    /// Anything at least this long, wherever it is.
+    pub(crate) min_length: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub(crate) name: String,
+    pub(crate) seconds: f64,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -28,18 +104,24 @@ pub enum SettingsInitError {
 }
 
 impl Settings {
-    pub fn load_or_create(settings_path: Option<PathBuf>) -> Result<Self, SettingsInitError> {
-        if let Some(settings_path) = settings_path {
-            return Self::from_settings_path(settings_path);
-        };
-
+    /// Where the settings are kept unless another file is given, without creating anything.
+    pub fn default_path() -> Result<PathBuf, SettingsInitError> {
         let settings_dir = match dirs::cache_dir() {
             Some(cache_dir) => cache_dir.join("ample"),
             None => dirs::home_dir()
                 .ok_or(SettingsInitError::NoHomeDirectory)?
                 .join(".cache/ample"),
         };
-        let settings_path = settings_dir.join("settings.json");
+        Ok(settings_dir.join("settings.json"))
+    }
+
+    pub fn load_or_create(settings_path: Option<PathBuf>) -> Result<Self, SettingsInitError> {
+        if let Some(settings_path) = settings_path {
+            return Self::from_settings_path(settings_path);
+        };
+
+        let settings_path = Self::default_path()?;
+        let settings_dir = settings_path.parent().unwrap_or(Path::new("."));
 
         if !settings_dir.exists() {
             std::fs::create_dir_all(&settings_dir).map_err(SettingsInitError::CreateDirectory)?;
@@ -73,16 +155,120 @@ impl Settings {
             .map_err(SettingsInitError::CreateFile)
     }
 
+    /// Counts a play of the song at `path`, which is saved later as songs can change often.
+    pub(crate) fn record_play(&mut self, path: PathBuf) {
+        self.stats.entry(path).or_default().play_count += 1;
+        self.save_later();
+    }
+
+    /// Leaves a change to be saved with the next one or by `flush`, for changes which come
+    /// too often to write the file for each.
+    pub(crate) fn save_later(&self) {
+        self.unsaved.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn bookmark(&self, path: &Path, name: &str) -> Option<&Bookmark> {
+        self.stats
+            .get(path)?
+            .bookmarks
+            .iter()
+            .find(|bookmark| bookmark.name == name)
+    }
+
+    /// Adds a bookmark to the song at `path`, replacing one with the same name.
+    pub(crate) fn add_bookmark(&mut self, path: PathBuf, bookmark: Bookmark) {
+        let bookmarks = &mut self.stats.entry(path).or_default().bookmarks;
+        bookmarks.retain(|b| b.name != bookmark.name);
+        let index = bookmarks.partition_point(|b| b.seconds <= bookmark.seconds);
+        bookmarks.insert(index, bookmark);
+    }
+
+    /// Whether the song at `path` is treated as an audiobook. Without a `duration`, only
+    /// the folders are checked.
+    pub(crate) fn is_audiobook(&self, path: &Path, duration: Option<Duration>) -> bool {
+        self.audiobooks
+            .folders
+            .iter()
+            .any(|folder| path.starts_with(folder))
+            || self
+                .audiobooks
+                .min_length
+                .zip(duration)
+                .is_some_and(|(min_length, duration)| duration >= min_length)
+    }
+
+    /// Where to start the song at `path`, if it is an audiobook which was left part way.
+    pub(crate) fn resume_position(&self, path: &Path, duration: Duration) -> Option<f64> {
+        if !self.is_audiobook(path, Some(duration)) {
+            return None;
+        }
+        let stats = self.stats.get(path)?;
+        stats.progress.filter(|_| !stats.finished)
+    }
+
+    pub(crate) fn set_progress(&mut self, path: PathBuf, position: f64) {
+        let stats = self.stats.entry(path).or_default();
+        stats.progress = Some(position);
+        stats.finished = false;
+    }
+
+    pub(crate) fn set_finished(&mut self, path: PathBuf, finished: bool) {
+        let stats = self.stats.entry(path).or_default();
+        stats.progress = None;
+        stats.finished = finished;
+    }
+
+    /// The speed remembered for the song at `path`, or the closest folder above it.
+    pub(crate) fn speed_for(&self, path: &Path) -> f64 {
+        path.ancestors()
+            .find_map(|path| self.speeds.get(path))
+            .copied()
+            .unwrap_or(self.speed.rate)
+    }
+
+    pub(crate) fn remember_speed(&mut self, path: &Path, scope: SpeedScope, rate: f64) {
+        if let Some(key) = speed_key(path, scope) {
+            self.speeds.insert(key.to_path_buf(), rate);
+        }
+    }
+
+    pub(crate) fn forget_speed(&mut self, path: &Path, scope: SpeedScope) {
+        if let Some(key) = speed_key(path, scope) {
+            self.speeds.remove(key);
+        }
+    }
+
+    /// Path of a file stored in the same directory as the settings file.
+    pub(crate) fn sibling_path(&self, file_name: &str) -> Option<PathBuf> {
+        let dir = self.path.as_ref()?.parent()?;
+        Some(dir.join(file_name))
+    }
+
     pub(crate) fn save(&self) -> Result<(), SettingsInitError> {
         if let Some(path) = &self.path {
-            self.store(path)
-        } else {
-            Ok(())
+            self.store(path)?;
+        }
+        self.unsaved.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Saves the changes which were not saved right away, if there are any.
+    pub(crate) fn flush(&self) -> Result<(), SettingsInitError> {
+        if self.unsaved.load(Ordering::Relaxed) {
+            self.save()?;
         }
+        Ok(())
     }
 }
 
-pub type SettingsState<'a> = tauri::State<'a, RwLock<Settings>>;
+fn speed_key(path: &Path, scope: SpeedScope) -> Option<&Path> {
+    match scope {
+        SpeedScope::Song => Some(path),
+        SpeedScope::Folder => path.parent(),
+    }
+}
+
+pub type SettingsState<'a> = tauri::State<'a, Arc<RwLock<Settings>>>;
 
 #[tauri::command]
 pub fn settings_list_libraries(settings: SettingsState<'_>) -> Vec<String> {
@@ -101,6 +287,17 @@ pub fn settings_save(settings: SettingsState<'_>) -> crate::Result<()> {
     Ok(())
 }
 
+#[tauri::command]
+pub fn settings_set_resume_session(
+    enabled: bool,
+    settings: SettingsState<'_>,
+) -> crate::Result<()> {
+    let mut settings = settings.write().unwrap();
+    settings.disable_resume = !enabled;
+    settings.save()?;
+    Ok(())
+}
+
 #[tauri::command]
 pub fn settings_remove_library(path: String, settings: SettingsState<'_>) -> crate::Result<()> {
     let path_buf = PathBuf::from(&path);
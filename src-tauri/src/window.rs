@@ -0,0 +1,81 @@
+//! Ties the context to the window, which is told about everything that happens and can be
+//! brought up or closed through the context.
+
+use std::sync::OnceLock;
+
+use tauri::{Emitter, Manager};
+use tokio::sync::broadcast;
+
+use crate::context::{Change, Context, Shell};
+
+/// The shell of the app with a window, which only exists once Tauri set it up.
+#[derive(Default)]
+pub(crate) struct Window(OnceLock<tauri::AppHandle>);
+
+impl Window {
+    pub(crate) fn attach(&self, app: tauri::AppHandle) {
+        if self.0.set(app).is_err() {
+            log::warn!("window was set up twice");
+        }
+    }
+}
+
+impl Shell for Window {
+    fn focus(&self) {
+        let Some(window) = self.0.get().and_then(|app| app.get_webview_window("main")) else {
+            return;
+        };
+        if let Err(e) = window
+            .unminimize()
+            .and_then(|_| window.show())
+            .and_then(|_| window.set_focus())
+        {
+            log::warn!("failed to focus window: {}", e);
+        }
+    }
+
+    fn quit(&self) {
+        if let Some(app) = self.0.get() {
+            app.exit(0);
+        }
+    }
+}
+
+/// Forwards playback events, queue and library changes to the frontend.
+pub(crate) fn forward_events(app: tauri::AppHandle, ctx: &Context) {
+    let mut events = ctx.engine.read().unwrap().subscribe();
+    let playback = app.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("skipped {} playback events", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if let Err(e) = playback.emit("playback", &event) {
+                log::error!("failed to emit playback event: {}", e);
+            }
+        }
+    });
+
+    let mut changes = ctx.subscribe();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let result = match changes.recv().await {
+                Ok(Change::Queue(queue)) => app.emit("queue_changed", queue),
+                Ok(Change::Library) => app.emit("library_changed", ()),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("skipped {} queue and library changes", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if let Err(e) = result {
+                log::error!("failed to emit change: {}", e);
+            }
+        }
+    });
+}